@@ -1,16 +1,26 @@
 use std::collections::VecDeque;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
 use ktr_lib::metadata::{Asn, Network};
+use ktr_lib::multipath::{MultipathGraph, MultipathTrace};
 use ktr_lib::peeringdb::PeeringDbManager;
-use ktr_lib::trace::{DidUpdate, Hop, TerminationReason, Trace, TraceConfig, TraceError};
-use ktr_lib::traceroute_net::TracerouteChannel;
+use ktr_lib::trace::{
+    DidUpdate, Hop, Monitor, MonitorSnapshot, TerminationReason, Trace, TraceConfig, TraceError,
+};
+use ktr_lib::traceroute_net::{
+    ProbeProtocol, ProbeTransport, ReceiveStats, ReceivedPacket, TracerouteChannel,
+};
 use serde::ser::SerializeStruct;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
+
+use capture::CaptureRecorder;
+
+mod capture;
 
 /// Index into the list of traces.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[repr(transparent)]
 pub struct TraceId(usize);
 
@@ -19,6 +29,8 @@ pub struct TraceId(usize);
 pub enum SafeTerminationReason {
     Termination(TerminationReason),
     Error(TraceError),
+    /// Stopped by a `StopTrace` command.
+    Stopped,
 }
 
 impl Serialize for SafeTerminationReason {
@@ -39,6 +51,11 @@ impl Serialize for SafeTerminationReason {
                 state.serialize_field("error", error)?;
                 state.end()
             }
+            SafeTerminationReason::Stopped => {
+                let mut state = serializer.serialize_struct("SafeTerminationReason", 1)?;
+                state.serialize_field("kind", "Stopped")?;
+                state.end()
+            }
         }
     }
 }
@@ -52,21 +69,127 @@ pub enum ControllerResult<'a> {
     TraceDone {
         id: TraceId,
         hops: Vec<Hop>,
+        /// With path MTU discovery, the biggest probe that reached the destination.
+        path_mtu: Option<u16>,
+        reason: SafeTerminationReason,
+    },
+    #[serde(rename_all = "camelCase")]
+    MultipathUpdate { id: TraceId, graph: MultipathGraph },
+    #[serde(rename_all = "camelCase")]
+    MultipathDone {
+        id: TraceId,
+        graph: MultipathGraph,
+        reason: SafeTerminationReason,
+    },
+    #[serde(rename_all = "camelCase")]
+    MonitorUpdate {
+        id: TraceId,
+        hops: &'a [Hop],
+        stats: MonitorSnapshot,
+    },
+    /// Monitors run until stopped, so this only happens on errors or when one is stopped.
+    #[serde(rename_all = "camelCase")]
+    MonitorDone {
+        id: TraceId,
+        hops: Vec<Hop>,
+        stats: MonitorSnapshot,
         reason: SafeTerminationReason,
     },
 }
 
-pub struct ControllerConfig<'a> {
-    pub traceroute_channel: TracerouteChannel,
+/// Anything the controller can run under a `TraceId`. `Trace` and `Monitor` are boxed because
+/// their hop buffers are much bigger than everything else.
+enum Job<'a> {
+    Trace(Box<Trace<'a>>),
+    Multipath(MultipathTrace<'a>),
+    Monitor(Box<Monitor<'a>>),
+}
+
+impl<'a> Job<'a> {
+    fn non_packet_poll(
+        &mut self,
+        traceroute_channel: &mut impl ProbeTransport,
+        peeringdb: &PeeringDbManager,
+    ) -> Result<(DidUpdate, Option<TerminationReason>), TraceError> {
+        match self {
+            Job::Trace(trace) => trace.non_packet_poll(traceroute_channel, peeringdb),
+            Job::Multipath(trace) => trace.non_packet_poll(traceroute_channel),
+            Job::Monitor(monitor) => monitor.non_packet_poll(traceroute_channel, peeringdb),
+        }
+    }
+
+    fn perhaps_use_packet(
+        &mut self,
+        packet: &ReceivedPacket,
+        traceroute_channel: &mut impl ProbeTransport,
+        peeringdb: &PeeringDbManager,
+    ) -> Result<(DidUpdate, Option<TerminationReason>), TraceError> {
+        match self {
+            Job::Trace(trace) => trace.perhaps_use_packet(packet, traceroute_channel, peeringdb),
+            Job::Multipath(trace) => trace.perhaps_use_packet(packet),
+            Job::Monitor(monitor) => {
+                monitor.perhaps_use_packet(packet, traceroute_channel, peeringdb)
+            }
+        }
+    }
+
+    fn update(&self, id: TraceId) -> ControllerResult<'_> {
+        match self {
+            Job::Trace(trace) => ControllerResult::TraceUpdate {
+                id,
+                hops: trace.hops(),
+            },
+            Job::Multipath(trace) => ControllerResult::MultipathUpdate {
+                id,
+                graph: trace.graph(),
+            },
+            Job::Monitor(monitor) => ControllerResult::MonitorUpdate {
+                id,
+                hops: monitor.hops(),
+                stats: monitor.snapshot(),
+            },
+        }
+    }
+
+    fn done(self, id: TraceId, reason: SafeTerminationReason) -> ControllerResult<'static> {
+        match self {
+            Job::Trace(trace) => ControllerResult::TraceDone {
+                id,
+                path_mtu: trace.path_mtu(),
+                hops: trace.to_hops(),
+                reason,
+            },
+            Job::Multipath(trace) => ControllerResult::MultipathDone {
+                id,
+                graph: trace.graph(),
+                reason,
+            },
+            Job::Monitor(monitor) => ControllerResult::MonitorDone {
+                id,
+                stats: monitor.snapshot(),
+                hops: monitor.to_hops(),
+                reason,
+            },
+        }
+    }
+}
+
+/// `T` is where probes go, which is normally a real `TracerouteChannel`.
+pub struct ControllerConfig<'a, T: ProbeTransport = TracerouteChannel> {
+    pub traceroute_channel: T,
     pub peeringdb: PeeringDbManager,
     pub trace_config: &'a TraceConfig,
+    /// Write a pcap file per trace here, of whatever the channel captures. Capturing has to be
+    /// turned on in the channel separately.
+    pub pcap_dir: Option<PathBuf>,
 }
 
-pub struct Controller<'a> {
-    traceroute_channel: TracerouteChannel,
+pub struct Controller<'a, T: ProbeTransport = TracerouteChannel> {
+    traceroute_channel: T,
     peeringdb: PeeringDbManager,
     trace_config: &'a TraceConfig,
-    traces: Vec<Option<Trace<'a>>>,
+    capture: Option<CaptureRecorder>,
+    traces: Vec<Option<Job<'a>>>,
     next_id: usize,
     duration_ringbuf: VecDeque<Duration>,
     last_lps_print: Instant,
@@ -93,37 +216,42 @@ macro_rules! handle_poll_result {
         match $poll_result {
             Ok((DidUpdate::No, _)) => {}
             Ok((DidUpdate::Yes, None)) => {
-                return Some(ControllerResult::TraceUpdate {
-                    id: TraceId($trace_id),
-                    hops: $self.traces[$trace_id].as_ref().unwrap().hops(),
-                });
+                return Some(
+                    $self.traces[$trace_id]
+                        .as_ref()
+                        .unwrap()
+                        .update(TraceId($trace_id)),
+                );
             }
             Ok((DidUpdate::Yes, Some(termination_reason))) => {
                 $self.next_id = $self.iter_cursor.min($self.next_id);
-                return Some(ControllerResult::TraceDone {
-                    id: TraceId($trace_id),
-                    hops: $self.traces[$trace_id].take().unwrap().to_hops(),
-                    reason: SafeTerminationReason::Termination(termination_reason),
-                });
+                $self.stop_capture(TraceId($trace_id));
+                return Some($self.traces[$trace_id].take().unwrap().done(
+                    TraceId($trace_id),
+                    SafeTerminationReason::Termination(termination_reason),
+                ));
             }
             Err(error) => {
                 $self.next_id = $trace_id.min($self.next_id);
-                return Some(ControllerResult::TraceDone {
-                    id: TraceId($trace_id),
-                    hops: $self.traces[$trace_id].take().unwrap().to_hops(),
-                    reason: SafeTerminationReason::Error(error),
-                });
+                $self.stop_capture(TraceId($trace_id));
+                return Some(
+                    $self.traces[$trace_id]
+                        .take()
+                        .unwrap()
+                        .done(TraceId($trace_id), SafeTerminationReason::Error(error)),
+                );
             }
         }
     };
 }
 
-impl<'a> Controller<'a> {
-    pub fn new(config: ControllerConfig<'a>) -> Self {
+impl<'a, T: ProbeTransport> Controller<'a, T> {
+    pub fn new(config: ControllerConfig<'a, T>) -> Self {
         Self {
             traceroute_channel: config.traceroute_channel,
             peeringdb: config.peeringdb,
             trace_config: config.trace_config,
+            capture: config.pcap_dir.map(CaptureRecorder::new),
             traces: vec![],
             duration_ringbuf: VecDeque::with_capacity(10000),
             last_lps_print: Instant::now(),
@@ -143,14 +271,23 @@ impl<'a> Controller<'a> {
         let start = Instant::now();
         loop {
             match self.traceroute_channel.poll() {
-                Ok(Some(result)) => {
+                Ok(Some(packet)) => {
+                    if let Some(capture) = &mut self.capture {
+                        capture.record_received(self.traceroute_channel.take_captured());
+                    }
                     for (i, trace) in self.traces.iter_mut().enumerate() {
                         if let Some(trace) = trace {
                             let poll_result = trace.perhaps_use_packet(
-                                &result,
+                                &packet,
                                 &mut self.traceroute_channel,
                                 &self.peeringdb,
                             );
+                            if let Some(capture) = &mut self.capture {
+                                capture.record_sent(
+                                    TraceId(i),
+                                    self.traceroute_channel.take_captured(),
+                                );
+                            }
                             handle_poll_result!(self, start, i, poll_result);
                         }
                     }
@@ -170,6 +307,12 @@ impl<'a> Controller<'a> {
             if let Some(trace) = &mut self.traces[self.iter_cursor] {
                 let poll_result =
                     trace.non_packet_poll(&mut self.traceroute_channel, &self.peeringdb);
+                if let Some(capture) = &mut self.capture {
+                    capture.record_sent(
+                        TraceId(self.iter_cursor),
+                        self.traceroute_channel.take_captured(),
+                    );
+                }
                 handle_poll_result!(self, start, self.iter_cursor, poll_result);
             }
 
@@ -182,10 +325,61 @@ impl<'a> Controller<'a> {
         }
     }
 
-    pub fn start_trace(&mut self, ip: IpAddr) -> TraceId {
+    /// Start a trace, optionally overriding the configured probe protocol.
+    pub fn start_trace(&mut self, ip: IpAddr, probe_protocol: Option<ProbeProtocol>) -> TraceId {
+        let probe_protocol = probe_protocol.unwrap_or(self.trace_config.probe_protocol);
+        self.start_job(Job::Trace(Box::new(Trace::with_probe_protocol(
+            ip,
+            self.trace_config,
+            probe_protocol,
+        ))))
+    }
+
+    /// Start a multipath trace, which reports a graph of load-balanced paths instead of hops.
+    pub fn start_multipath_trace(
+        &mut self,
+        ip: IpAddr,
+        probe_protocol: Option<ProbeProtocol>,
+    ) -> TraceId {
+        let probe_protocol = probe_protocol.unwrap_or(self.trace_config.probe_protocol);
+        self.start_job(Job::Multipath(MultipathTrace::with_probe_protocol(
+            ip,
+            self.trace_config,
+            probe_protocol,
+        )))
+    }
+
+    /// Start continuously monitoring the path to an IP, which streams statistics until stopped.
+    pub fn start_monitor(&mut self, ip: IpAddr, probe_protocol: Option<ProbeProtocol>) -> TraceId {
+        let probe_protocol = probe_protocol.unwrap_or(self.trace_config.probe_protocol);
+        self.start_job(Job::Monitor(Box::new(Monitor::with_probe_protocol(
+            ip,
+            self.trace_config,
+            probe_protocol,
+        ))))
+    }
+
+    /// Stop a running trace or monitor. Returns its final result, like the one it would have
+    /// finished with, or None if it wasn't running.
+    pub fn stop_trace(&mut self, id: TraceId) -> Option<ControllerResult<'static>> {
+        let job = self.traces.get_mut(id.0)?.take()?;
+        self.next_id = id.0.min(self.next_id);
+        self.stop_capture(id);
+        Some(job.done(id, SafeTerminationReason::Stopped))
+    }
+
+    fn start_job(&mut self, job: Job<'a>) -> TraceId {
+        let id = self.insert_job(job);
+        if let Some(capture) = &mut self.capture {
+            capture.start(id);
+        }
+        id
+    }
+
+    fn insert_job(&mut self, job: Job<'a>) -> TraceId {
         if self.next_id < self.traces.len() {
             let id = self.next_id;
-            self.traces[self.next_id] = Some(Trace::new(ip, self.trace_config));
+            self.traces[self.next_id] = Some(job);
 
             // Pick next id
             for i in (id + 1)..=self.traces.len() {
@@ -197,12 +391,18 @@ impl<'a> Controller<'a> {
 
             TraceId(id)
         } else {
-            self.traces.push(Some(Trace::new(ip, self.trace_config)));
+            self.traces.push(Some(job));
             self.next_id = self.traces.len();
             TraceId(self.next_id - 1)
         }
     }
 
+    fn stop_capture(&mut self, id: TraceId) {
+        if let Some(capture) = &mut self.capture {
+            capture.stop(id);
+        }
+    }
+
     pub fn lookup_asn(&self, asn: Asn) -> Option<Network> {
         match self.peeringdb.network_by_asn(asn) {
             Ok(result) => result,
@@ -212,4 +412,16 @@ impl<'a> Controller<'a> {
             }
         }
     }
+
+    /// How many replies the kernel has queued and dropped for us, and how many frames we
+    /// couldn't decode, if the channel can tell.
+    pub fn receive_stats(&mut self) -> Option<ReceiveStats> {
+        match self.traceroute_channel.receive_stats() {
+            Ok(stats) => stats,
+            Err(error) => {
+                eprintln!("Returning None due to receive stats error: {:?}", error);
+                None
+            }
+        }
+    }
 }
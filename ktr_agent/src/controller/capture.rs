@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ktr_lib::pcap::PcapWriter;
+use ktr_lib::traceroute_net::{CapturedPacket, PacketId};
+
+use super::TraceId;
+
+/// Splits captured packets up by the trace that sent them and writes each trace's to its own
+/// pcap file. Capture is a debugging aid, so failing to write only gets logged.
+pub(super) struct CaptureRecorder {
+    dir: PathBuf,
+    writers: HashMap<TraceId, PcapWriter<BufWriter<File>>>,
+    /// Which trace sent each probe still in flight, for attributing replies.
+    owners: HashMap<PacketId, TraceId>,
+}
+
+impl CaptureRecorder {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            writers: HashMap::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Open a new file for a trace. IDs get reused, so the name includes the start time.
+    pub fn start(&mut self, id: TraceId) {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.dir.join(format!("trace-{}-{}.pcap", id.0, started_at));
+        match PcapWriter::create(&path) {
+            Ok(writer) => {
+                self.writers.insert(id, writer);
+            }
+            Err(error) => eprintln!("Failed to create capture {}: {}", path.display(), error),
+        }
+    }
+
+    pub fn stop(&mut self, id: TraceId) {
+        if let Some(mut writer) = self.writers.remove(&id) {
+            if let Err(error) = writer.flush() {
+                eprintln!("Failed to write capture for trace {}: {}", id.0, error);
+            }
+        }
+        self.owners.retain(|_, owner| *owner != id);
+    }
+
+    /// Record packets sent while a trace was being polled.
+    pub fn record_sent(&mut self, id: TraceId, packets: Vec<CapturedPacket>) {
+        for packet in packets {
+            self.owners.insert(packet.id, id);
+            self.write(id, &packet);
+        }
+    }
+
+    /// Record replies, which go to whichever trace last sent a probe with the same ID.
+    pub fn record_received(&mut self, packets: Vec<CapturedPacket>) {
+        for packet in packets {
+            if let Some(&id) = self.owners.get(&packet.id) {
+                self.write(id, &packet);
+            }
+        }
+    }
+
+    fn write(&mut self, id: TraceId, packet: &CapturedPacket) {
+        let Some(writer) = self.writers.get_mut(&id) else {
+            return;
+        };
+        if let Err(error) = writer.write_packet(packet.timestamp, &packet.data) {
+            eprintln!("Failed to write capture for trace {}: {}", id.0, error);
+            self.writers.remove(&id);
+        }
+    }
+}
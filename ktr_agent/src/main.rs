@@ -1,16 +1,24 @@
 use std::io::{prelude::*, stdin, BufReader};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
 use std::thread::{self};
 
 use anyhow::Context;
 use clap::Parser;
 use ktr_agent::controller::{Controller, ControllerConfig, ControllerResult, TraceId};
+use ktr_lib::clock::{Clock, SystemClock};
 use ktr_lib::metadata::{Asn, Network};
+use ktr_lib::multipath::MultipathConfig;
 use ktr_lib::peeringdb::PeeringDbManager;
-use ktr_lib::trace::TraceConfig;
-use ktr_lib::traceroute_net::{interface_from_name, TracerouteChannel};
+use ktr_lib::replay_net::ReplayNetwork;
+use ktr_lib::trace::{MonitorConfig, TraceConfig};
+use ktr_lib::traceroute_net::{
+    interface_from_name, LinkType, ProbeOptions, ProbeProtocol, ProbeTransport, ReceiveStats,
+    SourceAddresses, TracerouteChannel,
+};
+use ktr_lib::whois_net::WhoisAsnLookup;
 use serde::{Deserialize, Serialize};
 
 struct InputLine(String);
@@ -23,9 +31,35 @@ struct CommandId(usize);
 #[serde(tag = "kind")]
 enum Command {
     #[serde(rename_all = "camelCase")]
-    StartTrace { command_id: CommandId, ip: IpAddr },
+    StartTrace {
+        command_id: CommandId,
+        ip: IpAddr,
+        /// If set, trace with TCP SYN probes to this port instead of the default protocol.
+        tcp_port: Option<u16>,
+    },
+    #[serde(rename_all = "camelCase")]
+    StartMultipathTrace {
+        command_id: CommandId,
+        ip: IpAddr,
+        /// If set, trace with TCP SYN probes to this port instead of the default protocol.
+        tcp_port: Option<u16>,
+    },
+    #[serde(rename_all = "camelCase")]
+    StartMonitor {
+        command_id: CommandId,
+        ip: IpAddr,
+        /// If set, monitor with TCP SYN probes to this port instead of the default protocol.
+        tcp_port: Option<u16>,
+    },
+    #[serde(rename_all = "camelCase")]
+    StopTrace {
+        command_id: CommandId,
+        trace_id: TraceId,
+    },
     #[serde(rename_all = "camelCase")]
     LookupAsn { command_id: CommandId, asn: Asn },
+    #[serde(rename_all = "camelCase")]
+    GetReceiveStats { command_id: CommandId },
 }
 
 #[derive(Debug, Serialize)]
@@ -37,16 +71,27 @@ enum Output<'a> {
         trace_id: TraceId,
     },
     #[serde(rename_all = "camelCase")]
+    StoppedTrace {
+        command_id: CommandId,
+        trace_id: TraceId,
+        was_running: bool,
+    },
+    #[serde(rename_all = "camelCase")]
     LookupAsnResult {
         command_id: CommandId,
         network: Option<Network>,
     },
+    #[serde(rename_all = "camelCase")]
+    ReceiveStats {
+        command_id: CommandId,
+        stats: Option<ReceiveStats>,
+    },
     /// Pass through to a `ControllerResult`.
     #[serde(untagged)]
     ControllerResult(ControllerResult<'a>),
 }
 
-fn controller_thread(config: ControllerConfig, rx: Receiver<InputLine>) -> ! {
+fn controller_thread<T: ProbeTransport>(config: ControllerConfig<T>, rx: Receiver<InputLine>) -> ! {
     let mut controller = Controller::new(config);
 
     fn output(output: &Output) {
@@ -66,13 +111,57 @@ fn controller_thread(config: ControllerConfig, rx: Receiver<InputLine>) -> ! {
                     }
                 };
                 match command {
-                    Command::StartTrace { command_id, ip } => {
-                        let trace_id = controller.start_trace(ip);
+                    Command::StartTrace {
+                        command_id,
+                        ip,
+                        tcp_port,
+                    } => {
+                        let probe_protocol = tcp_port.map(|port| ProbeProtocol::Tcp { port });
+                        let trace_id = controller.start_trace(ip, probe_protocol);
+                        output(&Output::StartedTrace {
+                            command_id,
+                            trace_id,
+                        });
+                    }
+                    Command::StartMultipathTrace {
+                        command_id,
+                        ip,
+                        tcp_port,
+                    } => {
+                        let probe_protocol = tcp_port.map(|port| ProbeProtocol::Tcp { port });
+                        let trace_id = controller.start_multipath_trace(ip, probe_protocol);
                         output(&Output::StartedTrace {
                             command_id,
                             trace_id,
                         });
                     }
+                    Command::StartMonitor {
+                        command_id,
+                        ip,
+                        tcp_port,
+                    } => {
+                        let probe_protocol = tcp_port.map(|port| ProbeProtocol::Tcp { port });
+                        let trace_id = controller.start_monitor(ip, probe_protocol);
+                        output(&Output::StartedTrace {
+                            command_id,
+                            trace_id,
+                        });
+                    }
+                    Command::StopTrace {
+                        command_id,
+                        trace_id,
+                    } => {
+                        let result = controller.stop_trace(trace_id);
+                        let was_running = result.is_some();
+                        if let Some(result) = result {
+                            output(&Output::ControllerResult(result));
+                        }
+                        output(&Output::StoppedTrace {
+                            command_id,
+                            trace_id,
+                            was_running,
+                        });
+                    }
                     Command::LookupAsn { command_id, asn } => {
                         let network = controller.lookup_asn(asn);
                         output(&Output::LookupAsnResult {
@@ -80,6 +169,10 @@ fn controller_thread(config: ControllerConfig, rx: Receiver<InputLine>) -> ! {
                             network,
                         });
                     }
+                    Command::GetReceiveStats { command_id } => {
+                        let stats = controller.receive_stats();
+                        output(&Output::ReceiveStats { command_id, stats });
+                    }
                 }
             }
             Err(mpsc::TryRecvError::Empty) => {}
@@ -96,14 +189,35 @@ fn controller_thread(config: ControllerConfig, rx: Receiver<InputLine>) -> ! {
 #[command(author, version)]
 struct Args {
     /// Name of the network interface to use for traceroute
-    #[arg(short = 'i', long)]
-    interface_name: String,
+    #[arg(short = 'i', long, required_unless_present = "replay")]
+    interface_name: Option<String>,
     /// Path to the local PeeringDB SQLite database
     #[arg(short = 'd', long)]
     peeringdb_path: PathBuf,
     /// Disable IPv6 support (IPv6 addresses will be soft, non-crashing errors)
     #[arg(long, default_value_t = false)]
     disable_ipv6: bool,
+    /// Framing of the interface (ethernet, raw, cooked, or null), detected if not set
+    #[arg(long)]
+    link_type: Option<LinkType>,
+    /// Use ping sockets and UDP error queues instead of raw sockets, which works without root
+    /// where net.ipv4.ping_group_range allows (ICMP and UDP probes only, Linux only)
+    #[arg(long, default_value_t = false)]
+    unprivileged: bool,
+    /// IPv4 address to send probes from, which the interface must have. Defaults to its first one
+    #[arg(long, conflicts_with = "replay")]
+    source_ipv4: Option<Ipv4Addr>,
+    /// IPv6 address to send probes from, which the interface must have. Defaults to its first
+    /// global one
+    #[arg(long, conflicts_with = "replay")]
+    source_ipv6: Option<Ipv6Addr>,
+    /// Write every probe and reply to a pcap file per trace in this directory, for debugging
+    #[arg(long, conflicts_with = "unprivileged")]
+    pcap_dir: Option<PathBuf>,
+    /// Answer probes from a pcap or pcapng capture of an earlier trace instead of the network,
+    /// for reproducing it offline. Use the same probe protocol it was captured with
+    #[arg(long, conflicts_with_all = ["interface_name", "unprivileged", "pcap_dir"])]
+    replay: Option<PathBuf>,
     /// The maximum number of hops
     #[arg(long, default_value_t = 64)]
     max_hops: u8,
@@ -126,11 +240,102 @@ struct Args {
     /// Size of the cache for IP to ASN WHOIS lookups
     #[arg(long, default_value_t = 8192)]
     asn_cache_size: usize,
+    /// Number of probes to send to each hop
+    #[arg(long, default_value_t = 1)]
+    probes_per_hop: u8,
+    /// Total size of probes in bytes, IP header included. As small as possible if not set
+    #[arg(long)]
+    probe_size: Option<u16>,
+    /// Discover the path MTU of traces, shrinking probes whenever a router says they're too big.
+    /// Starts from --probe-size, or 1500 bytes
+    #[arg(long, default_value_t = false)]
+    pmtu: bool,
+    /// DSCP to mark probes with (0 to 63)
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=63))]
+    dscp: u8,
+    /// ECN codepoint to mark probes with (0 to 3)
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=3))]
+    ecn: u8,
+    /// IPv6 flow label for trace probes, instead of one derived from the probe or flow. Ignored
+    /// by multipath traces and unprivileged mode
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=0xfffff))]
+    flow_label: Option<u32>,
+    /// Bytes to fill probe padding with, repeated, as hex (like "deadbeef"). Zeros if not set
+    #[arg(long, value_parser = parse_hex)]
+    payload_pattern: Option<HexBytes>,
+    /// For monitors, how long between probing every hop
+    #[arg(long, default_value = "1s")]
+    monitor_interval: humantime::Duration,
+    /// For monitors, how long to wait for a reply before counting a probe as lost
+    #[arg(long, default_value = "2s")]
+    monitor_timeout: humantime::Duration,
+    /// For monitors, the number of recent probes per hop to compute statistics over
+    #[arg(long, default_value_t = 100)]
+    monitor_window: usize,
+    /// Protocol to send probes with (icmp, udp, tcp, or tcp:<port>)
+    #[arg(long, default_value_t = ProbeProtocol::Icmp)]
+    probe_protocol: ProbeProtocol,
+    /// Keep the flow identifier constant for each trace (Paris traceroute) to avoid
+    /// load balancers creating fake links
+    #[arg(long, default_value_t = false)]
+    paris: bool,
+    /// For multipath traces, the confidence that every interface at a hop has been found,
+    /// between 0 and 1 exclusive
+    #[arg(long, default_value_t = 0.95, value_parser = parse_confidence)]
+    multipath_confidence: f64,
+    /// For multipath traces, the maximum number of flows to probe at a single hop
+    #[arg(long, default_value_t = 128, value_parser = clap::value_parser!(u16).range(1..))]
+    multipath_max_flows: u16,
+}
+
+/// Bytes given as hex on the command line. Clap would take a bare `Vec` to mean the flag repeats.
+#[derive(Debug, Clone)]
+struct HexBytes(Vec<u8>);
+
+fn parse_hex(text: &str) -> Result<HexBytes, String> {
+    if text.is_empty() || text.len() % 2 == 1 {
+        return Err("expected a non-empty, even number of hex digits".to_string());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("invalid hex byte at position {}", i))
+        })
+        .collect::<Result<_, _>>()
+        .map(HexBytes)
+}
+
+/// Anything outside of (0, 1) would have multipath traces send no probes at all.
+fn parse_confidence(text: &str) -> Result<f64, String> {
+    let confidence: f64 = text
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    if confidence > 0.0 && confidence < 1.0 {
+        Ok(confidence)
+    } else {
+        Err("expected a number between 0 and 1 exclusive".to_string())
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    let replay = args
+        .replay
+        .as_ref()
+        .map(|path| {
+            ReplayNetwork::from_file(path)
+                .with_context(|| format!("Failed to read capture {}", path.display()))
+        })
+        .transpose()?;
+    // Replays run on their own clock, as fast as the capture allows.
+    let clock: Arc<dyn Clock> = match &replay {
+        Some(replay) => Arc::new(replay.clock().clone()),
+        None => Arc::new(SystemClock),
+    };
+
     let trace_config = TraceConfig {
         max_hops: args.max_hops,
         max_sequential_pending: args.max_sequential_pending,
@@ -139,22 +344,95 @@ fn main() -> anyhow::Result<()> {
         destination_timeout: args.destination_timeout.into(),
         completion_timeout: args.completion_timeout.into(),
         asn_cache_size: args.asn_cache_size,
+        probe_protocol: args.probe_protocol,
+        paris: args.paris,
+        multipath: MultipathConfig {
+            confidence: args.multipath_confidence,
+            max_flows: args.multipath_max_flows,
+        },
+        probes_per_hop: args.probes_per_hop,
+        probe_options: ProbeOptions {
+            size: args.probe_size,
+            dscp: args.dscp,
+            ecn: args.ecn,
+            flow_label: args.flow_label,
+            pattern: args
+                .payload_pattern
+                .map(|pattern| pattern.0)
+                .unwrap_or_default(),
+        },
+        pmtu: args.pmtu,
+        monitor: MonitorConfig {
+            interval: args.monitor_interval.into(),
+            timeout: args.monitor_timeout.into(),
+            window: args.monitor_window,
+        },
+        clock,
+        asn_lookup: Arc::new(WhoisAsnLookup),
     };
     let trace_config = Box::leak(Box::new(trace_config));
 
-    let interface = interface_from_name(&args.interface_name)
-        .with_context(|| format!("Interface {} does not exist", args.interface_name))?;
-    let traceroute_channel = TracerouteChannel::from_interface(interface, !args.disable_ipv6)
-        .context("Failed to initialize traceroute networking (do you need to use sudo?)")?;
-    let peeringdb = PeeringDbManager::connect(args.peeringdb_path)
+    let peeringdb = PeeringDbManager::connect(&args.peeringdb_path)
         .context("Failed to open PeeringDB database")?;
 
-    let config = ControllerConfig {
+    if let Some(replay) = replay {
+        return run(ControllerConfig {
+            traceroute_channel: replay,
+            peeringdb,
+            trace_config,
+            pcap_dir: None,
+        });
+    }
+
+    // Clap makes sure we have an interface unless we're replaying.
+    let interface_name = args.interface_name.unwrap_or_default();
+    let interface = interface_from_name(&interface_name)
+        .with_context(|| format!("Interface {} does not exist", interface_name))?;
+    let source = SourceAddresses {
+        ipv4: args.source_ipv4,
+        ipv6: args.source_ipv6,
+    };
+    let mut traceroute_channel = if args.unprivileged {
+        #[cfg(target_os = "linux")]
+        {
+            TracerouteChannel::unprivileged_with_source(interface, !args.disable_ipv6, source)
+                .context(
+                    "Failed to open ping sockets (is your group in net.ipv4.ping_group_range?)",
+                )?
+        }
+        // Ping sockets with error queues are a Linux thing, so elsewhere it's raw or nothing.
+        #[cfg(not(target_os = "linux"))]
+        anyhow::bail!("Unprivileged mode is only supported on Linux")
+    } else {
+        let link_type = args
+            .link_type
+            .unwrap_or_else(|| LinkType::detect(&interface));
+        TracerouteChannel::from_interface_with_source(
+            interface,
+            !args.disable_ipv6,
+            link_type,
+            source,
+        )
+        .context("Failed to initialize traceroute networking (do you need to use sudo?)")?
+    };
+    if let Some(pcap_dir) = &args.pcap_dir {
+        std::fs::create_dir_all(pcap_dir)
+            .with_context(|| format!("Failed to create {}", pcap_dir.display()))?;
+        traceroute_channel.set_capture(true);
+    }
+
+    run(ControllerConfig {
         traceroute_channel,
         peeringdb,
         trace_config,
-    };
+        pcap_dir: args.pcap_dir,
+    })
+}
 
+/// Run the controller on its own thread, feeding it commands from stdin.
+fn run<T: ProbeTransport + Send + 'static>(
+    config: ControllerConfig<'static, T>,
+) -> anyhow::Result<()> {
     let (tx, rx) = mpsc::channel::<InputLine>();
     let controller = thread::spawn(|| controller_thread(config, rx));
 
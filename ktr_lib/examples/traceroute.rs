@@ -30,6 +30,8 @@ fn main() {
         destination_timeout: Duration::from_secs(3),
         completion_timeout: Duration::from_secs(4),
         asn_cache_size: 10,
+        probes_per_hop: 3,
+        ..Default::default()
     };
     let mut trace = Trace::new(ip, &config);
 
@@ -49,9 +51,10 @@ fn main() {
                         ip,
                         network_info,
                         hostname,
+                        details,
                         ..
                     } => format!(
-                        "{} ({})",
+                        "{} ({}) {:.1?}{}",
                         hostname.as_ref().unwrap_or(&ip.to_string()),
                         match network_info {
                             Some(NetworkInfo {
@@ -60,11 +63,19 @@ fn main() {
                             }) => format!("{:?}, {}", asn, network.name),
                             Some(NetworkInfo { asn, network: None }) => format!("{:?}", asn),
                             None => "AS???".to_string(),
+                        },
+                        details.rtt.min().unwrap_or_default(),
+                        match details.unreachable {
+                            Some(code) => format!(" {}", code.flag()),
+                            None => "".to_string(),
                         }
                     ),
                     Hop::Unused => unreachable!(),
                 };
-                println!("{:3}. {}", i + 1, hop_text);
+                match hop.loss() {
+                    Some(loss) => println!("{:3}. {} {:.0}% loss", i + 1, hop_text, loss * 100.0),
+                    None => println!("{:3}. {}", i + 1, hop_text),
+                }
             }
         }
 
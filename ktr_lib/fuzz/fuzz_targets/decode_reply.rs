@@ -0,0 +1,16 @@
+#![no_main]
+
+use ktr_lib::traceroute_net::{decode_reply, LinkType};
+use libfuzzer_sys::fuzz_target;
+
+// Every framing, since the same bytes mean something different under each.
+fuzz_target!(|frame: &[u8]| {
+    for link_type in [
+        LinkType::Ethernet,
+        LinkType::RawIp,
+        LinkType::LinuxCooked,
+        LinkType::Null,
+    ] {
+        let _ = decode_reply(link_type, frame);
+    }
+});
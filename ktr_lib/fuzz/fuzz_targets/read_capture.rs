@@ -0,0 +1,8 @@
+#![no_main]
+
+use ktr_lib::pcap::read_capture;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|capture: &[u8]| {
+    let _ = read_capture(capture);
+});
@@ -0,0 +1,118 @@
+//! Where traces get the time from. Everything is measured against `TraceConfig::clock`, so
+//! swapping in a `MockClock` makes timeouts and retries happen exactly when a test says so.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::traceroute_net::ReceivedPacket;
+
+/// How far `DelayedDeliveries::poll` moves the clock when nothing arrives, like the real
+/// channel's read timeout.
+const POLL_TIMEOUT: Duration = Duration::from_millis(50);
+
+pub trait Clock: Debug + Send + Sync {
+    /// Monotonic time, for timeouts and round trips.
+    fn now(&self) -> Instant;
+    /// Wall clock time, for timestamps we report.
+    fn system_now(&self) -> SystemTime;
+}
+
+/// The real time. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Time that only moves when told to. It starts at the real time when created, and clones share
+/// the same time, so one can be handed to a trace and another kept to advance it.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            system_start: SystemTime::now(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// How far the clock has been advanced since it was created.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed_nanos
+            .fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + self.elapsed()
+    }
+}
+
+/// Replies waiting to arrive at the time they're stamped with, for transports that fake the
+/// network on a `MockClock`.
+#[derive(Debug, Default)]
+pub(crate) struct DelayedDeliveries {
+    /// In the order they were queued, which breaks ties between replies due at once.
+    in_flight: Vec<ReceivedPacket>,
+}
+
+impl DelayedDeliveries {
+    /// Queue a reply to arrive at its `received_at`.
+    pub(crate) fn push(&mut self, packet: ReceivedPacket) {
+        self.in_flight.push(packet);
+    }
+
+    /// Return the earliest reply due within the poll timeout, moving the clock up to when it
+    /// arrives. Otherwise move the clock by the whole timeout, as if we'd waited for nothing.
+    pub(crate) fn poll(&mut self, clock: &MockClock) -> Option<ReceivedPacket> {
+        let deadline = clock.now() + POLL_TIMEOUT;
+        let next = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, packet)| packet.received_at)
+            .map(|(index, packet)| (index, packet.received_at));
+
+        match next {
+            Some((index, arrives_at)) if arrives_at <= deadline => {
+                let packet = self.in_flight.remove(index);
+                clock.advance(arrives_at.saturating_duration_since(clock.now()));
+                Some(packet)
+            }
+            _ => {
+                clock.advance(POLL_TIMEOUT);
+                None
+            }
+        }
+    }
+}
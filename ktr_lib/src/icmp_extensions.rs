@@ -0,0 +1,340 @@
+//! ICMP multipart extensions (RFC 4884) appended to Time Exceeded and Destination Unreachable
+//! messages, which is where routers put MPLS label stacks (RFC 4950) and information about the
+//! interface a probe arrived on (RFC 5837).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pnet::util::checksum;
+
+/// Where extensions start when a router doesn't fill in the RFC 4884 length field, which plenty
+/// of older MPLS implementations don't.
+const LEGACY_ORIGINAL_DATAGRAM_LENGTH: usize = 128;
+
+const EXTENSION_VERSION: u8 = 2;
+const CLASS_MPLS_LABEL_STACK: u8 = 1;
+const CLASS_INTERFACE_INFORMATION: u8 = 2;
+
+/// One entry of an MPLS label stack, outermost first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct MplsLabel {
+    pub label: u32,
+    /// Formerly the experimental bits.
+    pub traffic_class: u8,
+    pub bottom_of_stack: bool,
+    pub ttl: u8,
+}
+
+/// Which interface an interface information object describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum InterfaceRole {
+    /// The interface the probe arrived on.
+    Incoming,
+    /// A sub-IP component of the incoming interface.
+    SubIpComponent,
+    /// The interface the probe would have been sent out of.
+    Outgoing,
+    /// The next hop the probe would have been sent to.
+    NextHop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct InterfaceInformation {
+    pub role: InterfaceRole,
+    pub if_index: Option<u32>,
+    pub ip: Option<IpAddr>,
+    pub name: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+/// Everything we understood from an extension structure. Unknown objects are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct IcmpExtensions {
+    pub mpls_labels: Vec<MplsLabel>,
+    pub interfaces: Vec<InterfaceInformation>,
+}
+
+impl IcmpExtensions {
+    pub fn is_empty(&self) -> bool {
+        self.mpls_labels.is_empty() && self.interfaces.is_empty()
+    }
+
+    /// Parse extensions from a whole ICMPv4 Time Exceeded or Destination Unreachable message,
+    /// where the length field is in 32-bit words.
+    pub fn from_icmpv4_error(icmp: &[u8]) -> Self {
+        match icmp.get(5) {
+            Some(&length) => {
+                Self::from_payload(icmp.get(8..).unwrap_or_default(), length as usize * 4)
+            }
+            None => Self::default(),
+        }
+    }
+
+    /// Parse extensions from a whole ICMPv6 Time Exceeded or Destination Unreachable message,
+    /// where the length field is in 64-bit words.
+    pub fn from_icmpv6_error(icmp: &[u8]) -> Self {
+        match icmp.get(4) {
+            Some(&length) => {
+                Self::from_payload(icmp.get(8..).unwrap_or_default(), length as usize * 8)
+            }
+            None => Self::default(),
+        }
+    }
+
+    /// `payload` is everything after the ICMP header and `original_length` the length of the
+    /// quoted datagram from the header, in bytes.
+    fn from_payload(payload: &[u8], original_length: usize) -> Self {
+        if original_length > 0 {
+            payload
+                .get(original_length..)
+                .and_then(|extensions| Self::from_structure(extensions, false))
+                .unwrap_or_default()
+        } else {
+            // Without a length we could be looking at the tail of a long quoted packet, so only
+            // trust a structure with a real checksum.
+            payload
+                .get(LEGACY_ORIGINAL_DATAGRAM_LENGTH..)
+                .and_then(|extensions| Self::from_structure(extensions, true))
+                .unwrap_or_default()
+        }
+    }
+
+    fn from_structure(structure: &[u8], require_checksum: bool) -> Option<Self> {
+        if structure.len() < 4 || structure[0] >> 4 != EXTENSION_VERSION {
+            return None;
+        }
+        let expected_checksum = u16::from_be_bytes([structure[2], structure[3]]);
+        if expected_checksum == 0 {
+            if require_checksum {
+                return None;
+            }
+        } else if checksum(structure, 1) != expected_checksum {
+            return None;
+        }
+
+        let mut extensions = Self::default();
+        let mut objects = &structure[4..];
+        while objects.len() >= 4 {
+            let length = u16::from_be_bytes([objects[0], objects[1]]) as usize;
+            if length < 4 || length > objects.len() {
+                break;
+            }
+            let class = objects[2];
+            let c_type = objects[3];
+            let data = &objects[4..length];
+
+            match class {
+                CLASS_MPLS_LABEL_STACK if c_type == 1 => {
+                    extensions
+                        .mpls_labels
+                        .extend(data.chunks_exact(4).map(|entry| {
+                            let entry =
+                                u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                            MplsLabel {
+                                label: entry >> 12,
+                                traffic_class: ((entry >> 9) & 0b111) as u8,
+                                bottom_of_stack: entry & 0x100 != 0,
+                                ttl: entry as u8,
+                            }
+                        }));
+                }
+                CLASS_INTERFACE_INFORMATION => {
+                    if let Some(interface) = parse_interface_information(c_type, data) {
+                        extensions.interfaces.push(interface);
+                    }
+                }
+                _ => {}
+            }
+
+            objects = &objects[length..];
+        }
+
+        Some(extensions)
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    let taken = data.get(..length)?;
+    *data = &data[length..];
+    Some(taken)
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    let bytes = take(data, 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// RFC 5837 section 4.1. The C-Type says which of the optional fields are present, in order.
+fn parse_interface_information(c_type: u8, mut data: &[u8]) -> Option<InterfaceInformation> {
+    let role = match c_type >> 6 {
+        0 => InterfaceRole::Incoming,
+        1 => InterfaceRole::SubIpComponent,
+        2 => InterfaceRole::Outgoing,
+        _ => InterfaceRole::NextHop,
+    };
+
+    let if_index = if c_type & 0x08 != 0 {
+        Some(take_u32(&mut data)?)
+    } else {
+        None
+    };
+
+    let ip = if c_type & 0x04 != 0 {
+        let header = take(&mut data, 4)?;
+        match u16::from_be_bytes([header[0], header[1]]) {
+            1 => {
+                let octets: [u8; 4] = take(&mut data, 4)?.try_into().ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            2 => {
+                let octets: [u8; 16] = take(&mut data, 16)?.try_into().ok()?;
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => return None,
+        }
+    } else {
+        None
+    };
+
+    let name = if c_type & 0x02 != 0 {
+        // The length octet counts itself.
+        let length = *data.first()? as usize;
+        let subobject = take(&mut data, length.max(1))?;
+        let name = String::from_utf8_lossy(&subobject[1..]);
+        Some(name.trim_end_matches('\0').to_string())
+    } else {
+        None
+    };
+
+    let mtu = if c_type & 0x01 != 0 {
+        Some(take_u32(&mut data)?)
+    } else {
+        None
+    };
+
+    Some(InterfaceInformation {
+        role,
+        if_index,
+        ip,
+        name,
+        mtu,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An extension structure with a two-entry MPLS label stack.
+    const MPLS_STRUCTURE: [u8; 16] = [
+        0x20, 0x00, 0x44, 0x2b, // version 2, checksum
+        0x00, 0x0c, 0x01, 0x01, // 12 bytes, MPLS label stack
+        0x03, 0xe9, 0x40, 0x01, // label 16020, TTL 1
+        0x05, 0xdc, 0x51, 0x01, // label 24005, bottom of stack, TTL 1
+    ];
+
+    /// An extension structure with an incoming interface information object for ifIndex 5,
+    /// 192.0.2.1, named "ge-0/0/0", with an MTU of 1500.
+    const INTERFACE_STRUCTURE: [u8; 36] = [
+        0x20, 0x00, 0x13, 0xfa, // version 2, checksum
+        0x00, 0x20, 0x02, 0x0f, // 32 bytes, incoming interface with every field
+        0x00, 0x00, 0x00, 0x05, // ifIndex
+        0x00, 0x01, 0x00, 0x00, 192, 0, 2, 1, // IPv4 address
+        0x0c, b'g', b'e', b'-', b'0', b'/', b'0', b'/', b'0', 0x00, 0x00, 0x00, // name
+        0x00, 0x00, 0x05, 0xdc, // MTU
+    ];
+
+    /// An ICMPv4 Time Exceeded quoting a 128 byte original datagram, with the RFC 4884 length
+    /// in 32-bit words, or zero like a router that predates it.
+    fn time_exceeded_v4(length_words: u8, structure: &[u8]) -> Vec<u8> {
+        let mut message = vec![11, 0, 0, 0, 0, length_words, 0, 0];
+        let mut original = vec![0; LEGACY_ORIGINAL_DATAGRAM_LENGTH];
+        original[..4].copy_from_slice(&[0x45, 0x00, 0x00, 0x54]);
+        message.extend(original);
+        message.extend_from_slice(structure);
+        message
+    }
+
+    #[test]
+    fn parses_mpls_label_stack() {
+        let extensions = IcmpExtensions::from_icmpv4_error(&time_exceeded_v4(32, &MPLS_STRUCTURE));
+        assert_eq!(
+            extensions.mpls_labels,
+            vec![
+                MplsLabel {
+                    label: 16020,
+                    traffic_class: 0,
+                    bottom_of_stack: false,
+                    ttl: 1,
+                },
+                MplsLabel {
+                    label: 24005,
+                    traffic_class: 0,
+                    bottom_of_stack: true,
+                    ttl: 1,
+                },
+            ]
+        );
+        assert!(extensions.interfaces.is_empty());
+    }
+
+    #[test]
+    fn parses_interface_information() {
+        // ICMPv6 Time Exceeded, with the length in 64-bit words.
+        let mut message = vec![3, 0, 0, 0, 16, 0, 0, 0];
+        message.extend([0; LEGACY_ORIGINAL_DATAGRAM_LENGTH]);
+        message.extend_from_slice(&INTERFACE_STRUCTURE);
+
+        let extensions = IcmpExtensions::from_icmpv6_error(&message);
+        assert_eq!(
+            extensions.interfaces,
+            vec![InterfaceInformation {
+                role: InterfaceRole::Incoming,
+                if_index: Some(5),
+                ip: Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+                name: Some("ge-0/0/0".to_string()),
+                mtu: Some(1500),
+            }]
+        );
+        assert!(extensions.mpls_labels.is_empty());
+    }
+
+    #[test]
+    fn ignores_broken_structures() {
+        let mut bad_checksum = MPLS_STRUCTURE;
+        bad_checksum[3] ^= 0xff;
+        // The object claims to run past the end of the structure.
+        let mut bad_length = MPLS_STRUCTURE;
+        bad_length[5] = 0x40;
+        let fixed_checksum = checksum(&bad_length, 1);
+        bad_length[2..4].copy_from_slice(&fixed_checksum.to_be_bytes());
+
+        for structure in [&bad_checksum[..], &bad_length[..], &MPLS_STRUCTURE[..6]] {
+            let message = time_exceeded_v4(32, structure);
+            assert!(IcmpExtensions::from_icmpv4_error(&message).is_empty());
+        }
+        // A length pointing past the end of the message.
+        let message = time_exceeded_v4(255, &MPLS_STRUCTURE);
+        assert!(IcmpExtensions::from_icmpv4_error(&message).is_empty());
+        assert!(IcmpExtensions::from_icmpv4_error(&message[..5]).is_empty());
+    }
+
+    #[test]
+    fn finds_extensions_without_rfc_4884_length() {
+        let message = time_exceeded_v4(0, &MPLS_STRUCTURE);
+        let extensions = IcmpExtensions::from_icmpv4_error(&message);
+        assert_eq!(extensions.mpls_labels.len(), 2);
+
+        // Without a checksum, the bytes there could just as well be more of the quoted packet.
+        let mut unchecked = MPLS_STRUCTURE;
+        unchecked[2..4].copy_from_slice(&[0, 0]);
+        let message = time_exceeded_v4(0, &unchecked);
+        assert!(IcmpExtensions::from_icmpv4_error(&message).is_empty());
+    }
+}
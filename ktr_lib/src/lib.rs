@@ -1,5 +1,11 @@
+pub mod clock;
+pub mod icmp_extensions;
 pub mod metadata;
+pub mod multipath;
+pub mod pcap;
 pub mod peeringdb;
+pub mod replay_net;
+pub mod simulated_net;
 pub mod trace;
 pub mod traceroute_net;
 pub mod whois_net;
@@ -0,0 +1,384 @@
+//! Minimal writer for classic pcap files, which Wireshark and tcpdump open directly. Packets are
+//! stored from the IP header on, as the raw IP link type.
+//!
+//! There's also a reader for both pcap and pcapng, so captures from here or from tcpdump can be
+//! replayed.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use crate::traceroute_net::LinkType;
+
+/// Magic number for microsecond timestamps, written in our own byte order.
+const MAGIC: u32 = 0xa1b2c3d4;
+/// Magic number for nanosecond timestamps, which we only read.
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+/// The pcapng Section Header Block type, which is also what a pcapng file starts with.
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Interface option giving the timestamp resolution.
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+/// LINKTYPE_RAW: the packet starts with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    /// Create (or truncate) a capture file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the file header, after which packets can be added.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&MAGIC.to_ne_bytes())?;
+        writer.write_all(&VERSION_MAJOR.to_ne_bytes())?;
+        writer.write_all(&VERSION_MINOR.to_ne_bytes())?;
+        // Timezone offset and timestamp accuracy, which are always zero.
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&SNAPLEN.to_ne_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_ne_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write_packet(&mut self, timestamp: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let length = packet.len().min(SNAPLEN as usize);
+        self.writer
+            .write_all(&(since_epoch.as_secs() as u32).to_ne_bytes())?;
+        self.writer
+            .write_all(&since_epoch.subsec_micros().to_ne_bytes())?;
+        self.writer.write_all(&(length as u32).to_ne_bytes())?;
+        self.writer
+            .write_all(&(packet.len() as u32).to_ne_bytes())?;
+        self.writer.write_all(&packet[..length])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PcapError {
+    #[error("IO error reading capture: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not a pcap or pcapng file")]
+    UnknownFormat,
+    #[error("Unsupported link type {0}")]
+    UnsupportedLinkType(u32),
+    #[error("Capture is truncated or malformed")]
+    Malformed,
+}
+
+/// A packet read from a capture file.
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub timestamp: SystemTime,
+    /// Framing of `data`, from the interface it was captured on.
+    pub link_type: LinkType,
+    pub data: Vec<u8>,
+}
+
+/// Read every packet in a pcap or pcapng file, in file order.
+pub fn read_capture(mut reader: impl Read) -> Result<Vec<CapturedFrame>, PcapError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let cursor = Cursor::new(&bytes, false);
+    match cursor.peek_u32()? {
+        PCAPNG_SECTION_HEADER => read_pcapng(cursor),
+        _ => read_pcap(cursor),
+    }
+}
+
+/// Map a LINKTYPE_* value onto how we split frames.
+fn link_type_from_pcap(link_type: u32) -> Result<LinkType, PcapError> {
+    match link_type {
+        // BSD loopback, and OpenBSD loopback with the family in network byte order. We go by
+        // the IP version either way.
+        0 | 108 => Ok(LinkType::Null),
+        1 => Ok(LinkType::Ethernet),
+        // Raw IP, its OpenBSD number, and the IPv4 and IPv6 only versions.
+        101 | 14 | 228 | 229 => Ok(LinkType::RawIp),
+        113 => Ok(LinkType::LinuxCooked),
+        other => Err(PcapError::UnsupportedLinkType(other)),
+    }
+}
+
+fn read_pcap(mut cursor: Cursor) -> Result<Vec<CapturedFrame>, PcapError> {
+    let magic = cursor.take(4)?;
+    let magic = [magic[0], magic[1], magic[2], magic[3]];
+    let nanos = if u32::from_le_bytes(magic) == MAGIC || u32::from_le_bytes(magic) == MAGIC_NANOS {
+        cursor.big_endian = false;
+        u32::from_le_bytes(magic) == MAGIC_NANOS
+    } else if u32::from_be_bytes(magic) == MAGIC || u32::from_be_bytes(magic) == MAGIC_NANOS {
+        cursor.big_endian = true;
+        u32::from_be_bytes(magic) == MAGIC_NANOS
+    } else {
+        return Err(PcapError::UnknownFormat);
+    };
+    // Version, timezone, accuracy and snaplen.
+    cursor.take(16)?;
+    let link_type = link_type_from_pcap(cursor.u32()?)?;
+
+    let mut frames = Vec::new();
+    while !cursor.is_empty() {
+        let seconds = cursor.u32()? as u64;
+        let fraction = cursor.u32()? as u64;
+        let captured_length = cursor.u32()? as usize;
+        let _original_length = cursor.u32()?;
+        let subsecond = if nanos {
+            Duration::from_nanos(fraction)
+        } else {
+            Duration::from_micros(fraction)
+        };
+        frames.push(CapturedFrame {
+            timestamp: timestamp(seconds, subsecond)?,
+            link_type,
+            data: cursor.take(captured_length)?.to_vec(),
+        });
+    }
+    Ok(frames)
+}
+
+/// Timestamps can be anything up to 64 bits of seconds in pcapng, which `SystemTime` can't hold.
+fn timestamp(seconds: u64, subsecond: Duration) -> Result<SystemTime, PcapError> {
+    Duration::from_secs(seconds)
+        .checked_add(subsecond)
+        .and_then(|since_epoch| UNIX_EPOCH.checked_add(since_epoch))
+        .ok_or(PcapError::Malformed)
+}
+
+/// An interface from a pcapng Interface Description Block.
+struct PcapngInterface {
+    link_type: Result<LinkType, u32>,
+    /// Timestamp units per second.
+    ticks_per_second: u64,
+}
+
+fn read_pcapng(mut cursor: Cursor) -> Result<Vec<CapturedFrame>, PcapError> {
+    let mut interfaces = Vec::new();
+    let mut frames = Vec::new();
+
+    while !cursor.is_empty() {
+        if cursor.peek_u32()? == PCAPNG_SECTION_HEADER {
+            // The byte order magic comes after the type and length, and applies to both.
+            let header = cursor.bytes.get(cursor.position..cursor.position + 12);
+            let byte_order = &header.ok_or(PcapError::Malformed)?[8..12];
+            let byte_order = [byte_order[0], byte_order[1], byte_order[2], byte_order[3]];
+            cursor.big_endian = if u32::from_be_bytes(byte_order) == PCAPNG_BYTE_ORDER_MAGIC {
+                true
+            } else if u32::from_le_bytes(byte_order) == PCAPNG_BYTE_ORDER_MAGIC {
+                false
+            } else {
+                return Err(PcapError::UnknownFormat);
+            };
+            // Interface numbering starts over in each section.
+            interfaces.clear();
+        }
+
+        let block_type = cursor.u32()?;
+        let total_length = cursor.u32()? as usize;
+        let body = cursor.take(total_length.checked_sub(12).ok_or(PcapError::Malformed)?)?;
+        cursor.u32()?;
+        let mut body = Cursor::new(body, cursor.big_endian);
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = body.u16()? as u32;
+                // Reserved and snaplen.
+                body.take(6)?;
+                let mut ticks_per_second = 1_000_000;
+                while body.remaining() >= 4 {
+                    let code = body.u16()?;
+                    let length = body.u16()? as usize;
+                    let value = body.take(length)?;
+                    body.take((4 - length % 4) % 4)?;
+                    if code == PCAPNG_OPTION_TSRESOL {
+                        let resolution = *value.first().ok_or(PcapError::Malformed)?;
+                        // The top bit picks powers of two instead of ten.
+                        let (base, exponent) = if resolution & 0x80 == 0 {
+                            (10u64, resolution as u32)
+                        } else {
+                            (2u64, (resolution & 0x7f) as u32)
+                        };
+                        ticks_per_second =
+                            base.checked_pow(exponent).ok_or(PcapError::Malformed)?;
+                    }
+                    if code == 0 {
+                        break;
+                    }
+                }
+                interfaces.push(PcapngInterface {
+                    link_type: link_type_from_pcap(link_type).map_err(|_| link_type),
+                    ticks_per_second,
+                });
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = interfaces
+                    .get(body.u32()? as usize)
+                    .ok_or(PcapError::Malformed)?;
+                let ticks = (body.u32()? as u64) << 32 | body.u32()? as u64;
+                let captured_length = body.u32()? as usize;
+                let _original_length = body.u32()?;
+                let link_type = interface
+                    .link_type
+                    .map_err(PcapError::UnsupportedLinkType)?;
+                let seconds = ticks / interface.ticks_per_second;
+                let subsecond_ticks = ticks % interface.ticks_per_second;
+                let subsecond = Duration::from_nanos(
+                    (subsecond_ticks as u128 * 1_000_000_000 / interface.ticks_per_second as u128)
+                        as u64,
+                );
+                frames.push(CapturedFrame {
+                    timestamp: timestamp(seconds, subsecond)?,
+                    link_type,
+                    data: body.take(captured_length)?.to_vec(),
+                });
+            }
+            // Section headers were handled above, simple packets have no timestamp, and
+            // everything else isn't packets.
+            _ => {}
+        }
+    }
+    Ok(frames)
+}
+
+/// Reads numbers in the capture's byte order, failing on truncation instead of panicking.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    big_endian: bool,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8], big_endian: bool) -> Self {
+        Self {
+            bytes,
+            position: 0,
+            big_endian,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], PcapError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .ok_or(PcapError::Malformed)?;
+        let taken = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(PcapError::Malformed)?;
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, PcapError> {
+        let bytes = self.take(2)?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&mut self) -> Result<u32, PcapError> {
+        let bytes = self.take(4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// The next four bytes, little endian, for telling formats apart before the byte order is
+    /// known. The pcapng section header type reads the same either way.
+    fn peek_u32(&self) -> Result<u32, PcapError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + 4)
+            .ok_or(PcapError::Malformed)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_ne_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_headers_and_packets() {
+        let packet: Vec<u8> = (0..28).collect();
+        let timestamp = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_packet(timestamp, &packet).unwrap();
+        let bytes = writer.writer;
+
+        // Global header.
+        assert_eq!(u32_at(&bytes, 0), MAGIC);
+        assert_eq!(u16_at(&bytes, 4), 2);
+        assert_eq!(u16_at(&bytes, 6), 4);
+        assert_eq!(&bytes[8..16], &[0; 8]);
+        assert_eq!(u32_at(&bytes, 16), 65535);
+        assert_eq!(u32_at(&bytes, 20), 101);
+
+        // Record header, with the timestamp cut to microseconds.
+        assert_eq!(u32_at(&bytes, 24), 1_700_000_000);
+        assert_eq!(u32_at(&bytes, 28), 123_456);
+        assert_eq!(u32_at(&bytes, 32), 28);
+        assert_eq!(u32_at(&bytes, 36), 28);
+        assert_eq!(&bytes[40..], &packet[..]);
+
+        let frames = read_capture(bytes.as_slice()).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            frames[0].timestamp,
+            UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_000)
+        );
+        assert_eq!(frames[0].link_type, LinkType::RawIp);
+        assert_eq!(frames[0].data, packet);
+    }
+
+    #[test]
+    fn truncates_packets_to_snaplen() {
+        let packet = vec![0x45; SNAPLEN as usize + 100];
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_packet(UNIX_EPOCH, &packet).unwrap();
+        let bytes = writer.writer;
+
+        assert_eq!(u32_at(&bytes, 32), SNAPLEN);
+        assert_eq!(u32_at(&bytes, 36), SNAPLEN + 100);
+        assert_eq!(bytes.len(), 40 + SNAPLEN as usize);
+    }
+}
@@ -31,6 +31,14 @@ impl PeeringDbManager {
         })
     }
 
+    /// Build an in-memory database from SQL, like a fixture with only the networks a test
+    /// or replay needs. Only `peeringdb_network` and `peeringdb_organization` are read.
+    pub fn from_sql(sql: &str) -> Result<Self, PeeringDbError> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(sql)?;
+        Ok(PeeringDbManager { conn })
+    }
+
     pub fn network_by_asn(&self, asn: Asn) -> Result<Option<Network>, PeeringDbError> {
         self.conn
             .query_row(
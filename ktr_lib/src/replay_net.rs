@@ -0,0 +1,218 @@
+//! Replays a capture of a real trace, so it can be re-run without raw sockets or the network it
+//! happened on. Probes in the capture are matched to the ones a new trace sends by protocol,
+//! destination and TTL, in order, and their replies come back with the same delay as they did
+//! originally, decoded from the original bytes.
+//!
+//! Everything runs on a `MockClock`, like `SimulatedNetwork`, so traces over this should use its
+//! clock. Combine with `StaticAsnLookup` and `PeeringDbManager::from_sql` to keep lookups
+//! offline too.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{Clock, DelayedDeliveries, MockClock};
+use crate::pcap::{read_capture, PcapError};
+use crate::traceroute_net::{
+    decode_probe, decode_reply, reply_ttl, FlowId, PacketId, ProbeHeader, ProbeOptions,
+    ProbeProtocol, ProbeTransport, ReceivedPacket, TracerouteError, TracerouteResult,
+};
+
+/// A probe from the capture and everything it caused, by how long after it each reply came.
+#[derive(Debug)]
+struct RecordedProbe {
+    protocol: ProbeProtocol,
+    dst_ip: IpAddr,
+    ttl: u8,
+    size: u16,
+    header: ProbeHeader,
+    /// Replies by how long after the probe they came, with the TTL they came with.
+    replies: Vec<(Duration, TracerouteResult, Option<u8>)>,
+}
+
+/// Implements `ProbeTransport` by answering probes the way the captured network did. Probes
+/// nothing in the capture matches go unanswered, so a replay only makes sense with the same
+/// probe protocol the capture was made with.
+#[derive(Debug)]
+pub struct ReplayNetwork {
+    clock: MockClock,
+    /// Probes from the capture that nothing has been matched to yet, in capture order.
+    recorded: Vec<RecordedProbe>,
+    /// Replies stamped with when they arrive, in the order they were sent.
+    in_flight: DelayedDeliveries,
+    /// Headers of the recorded probe each sent one was matched to, by the sent probe's ID.
+    sent_headers: HashMap<PacketId, ProbeHeader>,
+}
+
+impl RecordedProbe {
+    /// Whether a reply with this probe's ID really belongs to it. TCP replies don't quote the
+    /// probe, so they have to come from its destination back to the port it was sent from, or
+    /// they're just the host's own connections.
+    fn answered_by(&self, result: &TracerouteResult) -> bool {
+        match (result, self.protocol, self.header.source_port) {
+            (TracerouteResult::TcpReply { .. }, ProbeProtocol::Tcp { port }, Some(source_port)) => {
+                result.is_tcp_reply_to(SocketAddr::new(self.dst_ip, port), source_port)
+            }
+            (TracerouteResult::TcpReply { .. }, ..) => false,
+            _ => true,
+        }
+    }
+}
+
+impl ReplayNetwork {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, PcapError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a pcap or pcapng capture. Anything that isn't a probe or a reply to one is skipped,
+    /// so a whole tcpdump of the interface works.
+    pub fn from_reader(reader: impl Read) -> Result<Self, PcapError> {
+        Self::with_clock(reader, MockClock::new())
+    }
+
+    pub fn with_clock(reader: impl Read, clock: MockClock) -> Result<Self, PcapError> {
+        let mut recorded: Vec<RecordedProbe> = Vec::new();
+        // Most recent probe for each ID, with when it was sent. Retries reuse IDs, so replies
+        // belong to the latest send before them.
+        let mut latest_send: HashMap<PacketId, (usize, SystemTime)> = HashMap::new();
+
+        for frame in read_capture(reader)? {
+            // Frames too broken to decode can't be matched to anything either.
+            if let Ok(Some(result)) = decode_reply(frame.link_type, &frame.data) {
+                let probe = latest_send.get(&result.id());
+                if let Some(&(index, sent_at)) =
+                    probe.filter(|(index, _)| recorded[*index].answered_by(&result))
+                {
+                    let delay = frame.timestamp.duration_since(sent_at).unwrap_or_default();
+                    let ttl = reply_ttl(frame.link_type, &frame.data);
+                    recorded[index].replies.push((delay, result, ttl));
+                }
+            } else if let Some(probe) = decode_probe(frame.link_type, &frame.data) {
+                latest_send.insert(probe.id, (recorded.len(), frame.timestamp));
+                recorded.push(RecordedProbe {
+                    protocol: probe.protocol,
+                    dst_ip: probe.dst_ip,
+                    ttl: probe.ttl,
+                    size: probe.size,
+                    header: probe.header,
+                    replies: Vec::new(),
+                });
+            }
+        }
+
+        Ok(Self {
+            clock,
+            recorded,
+            in_flight: DelayedDeliveries::default(),
+            sent_headers: HashMap::new(),
+        })
+    }
+
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// Number of probes from the capture that haven't been matched to one sent yet.
+    pub fn remaining_probes(&self) -> usize {
+        self.recorded.len()
+    }
+}
+
+/// The same reply, but to a different probe.
+fn with_id(result: TracerouteResult, new_id: PacketId) -> TracerouteResult {
+    match result {
+        TracerouteResult::IcmpReply(ip, _) => TracerouteResult::IcmpReply(ip, new_id),
+        TracerouteResult::IcmpTimeExceeded(ip, _, extensions, quoted) => {
+            TracerouteResult::IcmpTimeExceeded(ip, new_id, extensions, quoted)
+        }
+        TracerouteResult::IcmpDestinationUnreachable {
+            ip,
+            id: _,
+            dst_ip,
+            code,
+            extensions,
+            quoted,
+        } => TracerouteResult::IcmpDestinationUnreachable {
+            ip,
+            id: new_id,
+            dst_ip,
+            code,
+            extensions,
+            quoted,
+        },
+        TracerouteResult::IcmpPortUnreachable(ip, _, extensions, quoted) => {
+            TracerouteResult::IcmpPortUnreachable(ip, new_id, extensions, quoted)
+        }
+        TracerouteResult::IcmpPacketTooBig {
+            ip,
+            id: _,
+            dst_ip,
+            mtu,
+            extensions,
+            quoted,
+        } => TracerouteResult::IcmpPacketTooBig {
+            ip,
+            id: new_id,
+            dst_ip,
+            mtu,
+            extensions,
+            quoted,
+        },
+        TracerouteResult::TcpReply {
+            ip,
+            id: _,
+            source_port,
+            destination_port,
+        } => TracerouteResult::TcpReply {
+            ip,
+            id: new_id,
+            source_port,
+            destination_port,
+        },
+    }
+}
+
+impl ProbeTransport for ReplayNetwork {
+    fn send_probe(
+        &mut self,
+        protocol: ProbeProtocol,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        _flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        // Path MTU discovery sends the same TTL at several sizes, so those have to match too.
+        let matching = self.recorded.iter().position(|probe| {
+            probe.protocol == protocol
+                && probe.dst_ip == dst_ip
+                && probe.ttl == ttl
+                && options.size.unwrap_or(probe.size) == probe.size
+        });
+        if let Some(index) = matching {
+            let sent_at = self.clock.now();
+            let probe = self.recorded.remove(index);
+            self.sent_headers.insert(id, probe.header);
+            for (delay, result, ttl) in probe.replies {
+                self.in_flight.push(ReceivedPacket {
+                    result: with_id(result, id),
+                    received_at: sent_at + delay,
+                    ttl,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<ReceivedPacket>, TracerouteError> {
+        Ok(self.in_flight.poll(&self.clock))
+    }
+
+    /// Headers as they were captured, so replays show the same rewrites.
+    fn sent_header(&self, id: PacketId) -> Option<ProbeHeader> {
+        self.sent_headers.get(&id).copied()
+    }
+}
@@ -0,0 +1,351 @@
+//! An in-process network for running traces without touching a real one. Each destination gets
+//! a list of hops that answer (or don't) with configurable delay, loss and rate limiting, and
+//! everything runs on a `MockClock` so timeouts and retries play out the same way every time.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::clock::{Clock, DelayedDeliveries, MockClock};
+use crate::icmp_extensions::IcmpExtensions;
+use crate::traceroute_net::{
+    FlowId, PacketId, ProbeHeader, ProbeOptions, ProbeProtocol, ProbeTransport, ReceivedPacket,
+    TracerouteError, TracerouteResult, UnreachableCode,
+};
+
+/// Where simulated probes come from.
+const SOURCE_IPV4: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+const SOURCE_IPV6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+/// Port simulated TCP probes come from, which replies to them go back to.
+const SOURCE_PORT: u16 = 0xc000;
+
+/// TTL every simulated reply starts out with.
+const INITIAL_TTL: u8 = 64;
+
+/// TTL a reply arrives with after going back through `routers` routers.
+fn reply_ttl(routers: usize) -> u8 {
+    INITIAL_TTL.saturating_sub(routers.min(u8::MAX as usize) as u8)
+}
+
+/// One router along a simulated path, answering probes that expire there.
+#[derive(Debug, Clone)]
+pub struct SimulatedHop {
+    /// Who sends the Time Exceeded, or `None` for a router that never does.
+    pub ip: Option<IpAddr>,
+    /// Round trip time for probes expiring here.
+    pub delay: Duration,
+    /// Chance from 0 to 1 that a probe expiring here goes unanswered.
+    pub loss: f64,
+    /// Most errors this router sends per second, like control plane policing.
+    pub rate_limit: Option<u32>,
+    /// Drop this many probes before answering any, like a router that has to resolve ARP first.
+    pub ignored_probes: u32,
+    /// MTU of the link after this router. Probes bigger than it get a Packet Too Big from here,
+    /// or vanish if the router never says anything.
+    pub mtu: Option<u16>,
+    /// Remark probes it forwards with this DSCP, like a router enforcing QoS policy.
+    pub dscp: Option<u8>,
+    /// Rewrite the source address of probes it forwards to this, like a NAT.
+    pub nat: Option<IpAddr>,
+    /// Routers its replies go back through, if not as many as probes came through to get here,
+    /// like on an asymmetric path.
+    pub reverse_hops: Option<u8>,
+}
+
+impl SimulatedHop {
+    pub fn router(ip: IpAddr, delay: Duration) -> Self {
+        Self {
+            ip: Some(ip),
+            delay,
+            loss: 0.0,
+            rate_limit: None,
+            ignored_probes: 0,
+            mtu: None,
+            dscp: None,
+            nat: None,
+            reverse_hops: None,
+        }
+    }
+
+    /// A hop that forwards probes but never says anything, shown as `*` by classic traceroute.
+    pub fn silent() -> Self {
+        Self {
+            ip: None,
+            delay: Duration::ZERO,
+            loss: 0.0,
+            rate_limit: None,
+            ignored_probes: 0,
+            mtu: None,
+            dscp: None,
+            nat: None,
+            reverse_hops: None,
+        }
+    }
+}
+
+/// What happens to probes that make it past every hop.
+#[derive(Debug, Clone)]
+pub enum SimulatedDestination {
+    /// Answer like a normal host: an Echo Reply, Port Unreachable, or SYN-ACK depending on the
+    /// probe protocol.
+    Reply { delay: Duration },
+    /// Never answer, like a host behind a firewall that drops probes.
+    Silent,
+    /// Get a Destination Unreachable from `ip`, usually the last router.
+    Unreachable {
+        ip: IpAddr,
+        code: UnreachableCode,
+        delay: Duration,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulatedPath {
+    /// Hops in TTL order, not including the destination.
+    pub hops: Vec<SimulatedHop>,
+    pub destination: SimulatedDestination,
+}
+
+/// A probe as it was handed to the network, for checking what a trace sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SentProbe {
+    pub protocol: ProbeProtocol,
+    pub dst_ip: IpAddr,
+    pub ttl: u8,
+    pub id: PacketId,
+    pub flow: Option<FlowId>,
+    pub size: Option<u16>,
+    /// Simulated probes only have addresses, IDs and a traffic class, no transport header.
+    pub header: ProbeHeader,
+    pub sent_at: Instant,
+}
+
+/// Implements `ProbeTransport` over a set of simulated paths. Probes to destinations without a
+/// path vanish. Traces run over this should use its clock, from `clock`.
+#[derive(Debug)]
+pub struct SimulatedNetwork {
+    clock: MockClock,
+    paths: HashMap<IpAddr, SimulatedPath>,
+    /// Replies stamped with when they arrive, in the order they were sent.
+    in_flight: DelayedDeliveries,
+    /// Seeded so that loss is the same on every run.
+    rng: StdRng,
+    /// Probes seen by each hop, by destination and TTL, for `ignored_probes`.
+    probes_seen: HashMap<(IpAddr, u8), u32>,
+    /// Start of the current second and errors sent in it, by router.
+    rate_limits: HashMap<IpAddr, (Instant, u32)>,
+    sent: Vec<SentProbe>,
+}
+
+impl SimulatedNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self::with_clock(MockClock::new(), seed)
+    }
+
+    pub fn with_clock(clock: MockClock, seed: u64) -> Self {
+        Self {
+            clock,
+            paths: HashMap::new(),
+            in_flight: DelayedDeliveries::default(),
+            rng: StdRng::seed_from_u64(seed),
+            probes_seen: HashMap::new(),
+            rate_limits: HashMap::new(),
+            sent: Vec::new(),
+        }
+    }
+
+    /// Route probes to `dst_ip` along `path`, replacing any existing path.
+    pub fn add_path(&mut self, dst_ip: IpAddr, path: SimulatedPath) {
+        self.paths.insert(dst_ip, path);
+    }
+
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// Every probe sent so far, oldest first.
+    pub fn sent_probes(&self) -> &[SentProbe] {
+        &self.sent
+    }
+
+    /// Work out what a probe causes, if anything, when it comes back, and with what TTL.
+    fn respond(
+        &mut self,
+        protocol: ProbeProtocol,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        size: Option<u16>,
+        header: ProbeHeader,
+    ) -> Option<(Duration, TracerouteResult, u8)> {
+        let now = self.clock.now();
+        let path = self.paths.get(&dst_ip)?;
+        let index = ttl.checked_sub(1)? as usize;
+
+        // Every router before the one the probe expires at has to forward it first.
+        let forwarding = &path.hops[..index.min(path.hops.len())];
+        if let Some(size) = size {
+            if let Some((routers, hop)) = forwarding
+                .iter()
+                .enumerate()
+                .find(|(_, hop)| matches!(hop.mtu, Some(mtu) if mtu < size))
+            {
+                let result = TracerouteResult::IcmpPacketTooBig {
+                    ip: hop.ip?,
+                    id,
+                    dst_ip,
+                    mtu: hop.mtu,
+                    extensions: IcmpExtensions::default(),
+                    quoted: None,
+                };
+                let ttl = reply_ttl(hop.reverse_hops.map_or(routers, usize::from));
+                return Some((hop.delay, result, ttl));
+            }
+        }
+
+        let Some(hop) = path.hops.get(index) else {
+            return match path.destination {
+                SimulatedDestination::Reply { delay } => {
+                    let result = match protocol {
+                        ProbeProtocol::Icmp => TracerouteResult::IcmpReply(dst_ip, id),
+                        ProbeProtocol::Udp => TracerouteResult::IcmpPortUnreachable(
+                            dst_ip,
+                            id,
+                            IcmpExtensions::default(),
+                            None,
+                        ),
+                        ProbeProtocol::Tcp { port } => TracerouteResult::TcpReply {
+                            ip: dst_ip,
+                            id,
+                            source_port: port,
+                            destination_port: SOURCE_PORT,
+                        },
+                    };
+                    Some((delay, result, reply_ttl(path.hops.len())))
+                }
+                SimulatedDestination::Silent => None,
+                SimulatedDestination::Unreachable { ip, code, delay } => {
+                    let result = if code == UnreachableCode::Port {
+                        TracerouteResult::IcmpPortUnreachable(
+                            ip,
+                            id,
+                            IcmpExtensions::default(),
+                            None,
+                        )
+                    } else {
+                        TracerouteResult::IcmpDestinationUnreachable {
+                            ip,
+                            id,
+                            dst_ip,
+                            code,
+                            extensions: IcmpExtensions::default(),
+                            quoted: None,
+                        }
+                    };
+                    Some((delay, result, reply_ttl(path.hops.len())))
+                }
+            };
+        };
+
+        let seen = self.probes_seen.entry((dst_ip, ttl)).or_default();
+        *seen += 1;
+        if *seen <= hop.ignored_probes {
+            return None;
+        }
+        let ip = hop.ip?;
+        if hop.loss > 0.0 && self.rng.gen_bool(hop.loss) {
+            return None;
+        }
+        if let Some(rate_limit) = hop.rate_limit {
+            let (window_start, sent) = self.rate_limits.entry(ip).or_insert((now, 0));
+            if now.saturating_duration_since(*window_start) >= Duration::from_secs(1) {
+                *window_start = now;
+                *sent = 0;
+            }
+            if *sent >= rate_limit {
+                return None;
+            }
+            *sent += 1;
+        }
+
+        // The quoted probe shows whatever the routers before this one did to it, and the last
+        // bit of TTL it had left.
+        let mut quoted = ProbeHeader { ttl: 1, ..header };
+        for hop in forwarding {
+            if let Some(dscp) = hop.dscp {
+                quoted.dscp = dscp;
+            }
+            if let Some(nat) = hop.nat {
+                quoted.source_ip = Some(nat);
+            }
+        }
+        Some((
+            hop.delay,
+            TracerouteResult::IcmpTimeExceeded(ip, id, IcmpExtensions::default(), Some(quoted)),
+            reply_ttl(hop.reverse_hops.map_or(index, usize::from)),
+        ))
+    }
+}
+
+impl ProbeTransport for SimulatedNetwork {
+    fn send_probe(
+        &mut self,
+        protocol: ProbeProtocol,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        let sent_at = self.clock.now();
+        let header = ProbeHeader {
+            source_ip: Some(match dst_ip {
+                IpAddr::V4(_) => IpAddr::V4(SOURCE_IPV4),
+                IpAddr::V6(_) => IpAddr::V6(SOURCE_IPV6),
+            }),
+            ttl,
+            dscp: options.dscp & 0x3f,
+            ecn: options.ecn & 0x3,
+            id: dst_ip.is_ipv4().then_some(id.0),
+            source_port: None,
+            destination_port: None,
+            checksum: None,
+        };
+        self.sent.push(SentProbe {
+            protocol,
+            dst_ip,
+            ttl,
+            id,
+            flow,
+            size: options.size,
+            header,
+            sent_at,
+        });
+        if let Some((delay, result, reply_ttl)) =
+            self.respond(protocol, dst_ip, ttl, id, options.size, header)
+        {
+            self.in_flight.push(ReceivedPacket {
+                result,
+                received_at: sent_at + delay,
+                ttl: Some(reply_ttl),
+            });
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<ReceivedPacket>, TracerouteError> {
+        Ok(self.in_flight.poll(&self.clock))
+    }
+
+    fn sent_header(&self, id: PacketId) -> Option<ProbeHeader> {
+        self.sent
+            .iter()
+            .rev()
+            .find(|probe| probe.id == id)
+            .map(|probe| probe.header)
+    }
+}
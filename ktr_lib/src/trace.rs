@@ -2,16 +2,27 @@
 
 use std::io;
 use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 
 use quick_cache::unsync::Cache;
 use rand::Rng;
 use thiserror::Error;
 
+use crate::clock::{Clock, SystemClock};
+use crate::icmp_extensions::IcmpExtensions;
 use crate::metadata::{Asn, Network};
+use crate::multipath::MultipathConfig;
 use crate::peeringdb::{PeeringDbError, PeeringDbManager};
-use crate::traceroute_net::{PacketId, TracerouteChannel, TracerouteError, TracerouteResult};
-use crate::whois_net::{AsnFinder, AsnResult};
+use crate::traceroute_net::{
+    FlowId, PacketId, ProbeHeader, ProbeOptions, ProbeProtocol, ProbeTransport, ReceivedPacket,
+    TracerouteError, TracerouteResult, UnreachableCode,
+};
+use crate::whois_net::{AsnFinder, AsnLookup, AsnResult, WhoisAsnLookup};
+
+mod monitor;
+
+pub use monitor::{Monitor, MonitorConfig, MonitorHop, MonitorSnapshot, PathChange};
 
 #[derive(Error, Debug)]
 pub enum TraceError {
@@ -48,7 +59,7 @@ impl serde::Serialize for TraceError {
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct TraceConfig {
     /// The maximum number of hops.
     pub max_hops: u8,
@@ -65,6 +76,49 @@ pub struct TraceConfig {
     pub completion_timeout: Duration,
     /// Size of the cache for IP to ASN WHOIS lookups.
     pub asn_cache_size: usize,
+    /// Protocol to send probes with, including the destination port for TCP.
+    pub probe_protocol: ProbeProtocol,
+    /// Hold the flow identifier constant for the whole trace (Paris traceroute), so load
+    /// balancers don't send successive hops down different paths.
+    pub paris: bool,
+    /// Settings for multipath traces.
+    pub multipath: MultipathConfig,
+    /// Number of probes to send to each hop, like `traceroute -q`. Zero is treated as one.
+    pub probes_per_hop: u8,
+    /// Size, DSCP/ECN, flow label and padding of every probe.
+    pub probe_options: ProbeOptions,
+    /// Discover the path MTU by shrinking probes whenever a router says they're too big,
+    /// starting from the size in `probe_options`, or 1500 bytes if it doesn't have one.
+    pub pmtu: bool,
+    /// Settings for continuous monitoring.
+    pub monitor: MonitorConfig,
+    /// Where every timeout and timestamp comes from. Defaults to the system clock.
+    pub clock: Arc<dyn Clock>,
+    /// Where hop ASNs come from. Defaults to WHOIS.
+    pub asn_lookup: Arc<dyn AsnLookup>,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            max_hops: 0,
+            max_sequential_pending: 0,
+            wait_time_per_hop: Duration::ZERO,
+            retry_frequency: Duration::ZERO,
+            destination_timeout: Duration::ZERO,
+            completion_timeout: Duration::ZERO,
+            asn_cache_size: 0,
+            probe_protocol: ProbeProtocol::default(),
+            paris: false,
+            multipath: MultipathConfig::default(),
+            probes_per_hop: 0,
+            probe_options: ProbeOptions::default(),
+            pmtu: false,
+            monitor: MonitorConfig::default(),
+            clock: Arc::new(SystemClock),
+            asn_lookup: Arc::new(WhoisAsnLookup),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -75,6 +129,88 @@ pub struct NetworkInfo {
     pub network: Option<Network>,
 }
 
+/// Round trip times of a hop's answered probes, one per probe from the first reply to it, so
+/// they always agree with the probes' outcomes. Each is measured from the send it answered.
+#[derive(Debug, Clone, Default)]
+pub struct RttStats {
+    samples: Vec<Duration>,
+}
+
+impl RttStats {
+    fn new(sample: Duration) -> Self {
+        Self {
+            samples: vec![sample],
+        }
+    }
+
+    fn push(&mut self, sample: Duration) {
+        self.samples.push(sample);
+    }
+
+    pub fn samples(&self) -> &[Duration] {
+        &self.samples
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+        }
+    }
+
+    /// Population standard deviation.
+    pub fn stddev(&self) -> Option<Duration> {
+        let avg = self.avg()?.as_secs_f64();
+        let variance = self
+            .samples
+            .iter()
+            .map(|sample| (sample.as_secs_f64() - avg).powi(2))
+            .sum::<f64>()
+            / self.samples.len() as f64;
+        Some(Duration::from_secs_f64(variance.sqrt()))
+    }
+}
+
+/// Serialized in fractional milliseconds.
+#[cfg(feature = "serde")]
+impl serde::Serialize for RttStats {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let mut state = serializer.serialize_struct("RttStats", 5)?;
+        state.serialize_field(
+            "samples",
+            &self.samples.iter().copied().map(millis).collect::<Vec<_>>(),
+        )?;
+        state.serialize_field("min", &self.min().map(millis))?;
+        state.serialize_field("avg", &self.avg().map(millis))?;
+        state.serialize_field("max", &self.max().map(millis))?;
+        state.serialize_field("stddev", &self.stddev().map(millis))?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+fn duration_millis_serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
 #[cfg(feature = "serde")]
 fn system_time_serialize<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -87,6 +223,187 @@ where
     )
 }
 
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind"))]
+pub enum ProbeOutcome {
+    Pending,
+    Reply {
+        ip: IpAddr,
+        #[cfg_attr(feature = "serde", serde(serialize_with = "duration_millis_serialize"))]
+        rtt: Duration,
+    },
+    /// Nothing came back within the retry frequency. This can still turn into a reply if one
+    /// arrives late.
+    Timeout,
+}
+
+/// A single probe sent to a hop. Retries resend it with a new ID each time, so a late reply to
+/// an earlier send is still timed from that send.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct Probe {
+    /// ID of the first send.
+    pub id: PacketId,
+    pub outcome: ProbeOutcome,
+    /// Total size the probe was last sent at, which changes in path MTU discovery.
+    pub size: Option<u16>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    first_sent: Instant,
+    /// Every send's ID and when it went out, oldest first.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sends: Vec<(PacketId, Instant)>,
+}
+
+impl Probe {
+    fn new(size: Option<u16>, now: Instant) -> Self {
+        let id = new_probe_id();
+        Self {
+            id,
+            outcome: ProbeOutcome::Pending,
+            size,
+            first_sent: now,
+            sends: vec![(id, now)],
+        }
+    }
+
+    /// Send again under a new ID, returning it.
+    fn resend(&mut self, now: Instant) -> PacketId {
+        let id = new_probe_id();
+        self.sends.push((id, now));
+        id
+    }
+
+    /// When the send with this ID went out, if it was one of this probe's.
+    fn sent_at(&self, id: PacketId) -> Option<Instant> {
+        self.sends
+            .iter()
+            .find(|(sent_id, _)| *sent_id == id)
+            .map(|&(_, sent_at)| sent_at)
+    }
+}
+
+/// Zero and 0xffff are the same in ones' complement, so avoid them for IDs that end up in
+/// checksums.
+fn new_probe_id() -> PacketId {
+    PacketId(rand::thread_rng().gen_range(1..u16::MAX))
+}
+
+/// The responder that answered the most probes, preferring whichever answered first on a tie.
+fn dominant_responder(probes: &[Probe]) -> Option<IpAddr> {
+    let mut counts: Vec<(IpAddr, usize)> = vec![];
+    for probe in probes {
+        if let ProbeOutcome::Reply { ip, .. } = probe.outcome {
+            match counts.iter_mut().find(|(seen, _)| *seen == ip) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((ip, 1)),
+            }
+        }
+    }
+
+    let mut dominant: Option<(IpAddr, usize)> = None;
+    for (ip, count) in counts {
+        match dominant {
+            Some((_, best)) if best >= count => {}
+            _ => dominant = Some((ip, count)),
+        }
+    }
+    dominant.map(|(ip, _)| ip)
+}
+
+/// What replies to a hop's probes have told us beyond who sent them, shared by every hop state
+/// that has a responder.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct HopDetails {
+    pub rtt: RttStats,
+    pub probes: Vec<Probe>,
+    /// MPLS labels and interface information from the most recent reply that had any.
+    pub extensions: IcmpExtensions,
+    /// Set when the hop answered with a Destination Unreachable, like `!N` or `!X`.
+    pub unreachable: Option<UnreachableCode>,
+    /// In path MTU discovery, the biggest probe this hop answered, which is the path MTU as far
+    /// as here.
+    pub mtu: Option<u16>,
+    /// MTU of the link after this hop, if it said a probe was too big to go down it. Routers
+    /// that don't say are assumed to be at the next common MTU below the probe.
+    pub next_hop_mtu: Option<u16>,
+    /// How our probes had been changed by the time they got here, from the most recent reply
+    /// that quoted one.
+    pub rewrites: Rewrites,
+    /// How many hops away this is on the way there, which is the TTL its probes expire at.
+    pub forward_distance: u8,
+    /// TTL the most recent reply arrived with, if the transport could see it.
+    pub reply_ttl: Option<u8>,
+    /// How many hops away this is on the way back, counted like `forward_distance`, going by
+    /// `reply_ttl` and the most likely TTL the reply started with. A different number than
+    /// `forward_distance` means asymmetric routing, or hops hidden on the way there by an MPLS
+    /// tunnel that doesn't decrement the TTL.
+    pub reverse_distance: Option<u8>,
+    /// Other TTLs the same router answered at. Outside of a routing loop, that usually means a
+    /// router on the way isn't decrementing the TTL properly.
+    pub also_answered_at: Vec<u8>,
+}
+
+/// Header fields that reached a hop differently from how we sent them, going by the copy of the
+/// probe it quoted back. Each is what the field was changed to, so whatever changed it, like a
+/// NAT or a QoS policy, sits somewhere between us and the hop. Fields one side didn't have
+/// can't be compared and are left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct Rewrites {
+    pub source_ip: Option<IpAddr>,
+    pub dscp: Option<u8>,
+    /// ECN can also be changed on purpose, by congested routers marking Congestion Experienced.
+    pub ecn: Option<u8>,
+    /// IPv4 identification.
+    pub id: Option<u16>,
+    /// Source port, or Echo Request identifier.
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// Transport checksum, which changes along with the addresses and ports NATs rewrite, and
+    /// on its own where something recomputes it or touches the payload.
+    pub checksum: Option<u16>,
+    /// TTL or hop limit the probe got to the hop with, if it's one it couldn't have had. A Time
+    /// Exceeded should quote 0 or 1, and nothing should quote more than we sent, so anything
+    /// else means something on the way set it, like a middlebox that resets TTLs.
+    pub ttl: Option<u8>,
+}
+
+impl Rewrites {
+    /// Compare the probe a reply quoted with the one we sent, if the transport knows that.
+    fn of_reply(
+        result: &TracerouteResult,
+        traceroute_channel: &impl ProbeTransport,
+    ) -> Option<Self> {
+        let quoted = result.quoted()?;
+        let sent = traceroute_channel.sent_header(result.id())?;
+        let expired = matches!(result, TracerouteResult::IcmpTimeExceeded(..));
+        Some(Self::between(&sent, quoted, expired))
+    }
+
+    /// `expired` is for probes quoted by a router they expired at, which only had the last bit
+    /// of TTL left.
+    fn between(sent: &ProbeHeader, quoted: &ProbeHeader, expired: bool) -> Self {
+        fn changed<T: PartialEq>(sent: Option<T>, quoted: Option<T>) -> Option<T> {
+            quoted.filter(|quoted| sent.is_some_and(|sent| sent != *quoted))
+        }
+        Self {
+            source_ip: changed(sent.source_ip, quoted.source_ip),
+            dscp: changed(Some(sent.dscp), Some(quoted.dscp)),
+            ecn: changed(Some(sent.ecn), Some(quoted.ecn)),
+            id: changed(sent.id, quoted.id),
+            source_port: changed(sent.source_port, quoted.source_port),
+            destination_port: changed(sent.destination_port, quoted.destination_port),
+            checksum: changed(sent.checksum, quoted.checksum),
+            ttl: Some(quoted.ttl).filter(|&ttl| if expired { ttl > 1 } else { ttl > sent.ttl }),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[cfg_attr(feature = "serde", serde(tag = "kind"))]
@@ -96,9 +413,11 @@ pub enum Hop {
     #[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
     #[non_exhaustive]
     Pending {
+        /// ID of the first probe.
         id: PacketId,
         #[cfg_attr(feature = "serde", serde(serialize_with = "system_time_serialize"))]
         since: SystemTime,
+        probes: Vec<Probe>,
     },
 
     #[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
@@ -107,24 +426,111 @@ pub enum Hop {
         ip: IpAddr,
         #[cfg_attr(feature = "serde", serde(skip))]
         finder: AsnFinder,
+        #[cfg_attr(feature = "serde", serde(flatten))]
+        details: HopDetails,
     },
 
     #[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
     #[non_exhaustive]
+    /// `ip` is the dominant responder when probes were answered by more than one router.
     Done {
         ip: IpAddr,
         hostname: Option<String>,
         network_info: Option<NetworkInfo>,
+        #[cfg_attr(feature = "serde", serde(flatten))]
+        details: HopDetails,
     },
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Hop {
+    /// The router that answered for this hop, once one has.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Hop::Unused | Hop::Pending { .. } => None,
+            Hop::FindingAsn { ip, .. } | Hop::Done { ip, .. } => Some(*ip),
+        }
+    }
+
+    /// Every probe sent to this hop, in the order they were sent.
+    pub fn probes(&self) -> &[Probe] {
+        match self {
+            Hop::Unused => &[],
+            Hop::Pending { probes, .. } => probes,
+            Hop::FindingAsn { details, .. } | Hop::Done { details, .. } => &details.probes,
+        }
+    }
+
+    /// Fraction of settled probes that timed out, or None if none have settled yet.
+    pub fn loss(&self) -> Option<f64> {
+        let settled = self
+            .probes()
+            .iter()
+            .filter(|probe| !matches!(probe.outcome, ProbeOutcome::Pending))
+            .count();
+        let lost = self
+            .probes()
+            .iter()
+            .filter(|probe| matches!(probe.outcome, ProbeOutcome::Timeout))
+            .count();
+        (settled > 0).then(|| lost as f64 / settled as f64)
+    }
+
+    fn probes_mut(&mut self) -> &mut [Probe] {
+        match self {
+            Hop::Unused => &mut [],
+            Hop::Pending { probes, .. } => probes,
+            Hop::FindingAsn { details, .. } | Hop::Done { details, .. } => &mut details.probes,
+        }
+    }
+}
+
+/// TTLs replies commonly start out with: 64 from Linux and most other Unixes, 128 from Windows,
+/// and 255 from many routers' ICMP.
+const INITIAL_TTLS: [u8; 3] = [64, 128, 255];
+
+/// How many hops a reply that arrived with `reply_ttl` came from, counting the hop that sent
+/// it, assuming it started at the closest common initial TTL.
+fn reverse_distance(reply_ttl: u8) -> u8 {
+    let initial_ttl = INITIAL_TTLS
+        .into_iter()
+        .find(|&initial_ttl| initial_ttl >= reply_ttl)
+        .unwrap_or(u8::MAX);
+    initial_ttl - reply_ttl + 1
+}
+
+/// Where path MTU discovery starts without a probe size, which is Ethernet's MTU.
+const DEFAULT_PMTU_PROBE_SIZE: u16 = 1500;
+
+/// Common MTUs from RFC 1191, for guessing the next-hop MTU when a router doesn't report it.
+const MTU_PLATEAUS: [u16; 11] = [
+    65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68,
+];
+
+/// The next-hop MTU a Packet Too Big means for a probe of `size`, if it's any smaller. Outside
+/// of path MTU discovery that's just whatever the router said.
+fn next_hop_mtu(reported: Option<u16>, size: Option<u16>, dst_ip: IpAddr) -> Option<u16> {
+    let Some(size) = size else {
+        return reported;
+    };
+    let mtu = reported
+        .filter(|&mtu| mtu < size)
+        .or_else(|| MTU_PLATEAUS.into_iter().find(|&plateau| plateau < size))?;
+    // Links can't be smaller than this, whatever a router claims.
+    let minimum = if dst_ip.is_ipv6() { 1280 } else { 68 };
+    Some(mtu.max(minimum)).filter(|&mtu| mtu < size)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum TerminationReason {
     Done,
-    DestinationUnreachable,
+    /// The destination, or a router on the way, said our probes can't get there.
+    DestinationUnreachable(UnreachableCode),
     DestinationTimeout,
     CompletionTimeout,
+    /// Probes went around in circles through these routers, in the order they answered, so
+    /// they'll never get there.
+    RoutingLoop(Vec<IpAddr>),
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -201,6 +607,14 @@ pub struct Trace<'a> {
     dst_ip: IpAddr,
     state: TraceState,
     config: &'a TraceConfig,
+    probe_protocol: ProbeProtocol,
+    /// Flow shared by every probe in Paris mode.
+    flow: Option<FlowId>,
+    /// What probes look like. In path MTU discovery the size only ever shrinks.
+    options: ProbeOptions,
+    /// Next-hop MTU reported by every router that said a probe was too big, for whichever hop
+    /// turns out to be it.
+    too_big: Vec<(IpAddr, u16)>,
     hops_buffer: [Hop; u8::MAX as usize],
     used_hops: u8,
     /// Option<Asn> because we want to cache lookup failures as well.
@@ -209,10 +623,29 @@ pub struct Trace<'a> {
 
 impl<'a> Trace<'a> {
     pub fn new(dst_ip: IpAddr, config: &'a TraceConfig) -> Self {
+        Self::with_probe_protocol(dst_ip, config, config.probe_protocol)
+    }
+
+    /// Like `new`, but overriding the probe protocol from the config.
+    pub fn with_probe_protocol(
+        dst_ip: IpAddr,
+        config: &'a TraceConfig,
+        probe_protocol: ProbeProtocol,
+    ) -> Self {
         Self {
             dst_ip,
             state: TraceState::NotStarted,
             config,
+            probe_protocol,
+            flow: config.paris.then(|| FlowId(rand::thread_rng().gen())),
+            options: ProbeOptions {
+                size: match config.pmtu {
+                    true => config.probe_options.size.or(Some(DEFAULT_PMTU_PROBE_SIZE)),
+                    false => config.probe_options.size,
+                },
+                ..config.probe_options.clone()
+            },
+            too_big: Vec::new(),
             hops_buffer: std::array::from_fn(|_| Hop::Unused),
             used_hops: 0,
             asn_cache: Cache::new(config.asn_cache_size),
@@ -221,10 +654,11 @@ impl<'a> Trace<'a> {
 
     pub fn non_packet_poll(
         &mut self,
-        traceroute_channel: &mut TracerouteChannel,
+        traceroute_channel: &mut impl ProbeTransport,
         peeringdb: &PeeringDbManager,
     ) -> Result<(DidUpdate, Option<TerminationReason>), TraceError> {
-        let did_update: DidUpdate = match self.state {
+        let now = self.config.clock.now();
+        let did_update: DidUpdate = self.expire_probes(now).or(match self.state {
             TraceState::NotStarted => {
                 self.perhaps_start_next_hop(0, traceroute_channel)?;
                 self.poll_asn_finder(peeringdb)?
@@ -245,34 +679,38 @@ impl<'a> Trace<'a> {
                     || index >= self.config.max_hops - 1;
 
                 if all_hops_sent {
-                    if since.elapsed() > self.config.destination_timeout {
+                    if now.saturating_duration_since(since) > self.config.destination_timeout {
                         self.state = TraceState::Terminated(TerminationReason::DestinationTimeout);
                         DidUpdate::Yes
-                    } else if last_retry.elapsed() > self.config.retry_frequency {
-                        *last_retry = Instant::now();
+                    } else if now.saturating_duration_since(*last_retry)
+                        > self.config.retry_frequency
+                    {
+                        *last_retry = now;
                         self.retry_ping(traceroute_channel)?;
                         DidUpdate::No
                     } else {
                         self.poll_asn_finder(peeringdb)?
                     }
                 } else {
-                    if since.elapsed() > self.config.wait_time_per_hop {
+                    if now.saturating_duration_since(since) > self.config.wait_time_per_hop {
                         self.perhaps_start_next_hop(index + 1, traceroute_channel)?;
                     }
                     self.poll_asn_finder(peeringdb)?
                 }
             }
             TraceState::ReachedDestination { since, last_retry } => {
-                if since.elapsed() > self.config.completion_timeout && !self.all_hops_done() {
+                if now.saturating_duration_since(since) > self.config.completion_timeout
+                    && !self.all_hops_done()
+                {
                     self.state = TraceState::Terminated(TerminationReason::CompletionTimeout);
                     DidUpdate::Yes
-                } else if last_retry.elapsed() > self.config.retry_frequency {
+                } else if now.saturating_duration_since(last_retry) > self.config.retry_frequency {
                     // Gross! But the borrow checker is annoyed otherwise.
                     if let TraceState::ReachedDestination {
                         ref mut last_retry, ..
                     } = self.state
                     {
-                        *last_retry = Instant::now();
+                        *last_retry = now;
                     }
 
                     self.retry_ping(traceroute_channel)?;
@@ -286,82 +724,405 @@ impl<'a> Trace<'a> {
                 }
             }
             TraceState::Terminated(_) => DidUpdate::Yes,
-        };
+        });
 
-        match self.state {
-            TraceState::Terminated(reason) => Ok((did_update, Some(reason))),
-            _ => Ok((did_update, None)),
-        }
+        self.termination_status(did_update)
     }
 
     pub fn perhaps_use_packet(
         &mut self,
-        result: &TracerouteResult,
-        traceroute_channel: &mut TracerouteChannel,
+        packet: &ReceivedPacket,
+        traceroute_channel: &mut impl ProbeTransport,
         peeringdb: &PeeringDbManager,
     ) -> Result<(DidUpdate, Option<TerminationReason>), TraceError> {
-        let did_update: DidUpdate = match result {
-            &TracerouteResult::IcmpReply(ip, id) | &TracerouteResult::IcmpTimeExceeded(ip, id) => {
-                if let Some(hop_index) = self.hops_mut().iter_mut().position(|hop| match hop {
-                    Hop::Pending { id: hop_id, .. } => hop_id == &id,
-                    _ => false,
-                }) {
-                    let is_destination = ip == self.dst_ip;
-                    self.hops_buffer[hop_index] = if is_public(ip) {
-                        if let Some(&maybe_asn) = self.asn_cache.get(&ip) {
-                            Hop::Done {
-                                ip,
-                                hostname: do_rdns(&ip)?,
-                                network_info: maybe_asn
-                                    .map(|asn| get_network_info(asn, peeringdb))
-                                    .transpose()?,
-                            }
-                        } else {
-                            Hop::FindingAsn {
-                                ip,
-                                finder: AsnFinder::lookup(ip).map_err(TraceError::AsnLookup)?,
-                            }
-                        }
-                    } else {
-                        Hop::Done {
-                            ip,
-                            hostname: do_rdns(&ip)?,
-                            network_info: None,
-                        }
-                    };
+        let did_update: DidUpdate = match &packet.result {
+            &TracerouteResult::IcmpReply(ip, id)
+            | &TracerouteResult::IcmpTimeExceeded(ip, id, ..)
+            | &TracerouteResult::IcmpPortUnreachable(ip, id, ..)
+            | &TracerouteResult::TcpReply { ip, id, .. } => {
+                let Some((hop_index, probe_index)) = self.find_probe(id) else {
+                    return self.termination_status(DidUpdate::No);
+                };
+                let rewrites = Rewrites::of_reply(&packet.result, traceroute_channel);
 
-                    if is_destination {
-                        self.state = TraceState::ReachedDestination {
-                            since: Instant::now(),
-                            last_retry: Instant::now(),
-                        };
-                        DidUpdate::Yes
-                    } else if let TraceState::OnHop { index, .. } = self.state {
-                        if index == hop_index as u8 {
-                            // If this was a response to our current hop, we can move on to the next.
-                            self.perhaps_start_next_hop(index + 1, traceroute_channel)?;
-                        }
-                        DidUpdate::Yes
-                    } else {
-                        DidUpdate::No
+                let first_reply = self.record_reply(
+                    hop_index,
+                    probe_index,
+                    ip,
+                    packet,
+                    None,
+                    rewrites,
+                    peeringdb,
+                )?;
+                let routing_loop = match self.state {
+                    TraceState::OnHop { .. } => self.find_routing_loop(),
+                    _ => None,
+                };
+
+                if let Some(members) = routing_loop {
+                    self.state = TraceState::Terminated(TerminationReason::RoutingLoop(members));
+                    DidUpdate::Yes
+                } else if !first_reply {
+                    DidUpdate::Yes
+                } else if ip == self.dst_ip {
+                    self.state = TraceState::ReachedDestination {
+                        since: packet.received_at,
+                        last_retry: packet.received_at,
+                    };
+                    DidUpdate::Yes
+                } else if let TraceState::OnHop { index, .. } = self.state {
+                    if index == hop_index as u8 {
+                        // If this was a response to our current hop, we can move on to the next.
+                        self.perhaps_start_next_hop(index + 1, traceroute_channel)?;
                     }
+                    DidUpdate::Yes
                 } else {
                     DidUpdate::No
                 }
             }
 
-            &TracerouteResult::IcmpDestinationUnreachable(ip) => {
-                if ip == self.dst_ip {
-                    self.state = TraceState::Terminated(TerminationReason::DestinationUnreachable);
+            &TracerouteResult::IcmpDestinationUnreachable {
+                ip,
+                id,
+                dst_ip,
+                code,
+                ..
+            } => match self.find_probe(id) {
+                // Only our own probes, since other traces can share a destination.
+                Some((hop_index, probe_index)) if dst_ip == self.dst_ip => {
+                    self.record_reply(
+                        hop_index,
+                        probe_index,
+                        ip,
+                        packet,
+                        Some(code),
+                        Rewrites::of_reply(&packet.result, traceroute_channel),
+                        peeringdb,
+                    )?;
+                    self.state =
+                        TraceState::Terminated(TerminationReason::DestinationUnreachable(code));
                     DidUpdate::Yes
-                } else {
-                    DidUpdate::No
                 }
+                _ => DidUpdate::No,
+            },
+
+            &TracerouteResult::IcmpPacketTooBig {
+                ip,
+                id,
+                dst_ip,
+                mtu,
+                ..
+            } => match self.find_probe(id) {
+                Some((hop_index, probe_index)) if dst_ip == self.dst_ip => {
+                    let probe = &self.hops_buffer[hop_index].probes()[probe_index];
+                    let next_hop_mtu = next_hop_mtu(mtu, probe.size, self.dst_ip);
+                    if let Some(next_hop_mtu) = next_hop_mtu {
+                        self.record_too_big(ip, next_hop_mtu);
+                    }
+
+                    if let (true, Some(size), Some(next_hop_mtu)) =
+                        (self.config.pmtu, self.options.size, next_hop_mtu)
+                    {
+                        self.options.size = Some(size.min(next_hop_mtu));
+                        // Try again at a size that fits, unless something already answered.
+                        let probe = &mut self.hops_buffer[hop_index].probes_mut()[probe_index];
+                        if !matches!(probe.outcome, ProbeOutcome::Reply { .. })
+                            && probe.size > self.options.size
+                        {
+                            probe.size = self.options.size;
+                            let id = probe.resend(self.config.clock.now());
+                            traceroute_channel.send_probe(
+                                self.probe_protocol,
+                                self.dst_ip,
+                                hop_index as u8 + 1,
+                                id,
+                                self.flow,
+                                &self.options,
+                            )?;
+                        }
+                    } else {
+                        // Probes can't or won't get any smaller, so there's no way through.
+                        let code = UnreachableCode::FragmentationNeeded;
+                        self.record_reply(
+                            hop_index,
+                            probe_index,
+                            ip,
+                            packet,
+                            Some(code),
+                            Rewrites::of_reply(&packet.result, traceroute_channel),
+                            peeringdb,
+                        )?;
+                        self.state =
+                            TraceState::Terminated(TerminationReason::DestinationUnreachable(code));
+                    }
+                    DidUpdate::Yes
+                }
+                _ => DidUpdate::No,
+            },
+        };
+
+        self.termination_status(did_update)
+    }
+
+    /// Remember a router's next-hop MTU, and show it on its hop if we know which that is.
+    fn record_too_big(&mut self, ip: IpAddr, mtu: u16) {
+        match self.too_big.iter_mut().find(|(router, _)| *router == ip) {
+            Some((_, reported)) => *reported = mtu,
+            None => self.too_big.push((ip, mtu)),
+        }
+        for hop in self.hops_mut() {
+            if let Hop::FindingAsn {
+                ip: hop_ip,
+                details,
+                ..
+            }
+            | Hop::Done {
+                ip: hop_ip,
+                details,
+                ..
+            } = hop
+            {
+                if *hop_ip == ip {
+                    details.next_hop_mtu = Some(mtu);
+                }
+            }
+        }
+    }
+
+    /// In path MTU discovery, the biggest probe that made it to the destination, once one has.
+    /// That's the path MTU, unless a router on the way silently drops anything bigger.
+    pub fn path_mtu(&self) -> Option<u16> {
+        if !self.config.pmtu {
+            return None;
+        }
+        self.hops().iter().find_map(|hop| match hop {
+            Hop::FindingAsn { ip, details, .. } | Hop::Done { ip, details, .. }
+                if *ip == self.dst_ip =>
+            {
+                details.mtu
             }
+            _ => None,
+        })
+    }
+
+    /// Hop and probe index of the probe with the given ID.
+    fn find_probe(&self, id: PacketId) -> Option<(usize, usize)> {
+        self.hops().iter().enumerate().find_map(|(hop_index, hop)| {
+            hop.probes()
+                .iter()
+                .position(|probe| probe.sent_at(id).is_some())
+                .map(|probe_index| (hop_index, probe_index))
+        })
+    }
+
+    /// Record a reply to one of our probes, returning whether it was the first for its hop.
+    #[allow(clippy::too_many_arguments)]
+    fn record_reply(
+        &mut self,
+        hop_index: usize,
+        probe_index: usize,
+        ip: IpAddr,
+        packet: &ReceivedPacket,
+        unreachable: Option<UnreachableCode>,
+        rewrites: Option<Rewrites>,
+        peeringdb: &PeeringDbManager,
+    ) -> Result<bool, TraceError> {
+        let hop = &mut self.hops_buffer[hop_index];
+        let probe = &mut hop.probes_mut()[probe_index];
+        let sent_at = probe
+            .sent_at(packet.result.id())
+            .unwrap_or(probe.first_sent);
+        let rtt = packet.received_at.saturating_duration_since(sent_at);
+        // Duplicates, and replies to other sends of a probe that was already answered, would
+        // count one probe twice.
+        let newly_answered = !matches!(probe.outcome, ProbeOutcome::Reply { .. });
+        if newly_answered {
+            probe.outcome = ProbeOutcome::Reply { ip, rtt };
+        }
+        // Fixed-size probes don't say anything about the MTU.
+        let size = probe.size.filter(|_| self.config.pmtu);
+        let extensions = packet.result.extensions().cloned().unwrap_or_default();
+
+        let first_reply = match hop {
+            Hop::FindingAsn {
+                ip: hop_ip,
+                details,
+                ..
+            }
+            | Hop::Done {
+                ip: hop_ip,
+                details,
+                ..
+            } => {
+                if newly_answered {
+                    details.rtt.push(rtt);
+                }
+                if !extensions.is_empty() {
+                    details.extensions = extensions;
+                }
+                if unreachable.is_some() {
+                    details.unreachable = unreachable;
+                }
+                details.mtu = details.mtu.max(size);
+                if let Some(rewrites) = rewrites {
+                    details.rewrites = rewrites;
+                }
+                if packet.ttl.is_some() {
+                    details.reply_ttl = packet.ttl;
+                    details.reverse_distance = packet.ttl.map(reverse_distance);
+                }
+                // Another router may have overtaken the one we've been reporting.
+                if let Some(dominant) = dominant_responder(&details.probes) {
+                    if dominant != *hop_ip {
+                        let details = std::mem::take(details);
+                        self.hops_buffer[hop_index] =
+                            self.resolve_hop(dominant, details, peeringdb)?;
+                    }
+                }
+                false
+            }
+            Hop::Pending { probes, .. } => {
+                let details = HopDetails {
+                    rtt: RttStats::new(rtt),
+                    probes: std::mem::take(probes),
+                    extensions,
+                    unreachable,
+                    mtu: size,
+                    next_hop_mtu: None,
+                    rewrites: rewrites.unwrap_or_default(),
+                    forward_distance: hop_index as u8 + 1,
+                    reply_ttl: packet.ttl,
+                    reverse_distance: packet.ttl.map(reverse_distance),
+                    also_answered_at: Vec::new(),
+                };
+                self.hops_buffer[hop_index] = self.resolve_hop(ip, details, peeringdb)?;
+                true
+            }
+            Hop::Unused => false,
         };
+        self.mark_repeated_responders();
+        Ok(first_reply)
+    }
+
+    /// Who answered at each TTL so far, leaving out the destination, which answers at every
+    /// TTL past its own.
+    fn responders(&self) -> Vec<Option<IpAddr>> {
+        self.hops()
+            .iter()
+            .map(|hop| hop.ip().filter(|&ip| ip != self.dst_ip))
+            .collect()
+    }
+
+    /// Point out routers that answered at more than one TTL on every hop they answered at.
+    fn mark_repeated_responders(&mut self) {
+        let responders = self.responders();
+        for (index, hop) in self.hops_mut().iter_mut().enumerate() {
+            if let Hop::FindingAsn { ip, details, .. } | Hop::Done { ip, details, .. } = hop {
+                details.also_answered_at = responders
+                    .iter()
+                    .enumerate()
+                    .filter(|&(other, responder)| other != index && *responder == Some(*ip))
+                    .map(|(other, _)| other as u8 + 1)
+                    .collect();
+            }
+        }
+    }
+
+    /// The routers in a loop, if the same ones answered in the same order at consecutive TTLs
+    /// twice over, which is what probes going around in circles find. Each router only goes
+    /// around once per lap, so a single router answering over and over doesn't count.
+    fn find_routing_loop(&self) -> Option<Vec<IpAddr>> {
+        let responders = self.responders();
+        for start in 0..responders.len() {
+            for length in 2..=(responders.len() - start) / 2 {
+                // Longer loops from here would include the same unanswered or repeated hop.
+                let Some(members) = responders[start..start + length]
+                    .iter()
+                    .copied()
+                    .collect::<Option<Vec<IpAddr>>>()
+                else {
+                    break;
+                };
+                if members[..length - 1].contains(&members[length - 1]) {
+                    break;
+                }
+                if responders[start + length..start + 2 * length]
+                    .iter()
+                    .zip(&members)
+                    .all(|(responder, member)| *responder == Some(*member))
+                {
+                    return Some(members);
+                }
+            }
+        }
+        None
+    }
+
+    /// Build the hop for a responder, starting an ASN lookup unless it's cached or pointless.
+    fn resolve_hop(
+        &mut self,
+        ip: IpAddr,
+        mut details: HopDetails,
+        peeringdb: &PeeringDbManager,
+    ) -> Result<Hop, TraceError> {
+        details.next_hop_mtu = self
+            .too_big
+            .iter()
+            .find_map(|&(router, mtu)| (router == ip).then_some(mtu));
+
+        Ok(if is_public(ip) {
+            if let Some(&maybe_asn) = self.asn_cache.get(&ip) {
+                Hop::Done {
+                    ip,
+                    hostname: do_rdns(&ip)?,
+                    network_info: maybe_asn
+                        .map(|asn| get_network_info(asn, peeringdb))
+                        .transpose()?,
+                    details,
+                }
+            } else {
+                Hop::FindingAsn {
+                    ip,
+                    finder: self
+                        .config
+                        .asn_lookup
+                        .lookup(ip)
+                        .map_err(TraceError::AsnLookup)?,
+                    details,
+                }
+            }
+        } else {
+            Hop::Done {
+                ip,
+                hostname: do_rdns(&ip)?,
+                network_info: None,
+                details,
+            }
+        })
+    }
+
+    /// Mark probes that have gone unanswered for the retry frequency as timed out.
+    fn expire_probes(&mut self, now: Instant) -> DidUpdate {
+        let mut did_update = DidUpdate::No;
+        for hop in &mut self.hops_buffer[..self.used_hops as usize] {
+            for probe in hop.probes_mut() {
+                if matches!(probe.outcome, ProbeOutcome::Pending)
+                    && now.saturating_duration_since(probe.first_sent) > self.config.retry_frequency
+                {
+                    probe.outcome = ProbeOutcome::Timeout;
+                    did_update = DidUpdate::Yes;
+                }
+            }
+        }
+        did_update
+    }
 
-        match self.state {
-            TraceState::Terminated(reason) => Ok((did_update, Some(reason))),
+    fn termination_status(
+        &self,
+        did_update: DidUpdate,
+    ) -> Result<(DidUpdate, Option<TerminationReason>), TraceError> {
+        match &self.state {
+            TraceState::Terminated(reason) => Ok((did_update, Some(reason.clone()))),
             _ => Ok((did_update, None)),
         }
     }
@@ -395,10 +1156,27 @@ impl<'a> Trace<'a> {
             .all(|hop| matches!(hop, Hop::Done { .. }))
     }
 
-    fn retry_ping(&self, traceroute_channel: &mut TracerouteChannel) -> Result<(), TraceError> {
-        for (index, hop) in self.hops().iter().enumerate() {
-            if let Hop::Pending { id, .. } = hop {
-                traceroute_channel.send_echo(self.dst_ip, index as u8 + 1, *id)?;
+    fn retry_ping(
+        &mut self,
+        traceroute_channel: &mut impl ProbeTransport,
+    ) -> Result<(), TraceError> {
+        for (index, hop) in self.hops_buffer[..self.used_hops as usize]
+            .iter_mut()
+            .enumerate()
+        {
+            if let Hop::Pending { probes, .. } = hop {
+                for probe in probes {
+                    let id = probe.resend(self.config.clock.now());
+                    probe.size = self.options.size;
+                    traceroute_channel.send_probe(
+                        self.probe_protocol,
+                        self.dst_ip,
+                        index as u8 + 1,
+                        id,
+                        self.flow,
+                        &self.options,
+                    )?;
+                }
             }
         }
 
@@ -408,21 +1186,34 @@ impl<'a> Trace<'a> {
     fn perhaps_start_next_hop(
         &mut self,
         index: u8,
-        traceroute_channel: &mut TracerouteChannel,
+        traceroute_channel: &mut impl ProbeTransport,
     ) -> Result<(), TraceError> {
         if index < self.config.max_hops {
-            let id = PacketId(rand::thread_rng().gen());
+            let now = self.config.clock.now();
+            let probes: Vec<Probe> = (0..self.config.probes_per_hop.max(1))
+                .map(|_| Probe::new(self.options.size, now))
+                .collect();
             self.state = TraceState::OnHop {
-                since: Instant::now(),
-                last_retry: Instant::now(),
+                since: now,
+                last_retry: now,
                 index,
             };
+            for probe in &probes {
+                traceroute_channel.send_probe(
+                    self.probe_protocol,
+                    self.dst_ip,
+                    index + 1,
+                    probe.id,
+                    self.flow,
+                    &self.options,
+                )?;
+            }
             self.hops_buffer[index as usize] = Hop::Pending {
-                id,
-                since: SystemTime::now(),
+                id: probes[0].id,
+                since: self.config.clock.system_now(),
+                probes,
             };
             self.used_hops = self.used_hops.max(index + 1);
-            traceroute_channel.send_echo(self.dst_ip, index + 1, id)?;
         }
 
         Ok(())
@@ -433,31 +1224,40 @@ impl<'a> Trace<'a> {
 
         // Can't use .hops_mut() here because the borrow checker doesn't know that we're only using part of the struct.
         for hop in &mut self.hops_buffer[..self.used_hops as usize] {
-            did_update = did_update.or(if let Hop::FindingAsn { ip, finder } = hop {
-                match finder.poll().map_err(TraceError::AsnLookup)? {
-                    AsnResult::Found(asn) => {
-                        self.asn_cache.insert(*ip, Some(asn));
-                        *hop = Hop::Done {
-                            ip: *ip,
-                            hostname: do_rdns(ip)?,
-                            network_info: Some(get_network_info(asn, peeringdb)?),
-                        };
-                        DidUpdate::Yes
-                    }
-                    AsnResult::NotFound => {
-                        self.asn_cache.insert(*ip, None);
-                        *hop = Hop::Done {
-                            ip: *ip,
-                            hostname: do_rdns(ip)?,
-                            network_info: None,
-                        };
-                        DidUpdate::Yes
+            did_update = did_update.or(
+                if let Hop::FindingAsn {
+                    ip,
+                    finder,
+                    details,
+                } = hop
+                {
+                    match finder.poll().map_err(TraceError::AsnLookup)? {
+                        AsnResult::Found(asn) => {
+                            self.asn_cache.insert(*ip, Some(asn));
+                            *hop = Hop::Done {
+                                ip: *ip,
+                                hostname: do_rdns(ip)?,
+                                network_info: Some(get_network_info(asn, peeringdb)?),
+                                details: std::mem::take(details),
+                            };
+                            DidUpdate::Yes
+                        }
+                        AsnResult::NotFound => {
+                            self.asn_cache.insert(*ip, None);
+                            *hop = Hop::Done {
+                                ip: *ip,
+                                hostname: do_rdns(ip)?,
+                                network_info: None,
+                                details: std::mem::take(details),
+                            };
+                            DidUpdate::Yes
+                        }
+                        AsnResult::Pending => DidUpdate::No,
                     }
-                    AsnResult::Pending => DidUpdate::No,
-                }
-            } else {
-                DidUpdate::No
-            });
+                } else {
+                    DidUpdate::No
+                },
+            );
         }
 
         Ok(did_update)
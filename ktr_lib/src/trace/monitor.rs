@@ -0,0 +1,418 @@
+//! Continuous, mtr-style monitoring. A regular trace discovers the path first, then every hop
+//! gets one probe per interval and we keep rolling loss and latency statistics for each.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+
+use super::{DidUpdate, Hop, TerminationReason, Trace, TraceConfig, TraceError};
+use crate::peeringdb::PeeringDbManager;
+use crate::traceroute_net::{
+    FlowId, PacketId, ProbeProtocol, ProbeTransport, ReceivedPacket, TracerouteResult,
+};
+
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    /// How long between probing every hop.
+    pub interval: Duration,
+    /// How long to wait for a reply before counting a probe as lost.
+    pub timeout: Duration,
+    /// Number of recent probes per hop that statistics are computed over.
+    pub window: usize,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(2),
+            window: 100,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn option_duration_millis_serialize<S>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match duration {
+        Some(duration) => serializer.serialize_some(&(duration.as_secs_f64() * 1000.0)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A different router started answering at a TTL.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct PathChange {
+    pub ttl: u8,
+    pub from: IpAddr,
+    pub to: IpAddr,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "super::system_time_serialize")
+    )]
+    pub at: SystemTime,
+}
+
+/// Rolling statistics for one hop. Durations are serialized in fractional milliseconds.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct MonitorHop {
+    pub ttl: u8,
+    /// Whoever answered most recently.
+    pub responder: Option<IpAddr>,
+    /// Probes in the window, not counting ones still in flight.
+    pub sent: usize,
+    pub lost: usize,
+    /// Fraction of `sent` that was lost, like `Hop::loss`.
+    pub loss: f64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "option_duration_millis_serialize")
+    )]
+    pub last: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "option_duration_millis_serialize")
+    )]
+    pub avg: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "option_duration_millis_serialize")
+    )]
+    pub best: Option<Duration>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "option_duration_millis_serialize")
+    )]
+    pub worst: Option<Duration>,
+    /// Mean difference between consecutive round trip times.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "option_duration_millis_serialize")
+    )]
+    pub jitter: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct MonitorSnapshot {
+    pub cycles: u32,
+    pub hops: Vec<MonitorHop>,
+    /// The most recent path changes, oldest first.
+    pub path_changes: Vec<PathChange>,
+}
+
+/// Outcomes of the most recent probes to a TTL, None meaning lost.
+#[derive(Debug, Default)]
+struct HopWindow {
+    responder: Option<IpAddr>,
+    outcomes: VecDeque<Option<Duration>>,
+}
+
+impl HopWindow {
+    fn push(&mut self, outcome: Option<Duration>, window: usize) {
+        self.outcomes.push_back(outcome);
+        while self.outcomes.len() > window.max(1) {
+            self.outcomes.pop_front();
+        }
+    }
+
+    fn stats(&self, ttl: u8) -> MonitorHop {
+        let rtts: Vec<Duration> = self.outcomes.iter().flatten().copied().collect();
+        let sent = self.outcomes.len();
+        let lost = sent - rtts.len();
+
+        let jitter = if rtts.len() < 2 {
+            None
+        } else {
+            let total: Duration = rtts.windows(2).map(|pair| pair[1].abs_diff(pair[0])).sum();
+            Some(total / (rtts.len() - 1) as u32)
+        };
+
+        MonitorHop {
+            ttl,
+            responder: self.responder,
+            sent,
+            lost,
+            loss: if sent == 0 {
+                0.0
+            } else {
+                lost as f64 / sent as f64
+            },
+            last: rtts.last().copied(),
+            avg: (!rtts.is_empty()).then(|| rtts.iter().sum::<Duration>() / rtts.len() as u32),
+            best: rtts.iter().min().copied(),
+            worst: rtts.iter().max().copied(),
+            jitter,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InFlight {
+    hop_index: usize,
+    sent_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct Monitor<'a> {
+    dst_ip: IpAddr,
+    config: &'a TraceConfig,
+    /// Discovers the path and names its hops. Only fed packets until it terminates.
+    trace: Trace<'a>,
+    discovering: bool,
+    windows: Vec<HopWindow>,
+    in_flight: HashMap<PacketId, InFlight>,
+    last_cycle: Option<Instant>,
+    cycles: u32,
+    path_changes: VecDeque<PathChange>,
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(dst_ip: IpAddr, config: &'a TraceConfig) -> Self {
+        Self::with_probe_protocol(dst_ip, config, config.probe_protocol)
+    }
+
+    /// Like `new`, but overriding the probe protocol from the config.
+    pub fn with_probe_protocol(
+        dst_ip: IpAddr,
+        config: &'a TraceConfig,
+        probe_protocol: ProbeProtocol,
+    ) -> Self {
+        let mut trace = Trace::with_probe_protocol(dst_ip, config, probe_protocol);
+        // Always hold the flow constant, whether or not the config asks for Paris mode.
+        // Otherwise load balancers send each cycle down a different path, and every TTL looks
+        // like it changes routers all the time.
+        trace
+            .flow
+            .get_or_insert_with(|| FlowId(rand::thread_rng().gen()));
+
+        Self {
+            dst_ip,
+            config,
+            trace,
+            discovering: true,
+            windows: vec![],
+            in_flight: HashMap::new(),
+            last_cycle: None,
+            cycles: 0,
+            path_changes: VecDeque::new(),
+        }
+    }
+
+    /// Never terminates by itself, so the second half of the result is always None. It's
+    /// returned anyways to match the other trace kinds.
+    pub fn non_packet_poll(
+        &mut self,
+        traceroute_channel: &mut impl ProbeTransport,
+        peeringdb: &PeeringDbManager,
+    ) -> Result<(DidUpdate, Option<TerminationReason>), TraceError> {
+        if self.discovering {
+            let (did_update, termination) =
+                self.trace.non_packet_poll(traceroute_channel, peeringdb)?;
+            if termination.is_some() {
+                self.finish_discovery();
+            }
+            return Ok((did_update, None));
+        }
+
+        let now = self.config.clock.now();
+        self.expire_in_flight(now);
+        let cycle_due = match self.last_cycle {
+            Some(last_cycle) => {
+                now.saturating_duration_since(last_cycle) >= self.config.monitor.interval
+            }
+            None => true,
+        };
+        if cycle_due {
+            self.send_cycle(traceroute_channel)?;
+            // Each new cycle is when we report on the last one.
+            return Ok((DidUpdate::Yes, None));
+        }
+
+        Ok((DidUpdate::No, None))
+    }
+
+    pub fn perhaps_use_packet(
+        &mut self,
+        packet: &ReceivedPacket,
+        traceroute_channel: &mut impl ProbeTransport,
+        peeringdb: &PeeringDbManager,
+    ) -> Result<(DidUpdate, Option<TerminationReason>), TraceError> {
+        if self.discovering {
+            let (did_update, termination) =
+                self.trace
+                    .perhaps_use_packet(packet, traceroute_channel, peeringdb)?;
+            if termination.is_some() {
+                self.finish_discovery();
+            }
+            return Ok((did_update, None));
+        }
+
+        match packet.result {
+            TracerouteResult::IcmpReply(ip, id)
+            | TracerouteResult::IcmpTimeExceeded(ip, id, ..)
+            | TracerouteResult::IcmpPortUnreachable(ip, id, ..)
+            | TracerouteResult::TcpReply { ip, id, .. }
+            | TracerouteResult::IcmpDestinationUnreachable { ip, id, .. }
+            | TracerouteResult::IcmpPacketTooBig { ip, id, .. } => {
+                if let Some(in_flight) = self.in_flight.remove(&id) {
+                    let rtt = packet
+                        .received_at
+                        .saturating_duration_since(in_flight.sent_at);
+                    self.record_reply(in_flight.hop_index, ip, rtt);
+                }
+            }
+        }
+
+        // Updates only go out once per cycle.
+        Ok((DidUpdate::No, None))
+    }
+
+    /// Hops found by the initial trace, with hostnames and network info.
+    pub fn hops(&self) -> &[Hop] {
+        self.trace.hops()
+    }
+
+    /// Turn into the hops found by the initial trace.
+    pub fn to_hops(self) -> Vec<Hop> {
+        self.trace.to_hops()
+    }
+
+    pub fn snapshot(&self) -> MonitorSnapshot {
+        MonitorSnapshot {
+            cycles: self.cycles,
+            hops: self
+                .windows
+                .iter()
+                .enumerate()
+                .map(|(hop_index, window)| window.stats(hop_index as u8 + 1))
+                .collect(),
+            path_changes: self.path_changes.iter().cloned().collect(),
+        }
+    }
+
+    fn finish_discovery(&mut self) {
+        let hops = self.trace.hops();
+        if hops.iter().all(|hop| hop.ip().is_none()) {
+            // Nothing answered, like when the network is down. There's nothing to monitor, so
+            // keep discovering until something does.
+            let mut trace =
+                Trace::with_probe_protocol(self.dst_ip, self.config, self.trace.probe_protocol);
+            trace.flow = self.trace.flow;
+            self.trace = trace;
+            return;
+        }
+
+        self.discovering = false;
+        let hop_count = hops
+            .iter()
+            .position(|hop| hop.ip() == Some(self.dst_ip))
+            .map_or(hops.len(), |index| index + 1);
+        self.windows = hops[..hop_count]
+            .iter()
+            .map(|hop| HopWindow {
+                responder: hop.ip(),
+                outcomes: VecDeque::new(),
+            })
+            .collect();
+    }
+
+    fn expire_in_flight(&mut self, now: Instant) {
+        let timeout = self.config.monitor.timeout;
+        let expired: Vec<PacketId> = self
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight)| now.saturating_duration_since(in_flight.sent_at) > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            let in_flight = self.in_flight.remove(&id).unwrap();
+            if let Some(window) = self.windows.get_mut(in_flight.hop_index) {
+                window.push(None, self.config.monitor.window);
+            }
+        }
+    }
+
+    fn send_cycle(
+        &mut self,
+        traceroute_channel: &mut impl ProbeTransport,
+    ) -> Result<(), TraceError> {
+        let now = self.config.clock.now();
+        self.last_cycle = Some(now);
+        self.cycles += 1;
+
+        for hop_index in 0..self.windows.len() {
+            let id = loop {
+                let id = PacketId(rand::thread_rng().gen_range(1..u16::MAX));
+                if !self.in_flight.contains_key(&id) {
+                    break id;
+                }
+            };
+            self.in_flight.insert(
+                id,
+                InFlight {
+                    hop_index,
+                    sent_at: now,
+                },
+            );
+            traceroute_channel.send_probe(
+                self.trace.probe_protocol,
+                self.dst_ip,
+                hop_index as u8 + 1,
+                id,
+                self.trace.flow,
+                &self.trace.options,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn record_reply(&mut self, hop_index: usize, ip: IpAddr, rtt: Duration) {
+        let Some(window) = self.windows.get_mut(hop_index) else {
+            // The path got shorter since we sent this.
+            return;
+        };
+
+        if let Some(from) = window.responder {
+            if from != ip {
+                self.path_changes.push_back(PathChange {
+                    ttl: hop_index as u8 + 1,
+                    from,
+                    to: ip,
+                    at: self.config.clock.system_now(),
+                });
+                while self.path_changes.len() > self.config.monitor.window.max(1) {
+                    self.path_changes.pop_front();
+                }
+            }
+        }
+        window.responder = Some(ip);
+        window.push(Some(rtt), self.config.monitor.window);
+
+        if ip == self.dst_ip {
+            // The destination answering early means the path got shorter.
+            self.windows.truncate(hop_index + 1);
+            self.in_flight
+                .retain(|_, in_flight| in_flight.hop_index <= hop_index);
+        } else if hop_index + 1 == self.windows.len()
+            && self.windows.len() < self.config.max_hops as usize
+        {
+            // A router answering at the end means the path got longer.
+            self.windows.push(HopWindow::default());
+        }
+    }
+}
@@ -1,22 +1,255 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io::{self, ErrorKind};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::time::{Duration, Instant};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
 use thiserror::Error;
 
-use pnet::datalink::{channel, Channel, Config, DataLinkReceiver, NetworkInterface};
-use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::{icmp, icmpv6, ipv4, ipv6, Packet};
+use crate::icmp_extensions::IcmpExtensions;
+
+use pnet::datalink::NetworkInterface;
+#[cfg(not(target_os = "linux"))]
+use pnet::datalink::{channel, Channel, Config, DataLinkReceiver};
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::packet::icmp::destination_unreachable::{self, DestinationUnreachablePacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::tcp::TcpFlags;
+use pnet::packet::{icmp, icmpv6, ipv4, ipv6, tcp, udp, Packet};
 use pnet::transport::TransportChannelType::Layer3;
 use pnet::transport::{transport_channel, TransportProtocol, TransportSender};
 use pnet::util::checksum;
+use rand::Rng;
+
+#[cfg(target_os = "linux")]
+mod packet_socket;
+#[cfg(target_os = "linux")]
+mod unprivileged;
+
+#[cfg(target_os = "linux")]
+use packet_socket::PacketSocket;
+#[cfg(target_os = "linux")]
+use unprivileged::UnprivilegedChannel;
+
+/// How long reading a reply blocks before giving up, so traces get polled regularly.
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// First destination port for UDP probes, as used by classic traceroute. The TTL is added to this.
+const UDP_BASE_PORT: u16 = 33434;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(transparent)]
 pub struct PacketId(pub u16);
 
+/// Identifies a flow as seen by load balancers: everything that goes into an ECMP hash (ports,
+/// ICMP checksum, IPv6 flow label) is derived from this, so probes sharing a `FlowId` take the
+/// same path, Paris traceroute style. Packet IDs are still unique per probe and are hidden in
+/// fields routers don't hash on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[repr(transparent)]
+pub struct FlowId(pub u16);
+
+impl FlowId {
+    /// Source port for UDP and TCP probes. The top bit is always set to stay in the ephemeral range.
+    fn source_port(self) -> u16 {
+        0x8000 | self.0
+    }
+}
+
+/// How to build a probe beyond what it's for and where it goes, so traces can follow the path
+/// a particular kind of traffic takes.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct ProbeOptions {
+    /// Total length of the IP packet, made up with `pattern` after the transport header.
+    /// Without one, or if it's too small for the headers, probes are as small as they can be.
+    pub size: Option<u16>,
+    /// Differentiated Services code point, like 46 for Expedited Forwarding. Only the low 6
+    /// bits are used.
+    pub dscp: u8,
+    /// Explicit Congestion Notification bits. Only the low 2 bits are used.
+    pub ecn: u8,
+    /// IPv6 flow label for every probe, instead of one that follows the flow or packet ID.
+    /// Replies are still matched by the quoted transport header, which IPv6 routers always
+    /// include. Only raw channels can set this, and only the low 20 bits are used.
+    pub flow_label: Option<u32>,
+    /// Bytes to fill the padding with, over and over. Zeros if empty.
+    pub pattern: Vec<u8>,
+}
+
+impl ProbeOptions {
+    /// What goes after `transport_len` bytes of transport header and payload so the packet
+    /// comes out at the requested size.
+    fn padding(&self, dst_ip: IpAddr, transport_len: usize) -> Vec<u8> {
+        let ip_header_len = match dst_ip {
+            IpAddr::V4(_) => ipv4::Ipv4Packet::minimum_packet_size(),
+            IpAddr::V6(_) => ipv6::Ipv6Packet::minimum_packet_size(),
+        };
+        let length = self.size.map_or(0, |size| {
+            (size as usize).saturating_sub(ip_header_len + transport_len)
+        });
+        if self.pattern.is_empty() {
+            vec![0; length]
+        } else {
+            self.pattern.iter().copied().cycle().take(length).collect()
+        }
+    }
+
+    /// The IPv4 TOS byte or IPv6 traffic class.
+    fn traffic_class(&self) -> u8 {
+        (self.dscp & 0x3f) << 2 | self.ecn & 0x3
+    }
+
+    /// IPv6 flow label for a probe. Outside of a flow, it carries the packet ID as a last
+    /// resort for matching.
+    fn flow_label(&self, id: PacketId, flow: Option<FlowId>) -> u32 {
+        let flow_label = self
+            .flow_label
+            .unwrap_or(flow.map_or(id.0, |flow| flow.0) as u32);
+        flow_label & 0xfffff
+    }
+}
+
+/// Transport protocol used for probe packets.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ProbeProtocol {
+    /// ICMP Echo Requests. Reaching the destination means getting an Echo Reply.
+    #[default]
+    Icmp,
+    /// UDP datagrams to high ports. Reaching the destination means getting a Port Unreachable.
+    Udp,
+    /// TCP SYNs to a service port. Reaching the destination means getting a SYN-ACK or RST.
+    Tcp { port: u16 },
+}
+
+impl FromStr for ProbeProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "icmp" => Ok(ProbeProtocol::Icmp),
+            "udp" => Ok(ProbeProtocol::Udp),
+            "tcp" => Ok(ProbeProtocol::Tcp { port: 80 }),
+            other => match other.strip_prefix("tcp:").map(str::parse) {
+                Some(Ok(port)) => Ok(ProbeProtocol::Tcp { port }),
+                _ => Err(format!("unknown probe protocol: {}", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ProbeProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeProtocol::Icmp => write!(f, "icmp"),
+            ProbeProtocol::Udp => write!(f, "udp"),
+            ProbeProtocol::Tcp { port } => write!(f, "tcp:{}", port),
+        }
+    }
+}
+
+/// Framing of what the receive channel hands us, which depends on the kind of interface.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum LinkType {
+    /// Ethernet, which Linux also uses for loopback.
+    Ethernet,
+    /// Bare IP packets, like on tun and WireGuard interfaces.
+    RawIp,
+    /// Linux cooked capture (SLL), with a 16 byte pseudo-header.
+    LinuxCooked,
+    /// BSD loopback and utun framing, with a 4 byte address family in host byte order.
+    Null,
+}
+
+impl LinkType {
+    /// Guess the link type of an interface. On Linux this reads the ARP hardware type from
+    /// sysfs, elsewhere interfaces without a MAC address are assumed to use null framing.
+    pub fn detect(interface: &NetworkInterface) -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            let hardware_type =
+                std::fs::read_to_string(format!("/sys/class/net/{}/type", interface.name));
+            if let Ok(hardware_type) = hardware_type {
+                // See ARPHRD_* in linux/if_arp.h.
+                return match hardware_type.trim().parse::<u16>() {
+                    Ok(1) | Ok(772) => LinkType::Ethernet,
+                    // IPIP, SIT, GRE, IP6GRE, and tun/WireGuard's "none".
+                    Ok(768) | Ok(769) | Ok(776) | Ok(778) | Ok(823) | Ok(65534) => LinkType::RawIp,
+                    _ => LinkType::Ethernet,
+                };
+            }
+        }
+
+        let has_mac = matches!(interface.mac, Some(mac) if !mac.is_zero());
+        if has_mac || interface.is_loopback() {
+            LinkType::Ethernet
+        } else if cfg!(target_os = "linux") {
+            LinkType::RawIp
+        } else {
+            LinkType::Null
+        }
+    }
+
+    /// Split a frame into the EtherType of its network layer and the network layer packet.
+    fn network_packet(self, frame: &[u8]) -> Option<(EtherType, &[u8])> {
+        match self {
+            LinkType::Ethernet => {
+                let ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?);
+                Some((EtherType(ethertype), frame.get(14..)?))
+            }
+            LinkType::LinuxCooked => {
+                let ethertype = u16::from_be_bytes(frame.get(14..16)?.try_into().ok()?);
+                Some((EtherType(ethertype), frame.get(16..)?))
+            }
+            // Address family numbers for IPv6 differ between BSDs, so go by the IP version.
+            LinkType::RawIp => Some((ip_version_ethertype(frame)?, frame)),
+            LinkType::Null => {
+                let packet = frame.get(4..)?;
+                Some((ip_version_ethertype(packet)?, packet))
+            }
+        }
+    }
+}
+
+fn ip_version_ethertype(packet: &[u8]) -> Option<EtherType> {
+    match packet.first()? >> 4 {
+        4 => Some(EtherTypes::Ipv4),
+        6 => Some(EtherTypes::Ipv6),
+        _ => None,
+    }
+}
+
+impl FromStr for LinkType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ethernet" => Ok(LinkType::Ethernet),
+            "raw" => Ok(LinkType::RawIp),
+            "cooked" => Ok(LinkType::LinuxCooked),
+            "null" => Ok(LinkType::Null),
+            _ => Err(format!("unknown link type: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LinkType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkType::Ethernet => write!(f, "ethernet"),
+            LinkType::RawIp => write!(f, "raw"),
+            LinkType::LinuxCooked => write!(f, "cooked"),
+            LinkType::Null => write!(f, "null"),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum TracerouteError {
     #[error("Error constructing packet")]
@@ -29,67 +262,665 @@ pub enum TracerouteError {
     Ipv6ChannelIo(#[source] io::Error),
     #[error("Tried to use IPv6 but enable_ipv6 was set to false")]
     Ipv6Disabled,
+    #[error("Interface has no usable {0} source address")]
+    NoSourceAddress(&'static str),
+    #[error("Source address {0} is not on the interface")]
+    SourceAddressNotOnInterface(IpAddr),
+    #[error("Error binding sender to interface: {0}")]
+    BindToInterface(#[source] io::Error),
+    #[error("Probe protocol {0} is not supported without raw sockets")]
+    UnsupportedProtocol(ProbeProtocol),
+    #[error("Malformed reply: {0}")]
+    MalformedReply(#[from] DecodeError),
     #[error("Unknown and unexpected error")]
     Unknown,
 }
 
+/// What was wrong with a frame that looked like a reply.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("Truncated {0}")]
+    Truncated(&'static str),
+    #[error("Invalid {0}")]
+    Invalid(&'static str),
+}
+
+/// Why a router or host said a probe couldn't be delivered, covering both ICMPv4 and ICMPv6
+/// Destination Unreachable codes. Port unreachable is reported separately since it usually means
+/// we made it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", content = "code"))]
+pub enum UnreachableCode {
+    Network,
+    Host,
+    Protocol,
+    Port,
+    FragmentationNeeded,
+    SourceRouteFailed,
+    /// Communication administratively prohibited, by a filter or policy.
+    AdminProhibited,
+    HostPrecedenceViolation,
+    PrecedenceCutoff,
+    /// ICMPv6 only: the source address is out of scope for the destination.
+    BeyondScope,
+    /// ICMPv6 only: failed ingress/egress policy.
+    SourcePolicyFailed,
+    /// ICMPv6 only: a reject route to the destination.
+    RejectRoute,
+    Other(u8),
+}
+
+impl UnreachableCode {
+    pub fn from_icmpv4(code: u8) -> Self {
+        match code {
+            0 | 6 | 11 => UnreachableCode::Network,
+            1 | 7 | 8 | 12 => UnreachableCode::Host,
+            2 => UnreachableCode::Protocol,
+            3 => UnreachableCode::Port,
+            4 => UnreachableCode::FragmentationNeeded,
+            5 => UnreachableCode::SourceRouteFailed,
+            // Network and host prohibited (RFC 1122) and communication prohibited (RFC 1812).
+            9 | 10 | 13 => UnreachableCode::AdminProhibited,
+            14 => UnreachableCode::HostPrecedenceViolation,
+            15 => UnreachableCode::PrecedenceCutoff,
+            code => UnreachableCode::Other(code),
+        }
+    }
+
+    pub fn from_icmpv6(code: u8) -> Self {
+        match code {
+            0 => UnreachableCode::Network,
+            1 => UnreachableCode::AdminProhibited,
+            2 => UnreachableCode::BeyondScope,
+            3 => UnreachableCode::Host,
+            4 => UnreachableCode::Port,
+            5 => UnreachableCode::SourcePolicyFailed,
+            6 => UnreachableCode::RejectRoute,
+            code => UnreachableCode::Other(code),
+        }
+    }
+
+    /// The annotation classic traceroute prints next to the hop, like `!N`.
+    pub fn flag(&self) -> String {
+        match self {
+            UnreachableCode::Network => "!N".to_string(),
+            UnreachableCode::Host => "!H".to_string(),
+            UnreachableCode::Protocol => "!P".to_string(),
+            UnreachableCode::Port => "".to_string(),
+            UnreachableCode::FragmentationNeeded => "!F".to_string(),
+            UnreachableCode::SourceRouteFailed => "!S".to_string(),
+            UnreachableCode::AdminProhibited => "!X".to_string(),
+            UnreachableCode::HostPrecedenceViolation => "!V".to_string(),
+            UnreachableCode::PrecedenceCutoff => "!C".to_string(),
+            UnreachableCode::BeyondScope => "!B".to_string(),
+            UnreachableCode::SourcePolicyFailed => "!A".to_string(),
+            UnreachableCode::RejectRoute => "!R".to_string(),
+            UnreachableCode::Other(code) => format!("!<{}>", code),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum TracerouteResult {
     IcmpReply(IpAddr, PacketId),
-    IcmpTimeExceeded(IpAddr, PacketId),
-    IcmpDestinationUnreachable(IpAddr),
+    /// Time Exceeded, with the probe's headers as the router quoted them back if we got to see
+    /// them.
+    IcmpTimeExceeded(IpAddr, PacketId, IcmpExtensions, Option<ProbeHeader>),
+    /// Any Destination Unreachable other than port unreachable, for the probe quoted inside it.
+    IcmpDestinationUnreachable {
+        ip: IpAddr,
+        id: PacketId,
+        /// Destination of the quoted probe, so only the trace it belongs to reacts.
+        dst_ip: IpAddr,
+        code: UnreachableCode,
+        extensions: IcmpExtensions,
+        quoted: Option<ProbeHeader>,
+    },
+    /// Port unreachable in response to a UDP probe, which usually means we got to the destination.
+    IcmpPortUnreachable(IpAddr, PacketId, IcmpExtensions, Option<ProbeHeader>),
+    /// Fragmentation Needed or ICMPv6 Packet Too Big: a router couldn't forward the probe
+    /// because the next link's MTU is smaller than it.
+    IcmpPacketTooBig {
+        ip: IpAddr,
+        id: PacketId,
+        /// Destination of the quoted probe, so only the trace it belongs to reacts.
+        dst_ip: IpAddr,
+        /// The next-hop MTU, if the router said. Old IPv4 routers leave it as zero.
+        mtu: Option<u16>,
+        extensions: IcmpExtensions,
+        quoted: Option<ProbeHeader>,
+    },
+    /// SYN-ACK or RST in response to a TCP probe, meaning we got to the destination.
+    TcpReply {
+        ip: IpAddr,
+        id: PacketId,
+        /// Port the segment came from, which is the one the probe was sent to.
+        source_port: u16,
+        /// Port the segment was sent to, which is the one the probe came from.
+        destination_port: u16,
+    },
+}
+
+impl TracerouteResult {
+    /// Multipart extensions, for the ICMP errors that can carry them.
+    pub fn extensions(&self) -> Option<&IcmpExtensions> {
+        match self {
+            TracerouteResult::IcmpTimeExceeded(_, _, extensions, _)
+            | TracerouteResult::IcmpDestinationUnreachable { extensions, .. }
+            | TracerouteResult::IcmpPortUnreachable(_, _, extensions, _)
+            | TracerouteResult::IcmpPacketTooBig { extensions, .. } => Some(extensions),
+            TracerouteResult::IcmpReply(..) | TracerouteResult::TcpReply { .. } => None,
+        }
+    }
+
+    /// The probe's headers as quoted back in an ICMP error, if we got to see them.
+    pub fn quoted(&self) -> Option<&ProbeHeader> {
+        match self {
+            TracerouteResult::IcmpTimeExceeded(_, _, _, quoted)
+            | TracerouteResult::IcmpDestinationUnreachable { quoted, .. }
+            | TracerouteResult::IcmpPortUnreachable(_, _, _, quoted)
+            | TracerouteResult::IcmpPacketTooBig { quoted, .. } => quoted.as_ref(),
+            TracerouteResult::IcmpReply(..) | TracerouteResult::TcpReply { .. } => None,
+        }
+    }
+
+    /// ID of the probe this is a reply to.
+    pub fn id(&self) -> PacketId {
+        match self {
+            TracerouteResult::IcmpReply(_, id)
+            | TracerouteResult::IcmpTimeExceeded(_, id, ..)
+            | TracerouteResult::IcmpDestinationUnreachable { id, .. }
+            | TracerouteResult::IcmpPortUnreachable(_, id, ..)
+            | TracerouteResult::IcmpPacketTooBig { id, .. }
+            | TracerouteResult::TcpReply { id, .. } => *id,
+        }
+    }
+
+    /// Whether this could be a reply to a TCP probe sent from `source_port` to `destination`.
+    /// Segments the host's own connections receive look just like replies, but they come from
+    /// somewhere else or go to a port we didn't send from.
+    pub(crate) fn is_tcp_reply_to(&self, destination: SocketAddr, source_port: u16) -> bool {
+        match *self {
+            TracerouteResult::TcpReply {
+                ip,
+                source_port: reply_source_port,
+                destination_port,
+                ..
+            } => {
+                SocketAddr::new(ip, reply_source_port) == destination
+                    && destination_port == source_port
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Fields of a probe's headers that routers and middleboxes are known to change, either as we
+/// sent them or as a router quoted them back in an ICMP error. Comparing the two shows what
+/// happened to the probe on the way. Fields that the packet doesn't have, that weren't quoted,
+/// or that the kernel fills in are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct ProbeHeader {
+    pub source_ip: Option<IpAddr>,
+    /// TTL or hop limit.
+    pub ttl: u8,
+    pub dscp: u8,
+    pub ecn: u8,
+    /// IPv4 identification. IPv6 only has one in fragments.
+    pub id: Option<u16>,
+    /// UDP or TCP source port, or the Echo Request identifier, which NATs treat as one.
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+    /// Transport checksum. Zero counts as missing, since that's how IPv4 UDP probes go out and
+    /// how ICMPv6 ones look before the kernel fills it in.
+    pub checksum: Option<u16>,
+}
+
+impl ProbeHeader {
+    fn from_ipv4(packet: &ipv4::Ipv4Packet) -> Self {
+        let (source_port, destination_port, checksum) =
+            transport_fields(packet.get_next_level_protocol(), packet.payload());
+        Self {
+            source_ip: Some(IpAddr::V4(packet.get_source())).filter(|ip| !ip.is_unspecified()),
+            ttl: packet.get_ttl(),
+            dscp: packet.get_dscp(),
+            ecn: packet.get_ecn(),
+            id: Some(packet.get_identification()),
+            source_port,
+            destination_port,
+            checksum,
+        }
+    }
+
+    fn from_ipv6(packet: &ipv6::Ipv6Packet) -> Result<Self, DecodeError> {
+        let (protocol, transport) = ipv6_transport(packet)?;
+        let (source_port, destination_port, checksum) = transport_fields(protocol, transport);
+        let traffic_class = packet.get_traffic_class();
+        Ok(Self {
+            source_ip: Some(IpAddr::V6(packet.get_source())).filter(|ip| !ip.is_unspecified()),
+            ttl: packet.get_hop_limit(),
+            dscp: traffic_class >> 2,
+            ecn: traffic_class & 0x3,
+            id: None,
+            source_port,
+            destination_port,
+            checksum,
+        })
+    }
+}
+
+/// Source port, destination port and checksum of a transport header, as far as the bytes go.
+fn transport_fields(
+    protocol: IpNextHeaderProtocol,
+    transport: &[u8],
+) -> (Option<u16>, Option<u16>, Option<u16>) {
+    let (source_port, destination_port, checksum) = match protocol {
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
+            // Only Echo Requests have an identifier.
+            let identifier = quoted_u16(transport, 4)
+                .filter(|_| probe_protocol(protocol, transport) == Some(ProbeProtocol::Icmp));
+            (identifier, None, quoted_u16(transport, 2))
+        }
+        IpNextHeaderProtocols::Udp => (
+            quoted_u16(transport, 0),
+            quoted_u16(transport, 2),
+            quoted_u16(transport, 6),
+        ),
+        IpNextHeaderProtocols::Tcp => (
+            quoted_u16(transport, 0),
+            quoted_u16(transport, 2),
+            quoted_u16(transport, 16),
+        ),
+        _ => (None, None, None),
+    };
+    (
+        source_port,
+        destination_port,
+        checksum.filter(|&checksum| checksum != 0),
+    )
+}
+
+/// A parsed reply along with when it arrived, for timing round trips.
+#[derive(Debug)]
+pub struct ReceivedPacket {
+    pub result: TracerouteResult,
+    /// When the kernel says the reply arrived, on Linux. Elsewhere, and for errors we make up
+    /// ourselves, it's when we read it, which can be up to a poll interval late.
+    pub received_at: Instant,
+    /// TTL or hop limit the reply arrived with, if the transport got to see it.
+    pub ttl: Option<u8>,
+}
+
+/// Counters for the socket replies are read from, since the channel was opened.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct ReceiveStats {
+    /// Frames that made it through the filter and were queued for us. Only the kernel knows
+    /// this, so it's zero for unprivileged channels.
+    pub received: u64,
+    /// Frames that made it through the filter but were dropped because we didn't read them
+    /// fast enough. Replies among them show up as loss. Zero for unprivileged channels too.
+    pub dropped: u64,
+    /// Frames we read but couldn't decode, which were skipped. Anyone can send these.
+    pub undecodable: u64,
+}
+
+/// A probe we sent or a reply we matched, recorded for writing to a capture file.
+#[derive(Debug, Clone)]
+pub struct CapturedPacket {
+    /// The probe's ID, so captures can be split up by whoever sent it.
+    pub id: PacketId,
+    pub timestamp: SystemTime,
+    /// The IP packet and everything after it. Probes are recorded as we built them, so anything
+    /// the kernel fills in on the way out (an unspecified source address, ICMPv6 checksums) is
+    /// still zero.
+    pub data: Vec<u8>,
+}
+
+/// Something that can send probes and report what comes back. `Trace` and friends are written
+/// against this rather than a particular channel, so they can run over a different backend or a
+/// simulated network. `TracerouteChannel` is the real one.
+pub trait ProbeTransport {
+    /// Send a probe using the given protocol. Whatever it causes should come back from `poll`
+    /// carrying the same `id`.
+    ///
+    /// With a `flow`, every header field that load balancers hash on is held constant for that
+    /// flow. Without one, probes vary like classic traceroute.
+    ///
+    /// Probes too big to leave the interface should come back from `poll` as a Packet Too Big
+    /// from the source address rather than failing.
+    fn send_probe(
+        &mut self,
+        protocol: ProbeProtocol,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError>;
+
+    /// Return the next reply if there is one, waiting briefly at most. Replies should be
+    /// stamped with the same clock the traces using this are configured with.
+    fn poll(&mut self) -> Result<Option<ReceivedPacket>, TracerouteError>;
+
+    /// Packets recorded since the last call, oldest first, for transports that can capture
+    /// them. Nothing by default.
+    fn take_captured(&mut self) -> Vec<CapturedPacket> {
+        Vec::new()
+    }
+
+    /// Receive counters, for transports that have them. Nothing by default.
+    fn receive_stats(&mut self) -> Result<Option<ReceiveStats>, TracerouteError> {
+        Ok(None)
+    }
+
+    /// Headers of the last probe sent with `id`, for transports that build them themselves, so
+    /// they can be compared with what routers quote back. Nothing by default.
+    fn sent_header(&self, _id: PacketId) -> Option<ProbeHeader> {
+        None
+    }
 }
 
+/// IPv6 raw sockets compute checksums per protocol, so each probe protocol gets its own sender.
+struct Ipv6Senders {
+    icmpv6: TransportSender,
+    udp: TransportSender,
+    tcp: TransportSender,
+}
+
+/// Sends probes and reads back whatever they cause, with raw sockets or, on Linux, without.
 pub struct TracerouteChannel {
-    rx: Box<dyn DataLinkReceiver>,
+    backend: Backend,
+}
+
+enum Backend {
+    Raw(RawChannel),
+    #[cfg(target_os = "linux")]
+    Unprivileged(UnprivilegedChannel),
+}
+
+/// On Linux we open the receive socket ourselves to filter it. Elsewhere pnet sees everything.
+#[cfg(target_os = "linux")]
+type Receiver = PacketSocket;
+#[cfg(not(target_os = "linux"))]
+type Receiver = Box<dyn DataLinkReceiver>;
+
+/// Crafts whole IP packets and reads replies off the interface, which needs `CAP_NET_RAW`.
+struct RawChannel {
+    rx: Receiver,
+    link_type: LinkType,
     v4_tx: TransportSender,
-    v6_tx: Option<TransportSender>,
+    v6_tx: Option<Ipv6Senders>,
+    /// IPv4 address to send from, which TCP checksums need. Without one, the kernel fills it in.
+    source_ipv4: Option<Ipv4Addr>,
+    /// Global IPv6 address to send from, which transport checksums need.
+    source_ipv6: Option<Ipv6Addr>,
+    /// Source port for UDP and TCP probes, fixed for the lifetime of the channel.
+    source_port: u16,
     /// Sequence number that increments per request. Not used for matching.
     sequence_number: u16,
+    /// Probes and matched replies since the last `take_captured`, while capturing.
+    captured: Option<Vec<CapturedPacket>>,
+    /// MTU of the interface, for probes too big to send.
+    interface_mtu: Option<u16>,
+    /// Packet Too Bigs for probes the kernel refused to send, returned before anything else.
+    local_errors: Vec<TracerouteResult>,
+    /// Headers of the last probe sent with each ID. IDs are 16 bits, which keeps this bounded.
+    sent_headers: HashMap<PacketId, ProbeHeader>,
+    /// Where the last TCP probe with each ID went and the port it left from, so that segments
+    /// on the host's own connections aren't mistaken for replies.
+    tcp_probes: HashMap<PacketId, (SocketAddr, u16)>,
+    /// Frames that got through the filter but didn't decode, for `ReceiveStats`.
+    undecodable: u64,
+}
+
+fn open_ipv6_sender(protocol: IpNextHeaderProtocol) -> Result<TransportSender, TracerouteError> {
+    match transport_channel(512, Layer3(TransportProtocol::Ipv6(protocol))) {
+        Ok((tx, _)) => Ok(tx),
+        Err(e) => Err(TracerouteError::Ipv6ChannelIo(e)),
+    }
+}
+
+/// Addresses to send probes from. Whichever isn't set is picked from the interface.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SourceAddresses {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// The requested source addresses, as long as the interface has them, or else the first IPv4
+/// address of the interface and its first global IPv6 address.
+fn interface_source_ips(
+    interface: &NetworkInterface,
+    requested: SourceAddresses,
+) -> Result<(Option<Ipv4Addr>, Option<Ipv6Addr>), TracerouteError> {
+    let requested_ips = [
+        requested.ipv4.map(IpAddr::V4),
+        requested.ipv6.map(IpAddr::V6),
+    ];
+    for ip in requested_ips.into_iter().flatten() {
+        if !interface.ips.iter().any(|network| network.ip() == ip) {
+            return Err(TracerouteError::SourceAddressNotOnInterface(ip));
+        }
+    }
+
+    let source_ipv4 = requested.ipv4.or_else(|| {
+        interface.ips.iter().find_map(|network| match network.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            _ => None,
+        })
+    });
+    let source_ipv6 = requested.ipv6.or_else(|| {
+        interface.ips.iter().find_map(|network| match network.ip() {
+            IpAddr::V6(ip) if !is_ipv6_link_local(&ip) && !ip.is_loopback() => Some(ip),
+            _ => None,
+        })
+    });
+    Ok((source_ipv4, source_ipv6))
+}
+
+/// Make a sender only ever send out of the interface we listen on, whatever the routing table
+/// says, so replies to its probes come back where we can see them.
+#[cfg(target_os = "linux")]
+fn bind_to_interface(
+    sender: &TransportSender,
+    interface: &NetworkInterface,
+) -> Result<(), TracerouteError> {
+    let name = interface.name.as_bytes();
+    // SAFETY: `name` outlives the call and the length matches it.
+    let result = unsafe {
+        libc::setsockopt(
+            sender.socket.fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(TracerouteError::BindToInterface(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Elsewhere there's no `SO_BINDTODEVICE`, so the routing table picks the interface.
+#[cfg(not(target_os = "linux"))]
+fn bind_to_interface(
+    _sender: &TransportSender,
+    _interface: &NetworkInterface,
+) -> Result<(), TracerouteError> {
+    Ok(())
+}
+
+/// The interface's MTU, which only Linux tells us about.
+fn interface_mtu(interface: &NetworkInterface) -> Option<u16> {
+    #[cfg(target_os = "linux")]
+    {
+        let mtu = std::fs::read_to_string(format!("/sys/class/net/{}/mtu", interface.name));
+        if let Ok(mtu) = mtu {
+            return mtu.trim().parse::<u32>().ok().map(saturating_u16);
+        }
+    }
+
+    let _ = interface;
+    None
+}
+
+fn saturating_u16(value: u32) -> u16 {
+    value.min(u16::MAX as u32) as u16
+}
+
+/// Whether a send failed because the packet is bigger than the interface's MTU.
+fn is_too_big_to_send(error: &TracerouteError) -> bool {
+    match error {
+        TracerouteError::Ipv4ChannelIo(error) | TracerouteError::Ipv6ChannelIo(error) => {
+            error.raw_os_error() == Some(libc::EMSGSIZE)
+        }
+        _ => false,
+    }
+}
+
+fn is_ipv6_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Ones' complement addition, as used by Internet checksums.
+fn ones_complement_add(a: u16, b: u16) -> u16 {
+    let (sum, carry) = a.overflowing_add(b);
+    sum + carry as u16
+}
+
+/// Payload word that cancels out `id` in a checksum so that the sum of both is always `target`.
+/// This lets the identifier vary while the checksum, which routers hash on, stays constant.
+fn checksum_balancing_word(id: PacketId, target: u16) -> u16 {
+    ones_complement_add(target, !id.0)
+}
+
+/// TCP probes carry the packet ID in their sequence number, which survives in quoted headers
+/// and comes back (plus one) in the acknowledgement number of a SYN-ACK or RST.
+fn tcp_sequence_for_id(id: PacketId) -> u32 {
+    id.0 as u32
+}
+
+/// Recover the packet ID from a packet quoted inside an ICMP error. TCP probes prefer the
+/// sequence number because some middleboxes rewrite the IP identification.
+fn quoted_ipv4_packet_id(packet: &ipv4::Ipv4Packet) -> PacketId {
+    if packet.get_next_level_protocol() == IpNextHeaderProtocols::Tcp {
+        if let Some(sequence) = quoted_tcp_sequence(packet.payload()) {
+            return PacketId(sequence as u16);
+        }
+    }
+    PacketId(packet.get_identification())
+}
+
+/// The IPv6 flow label only carries the packet ID outside of Paris mode, so prefer the quoted
+/// transport header: the ICMPv6 identifier, UDP checksum, or TCP sequence number.
+fn quoted_ipv6_packet_id(packet: &ipv6::Ipv6Packet) -> Result<PacketId, DecodeError> {
+    let (protocol, transport) = ipv6_transport(packet)?;
+    let quoted_id = match protocol {
+        IpNextHeaderProtocols::Icmpv6 => quoted_u16(transport, 4),
+        IpNextHeaderProtocols::Udp => quoted_u16(transport, 6),
+        IpNextHeaderProtocols::Tcp => {
+            quoted_tcp_sequence(transport).map(|sequence| sequence as u16)
+        }
+        _ => None,
+    };
+    Ok(quoted_id.map_or(PacketId(packet.get_flow_label() as u16), PacketId))
+}
+
+fn quoted_u16(payload: &[u8], offset: usize) -> Option<u16> {
+    let bytes = payload.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Match a SYN-ACK or RST from the destination back to the SYN that caused it. Every port we
+/// send from, fixed or derived from a `FlowId`, has the top bit set. Whether it really came from
+/// where a probe went is up to whoever knows where that was.
+fn tcp_reply_id(packet: &tcp::TcpPacket) -> Option<PacketId> {
+    let flags = packet.get_flags();
+    let is_syn_ack = flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN | TcpFlags::ACK;
+    let is_rst_ack = flags & (TcpFlags::RST | TcpFlags::ACK) == TcpFlags::RST | TcpFlags::ACK;
+    if packet.get_destination() & 0x8000 != 0 && (is_syn_ack || is_rst_ack) {
+        Some(PacketId(packet.get_acknowledgement().wrapping_sub(1) as u16))
+    } else {
+        None
+    }
+}
+
+/// ICMP errors are only guaranteed to quote 8 bytes of the transport header, so this reads the
+/// sequence number by hand instead of requiring a full `TcpPacket`.
+fn quoted_tcp_sequence(payload: &[u8]) -> Option<u32> {
+    let bytes = payload.get(4..8)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
 }
 
 impl TracerouteChannel {
+    /// Open raw channels on an interface, detecting its link type.
     pub fn from_interface(
         interface: NetworkInterface,
         enable_ipv6: bool,
     ) -> Result<Self, TracerouteError> {
-        let (_, rx) = match channel(
-            &interface,
-            Config {
-                read_timeout: Some(Duration::from_millis(50)),
-                ..Default::default()
-            },
-        ) {
-            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => return Err(TracerouteError::Unknown),
-            Err(e) => return Err(TracerouteError::RxChannelIo(e)),
-        };
+        let link_type = LinkType::detect(&interface);
+        Self::from_interface_with_link_type(interface, enable_ipv6, link_type)
+    }
 
-        let (v4_tx, _) = match transport_channel(
-            512,
-            Layer3(TransportProtocol::Ipv4(IpNextHeaderProtocols::Icmp)),
-        ) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(e) => return Err(TracerouteError::Ipv4ChannelIo(e)),
-        };
-        let v6_tx = if enable_ipv6 {
-            let (v6_tx, _) = match transport_channel(
-                512,
-                Layer3(TransportProtocol::Ipv6(IpNextHeaderProtocols::Icmpv6)),
-            ) {
-                Ok((tx, rx)) => (tx, rx),
-                Err(e) => return Err(TracerouteError::Ipv6ChannelIo(e)),
-            };
-            Some(v6_tx)
-        } else {
-            None
-        };
+    /// Like `from_interface`, for when detection gets the link type wrong.
+    pub fn from_interface_with_link_type(
+        interface: NetworkInterface,
+        enable_ipv6: bool,
+        link_type: LinkType,
+    ) -> Result<Self, TracerouteError> {
+        Self::from_interface_with_source(
+            interface,
+            enable_ipv6,
+            link_type,
+            SourceAddresses::default(),
+        )
+    }
 
+    /// Like `from_interface_with_link_type`, sending from specific addresses. Fails if the
+    /// interface doesn't have them.
+    pub fn from_interface_with_source(
+        interface: NetworkInterface,
+        enable_ipv6: bool,
+        link_type: LinkType,
+        source: SourceAddresses,
+    ) -> Result<Self, TracerouteError> {
         Ok(Self {
-            rx,
-            v4_tx,
-            v6_tx,
-            sequence_number: 0,
+            backend: Backend::Raw(RawChannel::new(interface, enable_ipv6, link_type, source)?),
+        })
+    }
+
+    /// Open ping sockets and UDP sockets that collect ICMP errors, which needs no privileges as
+    /// long as our group is in `net.ipv4.ping_group_range`. TCP probes aren't supported, and
+    /// UDP probes can't be held to a flow since their destination port identifies them.
+    #[cfg(target_os = "linux")]
+    pub fn unprivileged(
+        interface: NetworkInterface,
+        enable_ipv6: bool,
+    ) -> Result<Self, TracerouteError> {
+        Self::unprivileged_with_source(interface, enable_ipv6, SourceAddresses::default())
+    }
+
+    /// Like `unprivileged`, sending from specific addresses. Fails if the interface doesn't
+    /// have them. The sockets are bound to the addresses but not to the interface, which would
+    /// need privileges on older kernels.
+    #[cfg(target_os = "linux")]
+    pub fn unprivileged_with_source(
+        interface: NetworkInterface,
+        enable_ipv6: bool,
+        source: SourceAddresses,
+    ) -> Result<Self, TracerouteError> {
+        Ok(Self {
+            backend: Backend::Unprivileged(UnprivilegedChannel::new(
+                &interface,
+                enable_ipv6,
+                source,
+            )?),
         })
     }
 
@@ -98,178 +929,1098 @@ impl TracerouteChannel {
         dst_ip: IpAddr,
         ttl: u8,
         id: PacketId,
+        flow: Option<FlowId>,
     ) -> Result<(), TracerouteError> {
-        self.sequence_number = self.sequence_number.wrapping_add(1);
-        match dst_ip {
-            IpAddr::V4(dst_ipv4) => {
-                let icmp_len = icmp::echo_request::MutableEchoRequestPacket::minimum_packet_size();
-                let ip_len = ipv4::MutableIpv4Packet::minimum_packet_size() + icmp_len;
-                let ip_header_len = ipv4::MutableIpv4Packet::minimum_packet_size() / 4;
-
-                // Construct the IP packet
-                let mut ip_buffer = vec![0; ip_len];
-                let mut ip_packet = ipv4::MutableIpv4Packet::new(&mut ip_buffer)
-                    .ok_or(TracerouteError::PacketConstruction)?;
+        self.send_probe(
+            ProbeProtocol::Icmp,
+            dst_ip,
+            ttl,
+            id,
+            flow,
+            &ProbeOptions::default(),
+        )
+    }
 
-                ip_packet.set_version(4);
-                ip_packet.set_header_length(ip_header_len as u8);
-                ip_packet.set_total_length(ip_len as u16);
-                ip_packet.set_identification(id.0);
-                ip_packet.set_flags(ipv4::Ipv4Flags::DontFragment);
-                ip_packet.set_ttl(ttl);
-                ip_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
-                ip_packet.set_source(Ipv4Addr::UNSPECIFIED);
-                ip_packet.set_destination(dst_ipv4);
-
-                // Construct the ICMP packet
-                let mut icmp_buffer = vec![0; icmp_len];
-                let mut icmp_packet =
-                    icmp::echo_request::MutableEchoRequestPacket::new(&mut icmp_buffer)
-                        .ok_or(TracerouteError::PacketConstruction)?;
-
-                icmp_packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
-                icmp_packet.set_icmp_code(icmp::echo_request::IcmpCodes::NoCode);
-                icmp_packet.set_identifier(id.0);
-                icmp_packet.set_sequence_number(self.sequence_number);
-
-                let icmp_checksum = checksum(icmp_packet.packet(), 1);
-                icmp_packet.set_checksum(icmp_checksum);
-
-                // Send!
-                ip_packet.set_payload(icmp_packet.packet());
-                self.v4_tx
-                    .send_to(ip_packet, dst_ip)
-                    .map_err(TracerouteError::Ipv4ChannelIo)?;
-            }
-            IpAddr::V6(dst_ipv6) => {
-                let icmpv6_len =
-                    icmpv6::echo_request::MutableEchoRequestPacket::minimum_packet_size();
-                let ipv6_len = ipv6::MutableIpv6Packet::minimum_packet_size() + icmpv6_len;
+    pub fn send_udp(
+        &mut self,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+    ) -> Result<(), TracerouteError> {
+        self.send_probe(
+            ProbeProtocol::Udp,
+            dst_ip,
+            ttl,
+            id,
+            flow,
+            &ProbeOptions::default(),
+        )
+    }
 
-                // Construct the IP packet
-                let mut ipv6_buffer = vec![0; ipv6_len];
-                let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_buffer)
-                    .ok_or(TracerouteError::PacketConstruction)?;
+    pub fn send_tcp_syn(
+        &mut self,
+        dst_ip: IpAddr,
+        dst_port: u16,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+    ) -> Result<(), TracerouteError> {
+        self.send_probe(
+            ProbeProtocol::Tcp { port: dst_port },
+            dst_ip,
+            ttl,
+            id,
+            flow,
+            &ProbeOptions::default(),
+        )
+    }
 
-                ipv6_packet.set_version(6);
-                ipv6_packet.set_flow_label(id.0 as u32);
-                ipv6_packet.set_payload_length(icmpv6_len as u16);
-                ipv6_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
-                ipv6_packet.set_hop_limit(ttl);
-                ipv6_packet.set_source(Ipv6Addr::UNSPECIFIED);
-                ipv6_packet.set_destination(dst_ipv6);
-
-                // Construct the ICMPv6 packet
-                let mut icmpv6_buffer = vec![0; icmpv6_len];
-                let mut icmpv6_packet =
-                    icmpv6::echo_request::MutableEchoRequestPacket::new(&mut icmpv6_buffer)
-                        .ok_or(TracerouteError::PacketConstruction)?;
-
-                icmpv6_packet.set_icmpv6_type(icmpv6::Icmpv6Types::EchoRequest);
-                icmpv6_packet.set_icmpv6_code(icmpv6::echo_request::Icmpv6Codes::NoCode);
-                icmpv6_packet.set_identifier(id.0);
-                icmpv6_packet.set_sequence_number(self.sequence_number);
-
-                // ICMPv6 checksum should be calculated in the kernel.
-
-                // Send!
-                ipv6_packet.set_payload(icmpv6_packet.packet());
-                self.v6_tx
-                    .as_mut()
-                    .ok_or(TracerouteError::Ipv6Disabled)?
-                    .send_to(ipv6_packet, dst_ip)
-                    .map_err(TracerouteError::Ipv6ChannelIo)?;
+    /// Start or stop recording every probe sent and every reply matched to one, to be collected
+    /// with `take_captured`. Only raw channels can do this, since otherwise the kernel builds
+    /// the packets and strips the replies.
+    pub fn set_capture(&mut self, enabled: bool) {
+        match &mut self.backend {
+            Backend::Raw(channel) => {
+                channel.captured = enabled.then(Vec::new);
             }
+            #[cfg(target_os = "linux")]
+            Backend::Unprivileged(_) => {}
         }
-
-        Ok(())
     }
+}
 
-    pub fn poll(&mut self) -> Result<Option<TracerouteResult>, TracerouteError> {
-        let start = Instant::now();
-        match self.rx.next() {
-            Ok(packet) => Ok((|| {
-                let packet = EthernetPacket::new(packet)?;
-
-                match packet.get_ethertype() {
-                    EtherTypes::Ipv4 => {
-                        let packet = ipv4::Ipv4Packet::new(packet.payload())?;
-                        let source_ip = packet.get_source();
-                        if packet.get_next_level_protocol() == IpNextHeaderProtocols::Icmp {
-                            let packet = icmp::IcmpPacket::new(packet.payload())?;
-                            match packet.get_icmp_type() {
-                                icmp::IcmpTypes::EchoReply => {
-                                    let packet =
-                                        icmp::echo_reply::EchoReplyPacket::new(packet.packet())?;
-                                    Some(TracerouteResult::IcmpReply(
-                                        IpAddr::V4(source_ip),
-                                        PacketId(packet.get_identifier()),
-                                    ))
-                                }
-                                icmp::IcmpTypes::TimeExceeded => {
-                                    let packet = icmp::time_exceeded::TimeExceededPacket::new(
-                                        packet.packet(),
-                                    )?;
-                                    let packet = ipv4::Ipv4Packet::new(packet.payload())?;
-
-                                    Some(TracerouteResult::IcmpTimeExceeded(
-                                        IpAddr::V4(source_ip),
-                                        PacketId(packet.get_identification()),
-                                    ))
-                                }
-                                icmp::IcmpTypes::DestinationUnreachable => {
-                                    Some(TracerouteResult::IcmpDestinationUnreachable(IpAddr::V4(
-                                        source_ip,
-                                    )))
-                                }
-                                _ => None,
-                            }
-                        } else {
-                            None
-                        }
+impl ProbeTransport for TracerouteChannel {
+    fn send_probe(
+        &mut self,
+        protocol: ProbeProtocol,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        match &mut self.backend {
+            Backend::Raw(channel) => channel.send_probe(protocol, dst_ip, ttl, id, flow, options),
+            #[cfg(target_os = "linux")]
+            Backend::Unprivileged(channel) => {
+                channel.send_probe(protocol, dst_ip, ttl, id, flow, options)
+            }
+        }
+    }
+
+    fn poll(&mut self) -> Result<Option<ReceivedPacket>, TracerouteError> {
+        match &mut self.backend {
+            Backend::Raw(channel) => channel.poll(),
+            #[cfg(target_os = "linux")]
+            Backend::Unprivileged(channel) => channel.poll(),
+        }
+    }
+
+    fn take_captured(&mut self) -> Vec<CapturedPacket> {
+        match &mut self.backend {
+            Backend::Raw(channel) => channel.captured.as_mut().map(std::mem::take),
+            #[cfg(target_os = "linux")]
+            Backend::Unprivileged(_) => None,
+        }
+        .unwrap_or_default()
+    }
+
+    /// Counters for the filtered receive socket of raw channels on Linux, and for what
+    /// unprivileged channels couldn't decode.
+    fn receive_stats(&mut self) -> Result<Option<ReceiveStats>, TracerouteError> {
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Raw(channel) => {
+                let stats = channel.rx.stats().map_err(TracerouteError::RxChannelIo)?;
+                Ok(Some(ReceiveStats {
+                    undecodable: channel.undecodable,
+                    ..stats
+                }))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Backend::Raw(_) => Ok(None),
+            #[cfg(target_os = "linux")]
+            Backend::Unprivileged(channel) => Ok(Some(channel.receive_stats())),
+        }
+    }
+
+    /// Unprivileged channels leave the headers to the kernel, so only raw ones know them.
+    fn sent_header(&self, id: PacketId) -> Option<ProbeHeader> {
+        match &self.backend {
+            Backend::Raw(channel) => channel.sent_headers.get(&id).copied(),
+            #[cfg(target_os = "linux")]
+            Backend::Unprivileged(_) => None,
+        }
+    }
+}
+
+/// Only replies get through the filter, so we never see most of the interface's traffic.
+#[cfg(target_os = "linux")]
+fn open_receiver(interface: &NetworkInterface) -> Result<Receiver, TracerouteError> {
+    PacketSocket::open(interface, READ_TIMEOUT).map_err(TracerouteError::RxChannelIo)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_receiver(interface: &NetworkInterface) -> Result<Receiver, TracerouteError> {
+    match channel(
+        interface,
+        Config {
+            read_timeout: Some(READ_TIMEOUT),
+            ..Default::default()
+        },
+    ) {
+        Ok(Channel::Ethernet(_, rx)) => Ok(rx),
+        Ok(_) => Err(TracerouteError::Unknown),
+        Err(e) => Err(TracerouteError::RxChannelIo(e)),
+    }
+}
+
+/// The next frame off the interface, with when the kernel says it arrived.
+#[cfg(target_os = "linux")]
+fn receive(rx: &mut Receiver) -> io::Result<(&[u8], Instant)> {
+    rx.receive()
+}
+
+/// pnet doesn't pass on the kernel's timestamps, so this is when we read the frame.
+#[cfg(not(target_os = "linux"))]
+fn receive(rx: &mut Receiver) -> io::Result<(&[u8], Instant)> {
+    rx.next().map(|frame| (frame, Instant::now()))
+}
+
+impl RawChannel {
+    fn new(
+        interface: NetworkInterface,
+        enable_ipv6: bool,
+        link_type: LinkType,
+        source: SourceAddresses,
+    ) -> Result<Self, TracerouteError> {
+        let (source_ipv4, source_ipv6) = interface_source_ips(&interface, source)?;
+        let rx = open_receiver(&interface)?;
+
+        let (v4_tx, _) = match transport_channel(
+            512,
+            Layer3(TransportProtocol::Ipv4(IpNextHeaderProtocols::Icmp)),
+        ) {
+            Ok((tx, rx)) => (tx, rx),
+            Err(e) => return Err(TracerouteError::Ipv4ChannelIo(e)),
+        };
+        bind_to_interface(&v4_tx, &interface)?;
+        let v6_tx = if enable_ipv6 {
+            let senders = Ipv6Senders {
+                icmpv6: open_ipv6_sender(IpNextHeaderProtocols::Icmpv6)?,
+                udp: open_ipv6_sender(IpNextHeaderProtocols::Udp)?,
+                tcp: open_ipv6_sender(IpNextHeaderProtocols::Tcp)?,
+            };
+            for sender in [&senders.icmpv6, &senders.udp, &senders.tcp] {
+                bind_to_interface(sender, &interface)?;
+            }
+            Some(senders)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            rx,
+            link_type,
+            v4_tx,
+            v6_tx,
+            source_ipv4,
+            source_ipv6,
+            source_port: rand::thread_rng().gen_range(49152..=65535),
+            sequence_number: 0,
+            captured: None,
+            interface_mtu: interface_mtu(&interface),
+            local_errors: Vec::new(),
+            sent_headers: HashMap::new(),
+            tcp_probes: HashMap::new(),
+            undecodable: 0,
+        })
+    }
+
+    fn send_probe(
+        &mut self,
+        protocol: ProbeProtocol,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        let result = match protocol {
+            ProbeProtocol::Icmp => self.send_echo(dst_ip, ttl, id, flow, options),
+            ProbeProtocol::Udp => self.send_udp(dst_ip, ttl, id, flow, options),
+            ProbeProtocol::Tcp { port } => self.send_tcp_syn(dst_ip, port, ttl, id, flow, options),
+        };
+
+        // Bigger than the interface's MTU, so it's our own first hop that's too small.
+        match result {
+            Err(error) if options.size.is_some() && is_too_big_to_send(&error) => {
+                let source_ip = match dst_ip {
+                    IpAddr::V4(_) => self.source_ipv4.map(IpAddr::V4),
+                    IpAddr::V6(_) => self.source_ipv6.map(IpAddr::V6),
+                };
+                self.local_errors.push(TracerouteResult::IcmpPacketTooBig {
+                    ip: source_ip.ok_or(error)?,
+                    id,
+                    dst_ip,
+                    mtu: self.interface_mtu,
+                    extensions: IcmpExtensions::default(),
+                    quoted: None,
+                });
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn send_echo(
+        &mut self,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        let echo = echo_request(dst_ip, id, self.sequence_number, flow, options)?;
+
+        match dst_ip {
+            IpAddr::V4(dst_ipv4) => self.send_ipv4(
+                self.source_ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                dst_ipv4,
+                ttl,
+                id,
+                options.traffic_class(),
+                IpNextHeaderProtocols::Icmp,
+                &echo,
+            ),
+            IpAddr::V6(dst_ipv6) => self.send_ipv6(
+                self.source_ipv6.unwrap_or(Ipv6Addr::UNSPECIFIED),
+                dst_ipv6,
+                ttl,
+                id,
+                options.traffic_class(),
+                options.flow_label(id, flow),
+                IpNextHeaderProtocols::Icmpv6,
+                &echo,
+            ),
+        }
+    }
+
+    fn send_udp(
+        &mut self,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        match dst_ip {
+            IpAddr::V4(dst_ipv4) => {
+                let src_ipv4 = self.source_ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED);
+                let udp = udp_probe(
+                    IpAddr::V4(src_ipv4),
+                    dst_ip,
+                    ttl,
+                    id,
+                    flow,
+                    self.source_port,
+                    options,
+                )?;
+                self.send_ipv4(
+                    src_ipv4,
+                    dst_ipv4,
+                    ttl,
+                    id,
+                    options.traffic_class(),
+                    IpNextHeaderProtocols::Udp,
+                    &udp,
+                )
+            }
+            IpAddr::V6(dst_ipv6) => {
+                // UDP checksums are mandatory over IPv6, which means we need to know our address.
+                let src_ipv6 = self
+                    .source_ipv6
+                    .ok_or(TracerouteError::NoSourceAddress("IPv6"))?;
+                let udp = udp_probe(
+                    IpAddr::V6(src_ipv6),
+                    dst_ip,
+                    ttl,
+                    id,
+                    flow,
+                    self.source_port,
+                    options,
+                )?;
+                self.send_ipv6(
+                    src_ipv6,
+                    dst_ipv6,
+                    ttl,
+                    id,
+                    options.traffic_class(),
+                    options.flow_label(id, flow),
+                    IpNextHeaderProtocols::Udp,
+                    &udp,
+                )
+            }
+        }
+    }
+
+    fn send_tcp_syn(
+        &mut self,
+        dst_ip: IpAddr,
+        dst_port: u16,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        let src_port = flow.map_or(self.source_port, FlowId::source_port);
+
+        // TCP checksums are mandatory for both IP versions, so we need to know our address.
+        match dst_ip {
+            IpAddr::V4(dst_ipv4) => {
+                let src_ipv4 = self
+                    .source_ipv4
+                    .ok_or(TracerouteError::NoSourceAddress("IPv4"))?;
+                let syn = tcp_syn(
+                    IpAddr::V4(src_ipv4),
+                    dst_ip,
+                    dst_port,
+                    src_port,
+                    id,
+                    options,
+                )?;
+                self.send_ipv4(
+                    src_ipv4,
+                    dst_ipv4,
+                    ttl,
+                    id,
+                    options.traffic_class(),
+                    IpNextHeaderProtocols::Tcp,
+                    &syn,
+                )?;
+            }
+            IpAddr::V6(dst_ipv6) => {
+                let src_ipv6 = self
+                    .source_ipv6
+                    .ok_or(TracerouteError::NoSourceAddress("IPv6"))?;
+                let syn = tcp_syn(
+                    IpAddr::V6(src_ipv6),
+                    dst_ip,
+                    dst_port,
+                    src_port,
+                    id,
+                    options,
+                )?;
+                self.send_ipv6(
+                    src_ipv6,
+                    dst_ipv6,
+                    ttl,
+                    id,
+                    options.traffic_class(),
+                    options.flow_label(id, flow),
+                    IpNextHeaderProtocols::Tcp,
+                    &syn,
+                )?;
+            }
+        }
+        self.tcp_probes
+            .insert(id, (SocketAddr::new(dst_ip, dst_port), src_port));
+
+        Ok(())
+    }
+
+    /// Wrap a transport packet in an IPv4 header carrying `id` and send it.
+    #[allow(clippy::too_many_arguments)]
+    fn send_ipv4(
+        &mut self,
+        src_ipv4: Ipv4Addr,
+        dst_ipv4: Ipv4Addr,
+        ttl: u8,
+        id: PacketId,
+        tos: u8,
+        protocol: IpNextHeaderProtocol,
+        payload: &[u8],
+    ) -> Result<(), TracerouteError> {
+        let ip_buffer = ipv4_probe(src_ipv4, dst_ipv4, ttl, id, tos, protocol, payload)?;
+        let ip_packet =
+            ipv4::Ipv4Packet::new(&ip_buffer).ok_or(TracerouteError::PacketConstruction)?;
+        self.capture(id, ip_packet.packet());
+        self.sent_headers
+            .insert(id, ProbeHeader::from_ipv4(&ip_packet));
+
+        self.v4_tx
+            .send_to(ip_packet, IpAddr::V4(dst_ipv4))
+            .map_err(TracerouteError::Ipv4ChannelIo)?;
+        Ok(())
+    }
+
+    /// Wrap a transport packet in an IPv6 header and send it.
+    #[allow(clippy::too_many_arguments)]
+    fn send_ipv6(
+        &mut self,
+        src_ipv6: Ipv6Addr,
+        dst_ipv6: Ipv6Addr,
+        ttl: u8,
+        id: PacketId,
+        traffic_class: u8,
+        flow_label: u32,
+        protocol: IpNextHeaderProtocol,
+        payload: &[u8],
+    ) -> Result<(), TracerouteError> {
+        let ipv6_buffer = ipv6_probe(
+            src_ipv6,
+            dst_ipv6,
+            ttl,
+            traffic_class,
+            flow_label,
+            protocol,
+            payload,
+        )?;
+        let ipv6_packet =
+            ipv6::Ipv6Packet::new(&ipv6_buffer).ok_or(TracerouteError::PacketConstruction)?;
+        self.capture(id, ipv6_packet.packet());
+        if let Ok(header) = ProbeHeader::from_ipv6(&ipv6_packet) {
+            self.sent_headers.insert(id, header);
+        }
+
+        let senders = self.v6_tx.as_mut().ok_or(TracerouteError::Ipv6Disabled)?;
+        let sender = match protocol {
+            IpNextHeaderProtocols::Udp => &mut senders.udp,
+            IpNextHeaderProtocols::Tcp => &mut senders.tcp,
+            _ => &mut senders.icmpv6,
+        };
+        sender
+            .send_to(ipv6_packet, IpAddr::V6(dst_ipv6))
+            .map_err(TracerouteError::Ipv6ChannelIo)?;
+        Ok(())
+    }
+
+    fn capture(&mut self, id: PacketId, packet: &[u8]) {
+        if let Some(captured) = &mut self.captured {
+            captured.push(CapturedPacket {
+                id,
+                timestamp: SystemTime::now(),
+                data: packet.to_vec(),
+            });
+        }
+    }
+
+    fn poll(&mut self) -> Result<Option<ReceivedPacket>, TracerouteError> {
+        if !self.local_errors.is_empty() {
+            return Ok(Some(ReceivedPacket {
+                result: self.local_errors.remove(0),
+                received_at: Instant::now(),
+                ttl: None,
+            }));
+        }
+
+        loop {
+            let (packet, received_at) = match receive(&mut self.rx) {
+                Ok(received) => received,
+                Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(error) => return Err(TracerouteError::RxChannelIo(error)),
+            };
+            // Anything on the internet can send us a broken frame, so those are only counted.
+            // Failing the poll would hold up the replies queued behind them.
+            let Ok(result) = decode_reply(self.link_type, packet) else {
+                self.undecodable += 1;
+                continue;
+            };
+            let result = result.filter(|result| is_reply_to_probe(&self.tcp_probes, result));
+
+            if let (Some(result), Some(captured)) = (&result, &mut self.captured) {
+                if let Some((_, packet)) = self.link_type.network_packet(packet) {
+                    captured.push(CapturedPacket {
+                        id: result.id(),
+                        timestamp: SystemTime::now(),
+                        data: packet.to_vec(),
+                    });
+                }
+            }
+
+            return Ok(result.map(|result| ReceivedPacket {
+                result,
+                received_at,
+                ttl: reply_ttl(self.link_type, packet),
+            }));
+        }
+    }
+}
+
+/// An ICMP or ICMPv6 Echo Request, depending on where it's going. `sequence_number` is only
+/// used outside of a flow.
+fn echo_request(
+    dst_ip: IpAddr,
+    id: PacketId,
+    sequence_number: u16,
+    flow: Option<FlowId>,
+    options: &ProbeOptions,
+) -> Result<Vec<u8>, TracerouteError> {
+    // In a flow, the sequence number is fixed and an extra payload word balances out the
+    // identifier, so the checksum only depends on the flow.
+    let (sequence_number, mut payload) = match flow {
+        Some(flow) => (
+            0,
+            checksum_balancing_word(id, flow.0).to_be_bytes().to_vec(),
+        ),
+        None => (sequence_number, vec![]),
+    };
+    // Echo Requests have the same 8 byte header in ICMP and ICMPv6. Padding after the
+    // balancing word is the same for every probe, so the checksum still only depends on
+    // the flow.
+    let echo_len = icmp::echo_request::EchoRequestPacket::minimum_packet_size() + payload.len();
+    payload.extend(options.padding(dst_ip, echo_len));
+
+    match dst_ip {
+        IpAddr::V4(_) => {
+            let icmp_len =
+                icmp::echo_request::MutableEchoRequestPacket::minimum_packet_size() + payload.len();
+
+            // Construct the ICMP packet
+            let mut icmp_buffer = vec![0; icmp_len];
+            let mut icmp_packet =
+                icmp::echo_request::MutableEchoRequestPacket::new(&mut icmp_buffer)
+                    .ok_or(TracerouteError::PacketConstruction)?;
+
+            icmp_packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
+            icmp_packet.set_icmp_code(icmp::echo_request::IcmpCodes::NoCode);
+            icmp_packet.set_identifier(id.0);
+            icmp_packet.set_sequence_number(sequence_number);
+            icmp_packet.set_payload(&payload);
+
+            let icmp_checksum = checksum(icmp_packet.packet(), 1);
+            icmp_packet.set_checksum(icmp_checksum);
+            Ok(icmp_buffer)
+        }
+        IpAddr::V6(_) => {
+            let icmpv6_len = icmpv6::echo_request::MutableEchoRequestPacket::minimum_packet_size()
+                + payload.len();
+
+            // Construct the ICMPv6 packet
+            let mut icmpv6_buffer = vec![0; icmpv6_len];
+            let mut icmpv6_packet =
+                icmpv6::echo_request::MutableEchoRequestPacket::new(&mut icmpv6_buffer)
+                    .ok_or(TracerouteError::PacketConstruction)?;
+
+            icmpv6_packet.set_icmpv6_type(icmpv6::Icmpv6Types::EchoRequest);
+            icmpv6_packet.set_icmpv6_code(icmpv6::echo_request::Icmpv6Codes::NoCode);
+            icmpv6_packet.set_identifier(id.0);
+            icmpv6_packet.set_sequence_number(sequence_number);
+            icmpv6_packet.set_payload(&payload);
+
+            // ICMPv6 checksum should be calculated in the kernel.
+            Ok(icmpv6_buffer)
+        }
+    }
+}
+
+/// A UDP datagram to a traceroute port. Outside of a flow it goes from `source_port` to a port
+/// that depends on `ttl`. The source address only matters over IPv6, where the checksum
+/// carries the packet ID.
+fn udp_probe(
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    ttl: u8,
+    id: PacketId,
+    flow: Option<FlowId>,
+    source_port: u16,
+    options: &ProbeOptions,
+) -> Result<Vec<u8>, TracerouteError> {
+    // Over IPv6 the packet ID lives in the checksum, tuned with this payload word. Any
+    // padding goes after it.
+    let mut payload = if dst_ip.is_ipv6() { vec![0; 2] } else { vec![] };
+    let udp_len = udp::MutableUdpPacket::minimum_packet_size() + payload.len();
+    payload.extend(options.padding(dst_ip, udp_len));
+    let udp_len = udp::MutableUdpPacket::minimum_packet_size() + payload.len();
+    let (src_port, dst_port) = match flow {
+        Some(flow) => (flow.source_port(), UDP_BASE_PORT),
+        None => (source_port, UDP_BASE_PORT.wrapping_add(ttl as u16)),
+    };
+
+    // Construct the UDP packet
+    let mut udp_buffer = vec![0; udp_len];
+    let mut udp_packet =
+        udp::MutableUdpPacket::new(&mut udp_buffer).ok_or(TracerouteError::PacketConstruction)?;
+
+    udp_packet.set_source(src_port);
+    udp_packet.set_destination(dst_port);
+    udp_packet.set_length(udp_len as u16);
+    udp_packet.set_payload(&payload);
+
+    match (src_ip, dst_ip) {
+        // UDP checksums are optional over IPv4, so leave it as zero.
+        (_, IpAddr::V4(_)) => {}
+        (IpAddr::V6(src_ipv6), IpAddr::V6(dst_ipv6)) => {
+            // The checksum doubles as the packet ID, since the flow label and ports may be
+            // held constant. Work out the payload word that makes the checksum equal it.
+            let zero_payload_checksum =
+                udp::ipv6_checksum(&udp_packet.to_immutable(), &src_ipv6, &dst_ipv6);
+            let balancing_word = ones_complement_add(!id.0, zero_payload_checksum);
+            payload[..2].copy_from_slice(&balancing_word.to_be_bytes());
+            udp_packet.set_payload(&payload);
+            let udp_checksum = udp::ipv6_checksum(&udp_packet.to_immutable(), &src_ipv6, &dst_ipv6);
+            udp_packet.set_checksum(udp_checksum);
+        }
+        _ => return Err(TracerouteError::PacketConstruction),
+    }
+    Ok(udp_buffer)
+}
+
+/// A bare SYN from `src_port` carrying the packet ID in its sequence number.
+fn tcp_syn(
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    src_port: u16,
+    id: PacketId,
+    options: &ProbeOptions,
+) -> Result<Vec<u8>, TracerouteError> {
+    let header_len = tcp::MutableTcpPacket::minimum_packet_size();
+    let padding = options.padding(dst_ip, header_len);
+    let tcp_len = header_len + padding.len();
+
+    // Construct the TCP packet
+    let mut tcp_buffer = vec![0; tcp_len];
+    let mut tcp_packet =
+        tcp::MutableTcpPacket::new(&mut tcp_buffer).ok_or(TracerouteError::PacketConstruction)?;
+
+    tcp_packet.set_source(src_port);
+    tcp_packet.set_destination(dst_port);
+    tcp_packet.set_sequence(tcp_sequence_for_id(id));
+    tcp_packet.set_data_offset((header_len / 4) as u8);
+    tcp_packet.set_flags(TcpFlags::SYN);
+    tcp_packet.set_window(u16::MAX);
+    tcp_packet.set_payload(&padding);
+
+    let tcp_checksum = match (src_ip, dst_ip) {
+        (IpAddr::V4(src_ipv4), IpAddr::V4(dst_ipv4)) => {
+            tcp::ipv4_checksum(&tcp_packet.to_immutable(), &src_ipv4, &dst_ipv4)
+        }
+        (IpAddr::V6(src_ipv6), IpAddr::V6(dst_ipv6)) => {
+            tcp::ipv6_checksum(&tcp_packet.to_immutable(), &src_ipv6, &dst_ipv6)
+        }
+        _ => return Err(TracerouteError::PacketConstruction),
+    };
+    tcp_packet.set_checksum(tcp_checksum);
+    Ok(tcp_buffer)
+}
+
+/// An IPv4 packet around a probe, carrying `id` in its identification field.
+fn ipv4_probe(
+    src_ipv4: Ipv4Addr,
+    dst_ipv4: Ipv4Addr,
+    ttl: u8,
+    id: PacketId,
+    tos: u8,
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> Result<Vec<u8>, TracerouteError> {
+    let ip_len = ipv4::MutableIpv4Packet::minimum_packet_size() + payload.len();
+    let ip_header_len = ipv4::MutableIpv4Packet::minimum_packet_size() / 4;
+
+    // Construct the IP packet
+    let mut ip_buffer = vec![0; ip_len];
+    let mut ip_packet =
+        ipv4::MutableIpv4Packet::new(&mut ip_buffer).ok_or(TracerouteError::PacketConstruction)?;
+
+    ip_packet.set_version(4);
+    ip_packet.set_header_length(ip_header_len as u8);
+    ip_packet.set_dscp(tos >> 2);
+    ip_packet.set_ecn(tos & 0x3);
+    ip_packet.set_total_length(ip_len as u16);
+    ip_packet.set_identification(id.0);
+    ip_packet.set_flags(ipv4::Ipv4Flags::DontFragment);
+    ip_packet.set_ttl(ttl);
+    ip_packet.set_next_level_protocol(protocol);
+    ip_packet.set_source(src_ipv4);
+    ip_packet.set_destination(dst_ipv4);
+    ip_packet.set_payload(payload);
+    Ok(ip_buffer)
+}
+
+/// An IPv6 packet around a probe.
+fn ipv6_probe(
+    src_ipv6: Ipv6Addr,
+    dst_ipv6: Ipv6Addr,
+    ttl: u8,
+    traffic_class: u8,
+    flow_label: u32,
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> Result<Vec<u8>, TracerouteError> {
+    let ipv6_len = ipv6::MutableIpv6Packet::minimum_packet_size() + payload.len();
+
+    // Construct the IP packet
+    let mut ipv6_buffer = vec![0; ipv6_len];
+    let mut ipv6_packet = ipv6::MutableIpv6Packet::new(&mut ipv6_buffer)
+        .ok_or(TracerouteError::PacketConstruction)?;
+
+    ipv6_packet.set_version(6);
+    ipv6_packet.set_traffic_class(traffic_class);
+    ipv6_packet.set_flow_label(flow_label);
+    ipv6_packet.set_payload_length(payload.len() as u16);
+    ipv6_packet.set_next_header(protocol);
+    ipv6_packet.set_hop_limit(ttl);
+    ipv6_packet.set_source(src_ipv6);
+    ipv6_packet.set_destination(dst_ipv6);
+    ipv6_packet.set_payload(payload);
+    Ok(ipv6_buffer)
+}
+
+/// TCP replies come from the destination, so only ones from a probe's destination back to the
+/// port it was sent from count. Anything else gets matched by its quoted probe.
+fn is_reply_to_probe(
+    tcp_probes: &HashMap<PacketId, (SocketAddr, u16)>,
+    result: &TracerouteResult,
+) -> bool {
+    match result {
+        TracerouteResult::TcpReply { id, .. } => {
+            tcp_probes
+                .get(id)
+                .is_some_and(|&(destination, source_port)| {
+                    result.is_tcp_reply_to(destination, source_port)
+                })
+        }
+        _ => true,
+    }
+}
+
+/// Parse a frame read off the interface into a reply to one of our probes. Frames that aren't
+/// replies at all are `Ok(None)`, and ones that look like replies but are cut short or otherwise
+/// broken are errors.
+///
+/// This only looks at the bytes given, so it's what to use for replies captured some other way.
+/// It must never panic, whatever the input: frames come straight from the internet. The
+/// `decode_reply` fuzz target in `ktr_lib/fuzz` checks that.
+pub fn decode_reply(
+    link_type: LinkType,
+    frame: &[u8],
+) -> Result<Option<TracerouteResult>, DecodeError> {
+    let Some((ethertype, packet)) = link_type.network_packet(frame) else {
+        return Ok(None);
+    };
+
+    match ethertype {
+        EtherTypes::Ipv4 => decode_ipv4_reply(packet),
+        EtherTypes::Ipv6 => decode_ipv6_reply(packet),
+        _ => Ok(None),
+    }
+}
+
+/// TTL or hop limit of a frame that `decode_reply` made sense of.
+pub(crate) fn reply_ttl(link_type: LinkType, frame: &[u8]) -> Option<u8> {
+    let (ethertype, packet) = link_type.network_packet(frame)?;
+    match ethertype {
+        EtherTypes::Ipv4 => ipv4::Ipv4Packet::new(packet).map(|packet| packet.get_ttl()),
+        EtherTypes::Ipv6 => ipv6::Ipv6Packet::new(packet).map(|packet| packet.get_hop_limit()),
+        _ => None,
+    }
+}
+
+fn decode_ipv4_reply(packet: &[u8]) -> Result<Option<TracerouteResult>, DecodeError> {
+    let packet = ipv4_packet(packet, "IPv4 header")?;
+    let source_ip = IpAddr::V4(packet.get_source());
+
+    match packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Icmp => {
+            let packet = icmp::IcmpPacket::new(packet.payload())
+                .ok_or(DecodeError::Truncated("ICMP header"))?;
+            match packet.get_icmp_type() {
+                icmp::IcmpTypes::EchoReply => {
+                    let packet = icmp::echo_reply::EchoReplyPacket::new(packet.packet())
+                        .ok_or(DecodeError::Truncated("ICMP Echo Reply"))?;
+                    Ok(Some(TracerouteResult::IcmpReply(
+                        source_ip,
+                        PacketId(packet.get_identifier()),
+                    )))
+                }
+                icmp::IcmpTypes::TimeExceeded => {
+                    let extensions = IcmpExtensions::from_icmpv4_error(packet.packet());
+                    let packet = icmp::time_exceeded::TimeExceededPacket::new(packet.packet())
+                        .ok_or(DecodeError::Truncated("ICMP Time Exceeded"))?;
+                    let packet = quoted_ipv4_packet(packet.payload())?;
+
+                    Ok(Some(TracerouteResult::IcmpTimeExceeded(
+                        source_ip,
+                        quoted_ipv4_packet_id(&packet),
+                        extensions,
+                        Some(ProbeHeader::from_ipv4(&packet)),
+                    )))
+                }
+                icmp::IcmpTypes::DestinationUnreachable => {
+                    let extensions = IcmpExtensions::from_icmpv4_error(packet.packet());
+                    let packet = DestinationUnreachablePacket::new(packet.packet())
+                        .ok_or(DecodeError::Truncated("ICMP Destination Unreachable"))?;
+                    let code = packet.get_icmp_code();
+                    // The next-hop MTU is in the low half of the otherwise unused word.
+                    let mtu = quoted_u16(packet.packet(), 6).filter(|&mtu| mtu != 0);
+                    let packet = quoted_ipv4_packet(packet.payload())?;
+
+                    if code == destination_unreachable::IcmpCodes::FragmentationRequiredAndDFFlagSet
+                    {
+                        Ok(Some(TracerouteResult::IcmpPacketTooBig {
+                            ip: source_ip,
+                            id: quoted_ipv4_packet_id(&packet),
+                            dst_ip: IpAddr::V4(packet.get_destination()),
+                            mtu,
+                            extensions,
+                            quoted: Some(ProbeHeader::from_ipv4(&packet)),
+                        }))
+                    } else if code == destination_unreachable::IcmpCodes::DestinationPortUnreachable
+                    {
+                        Ok(Some(TracerouteResult::IcmpPortUnreachable(
+                            source_ip,
+                            quoted_ipv4_packet_id(&packet),
+                            extensions,
+                            Some(ProbeHeader::from_ipv4(&packet)),
+                        )))
+                    } else {
+                        Ok(Some(TracerouteResult::IcmpDestinationUnreachable {
+                            ip: source_ip,
+                            id: quoted_ipv4_packet_id(&packet),
+                            dst_ip: IpAddr::V4(packet.get_destination()),
+                            code: UnreachableCode::from_icmpv4(code.0),
+                            extensions,
+                            quoted: Some(ProbeHeader::from_ipv4(&packet)),
+                        }))
                     }
-                    EtherTypes::Ipv6 => {
-                        let packet = ipv6::Ipv6Packet::new(packet.payload()).unwrap();
-                        let source_ip = packet.get_source();
-                        if packet.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
-                            let packet = icmpv6::Icmpv6Packet::new(packet.payload())?;
-                            match packet.get_icmpv6_type() {
-                                icmpv6::Icmpv6Types::EchoReply => {
-                                    let packet =
-                                        icmpv6::echo_reply::EchoReplyPacket::new(packet.packet())?;
-                                    Some(TracerouteResult::IcmpReply(
-                                        IpAddr::V6(source_ip),
-                                        PacketId(packet.get_identifier()),
-                                    ))
-                                }
-                                icmpv6::Icmpv6Types::TimeExceeded => {
-                                    let packet = icmpv6::time_exceeded::TimeExceededPacket::new(
-                                        packet.packet(),
-                                    )?;
-                                    let packet = ipv6::Ipv6Packet::new(packet.payload())?;
-
-                                    Some(TracerouteResult::IcmpTimeExceeded(
-                                        IpAddr::V6(source_ip),
-                                        PacketId(packet.get_flow_label() as u16),
-                                    ))
-                                }
-                                icmpv6::Icmpv6Types::DestinationUnreachable => {
-                                    Some(TracerouteResult::IcmpDestinationUnreachable(IpAddr::V6(
-                                        source_ip,
-                                    )))
-                                }
-                                _ => None,
-                            }
-                        } else {
-                            None
-                        }
+                }
+                _ => Ok(None),
+            }
+        }
+        IpNextHeaderProtocols::Tcp => decode_tcp_reply(source_ip, packet.payload()),
+        _ => Ok(None),
+    }
+}
+
+fn decode_ipv6_reply(packet: &[u8]) -> Result<Option<TracerouteResult>, DecodeError> {
+    let packet = ipv6::Ipv6Packet::new(packet).ok_or(DecodeError::Truncated("IPv6 header"))?;
+    let source_ip = IpAddr::V6(packet.get_source());
+    let (protocol, transport) = ipv6_transport(&packet)?;
+
+    match protocol {
+        IpNextHeaderProtocols::Icmpv6 => {
+            let packet = icmpv6::Icmpv6Packet::new(transport)
+                .ok_or(DecodeError::Truncated("ICMPv6 header"))?;
+            match packet.get_icmpv6_type() {
+                icmpv6::Icmpv6Types::EchoReply => {
+                    let packet = icmpv6::echo_reply::EchoReplyPacket::new(packet.packet())
+                        .ok_or(DecodeError::Truncated("ICMPv6 Echo Reply"))?;
+                    Ok(Some(TracerouteResult::IcmpReply(
+                        source_ip,
+                        PacketId(packet.get_identifier()),
+                    )))
+                }
+                icmpv6::Icmpv6Types::TimeExceeded => {
+                    let extensions = IcmpExtensions::from_icmpv6_error(packet.packet());
+                    let packet = icmpv6::time_exceeded::TimeExceededPacket::new(packet.packet())
+                        .ok_or(DecodeError::Truncated("ICMPv6 Time Exceeded"))?;
+                    let packet = quoted_ipv6_packet(packet.payload())?;
+
+                    Ok(Some(TracerouteResult::IcmpTimeExceeded(
+                        source_ip,
+                        quoted_ipv6_packet_id(&packet)?,
+                        extensions,
+                        Some(ProbeHeader::from_ipv6(&packet)?),
+                    )))
+                }
+                icmpv6::Icmpv6Types::DestinationUnreachable => {
+                    let extensions = IcmpExtensions::from_icmpv6_error(packet.packet());
+                    let code = UnreachableCode::from_icmpv6(packet.get_icmpv6_code().0);
+                    // The payload starts with 4 unused bytes before the quoted packet.
+                    let quoted = packet.payload().get(4..).unwrap_or_default();
+                    let packet = quoted_ipv6_packet(quoted)?;
+
+                    if code == UnreachableCode::Port {
+                        Ok(Some(TracerouteResult::IcmpPortUnreachable(
+                            source_ip,
+                            quoted_ipv6_packet_id(&packet)?,
+                            extensions,
+                            Some(ProbeHeader::from_ipv6(&packet)?),
+                        )))
+                    } else {
+                        Ok(Some(TracerouteResult::IcmpDestinationUnreachable {
+                            ip: source_ip,
+                            id: quoted_ipv6_packet_id(&packet)?,
+                            dst_ip: IpAddr::V6(packet.get_destination()),
+                            code,
+                            extensions,
+                            quoted: Some(ProbeHeader::from_ipv6(&packet)?),
+                        }))
                     }
-                    _ => None,
                 }
-            })()),
-            Err(error) if error.kind() == ErrorKind::TimedOut => Ok(None),
-            Err(error) => Err(TracerouteError::RxChannelIo(error)),
+                icmpv6::Icmpv6Types::PacketTooBig => {
+                    // The MTU takes up the 4 bytes after the checksum, then the quoted packet.
+                    let mtu = packet
+                        .payload()
+                        .get(..4)
+                        .ok_or(DecodeError::Truncated("ICMPv6 Packet Too Big"))?;
+                    let mtu = u32::from_be_bytes([mtu[0], mtu[1], mtu[2], mtu[3]]);
+                    let packet = quoted_ipv6_packet(&packet.payload()[4..])?;
+
+                    Ok(Some(TracerouteResult::IcmpPacketTooBig {
+                        ip: source_ip,
+                        id: quoted_ipv6_packet_id(&packet)?,
+                        dst_ip: IpAddr::V6(packet.get_destination()),
+                        mtu: Some(saturating_u16(mtu)).filter(|&mtu| mtu != 0),
+                        extensions: IcmpExtensions::default(),
+                        quoted: Some(ProbeHeader::from_ipv6(&packet)?),
+                    }))
+                }
+                _ => Ok(None),
+            }
+        }
+        IpNextHeaderProtocols::Tcp => decode_tcp_reply(source_ip, transport),
+        _ => Ok(None),
+    }
+}
+
+fn decode_tcp_reply(
+    source_ip: IpAddr,
+    segment: &[u8],
+) -> Result<Option<TracerouteResult>, DecodeError> {
+    let packet = tcp::TcpPacket::new(segment).ok_or(DecodeError::Truncated("TCP header"))?;
+    Ok(tcp_reply_id(&packet).map(|id| TracerouteResult::TcpReply {
+        ip: source_ip,
+        id,
+        source_port: packet.get_source(),
+        destination_port: packet.get_destination(),
+    }))
+}
+
+fn quoted_ipv4_packet(quoted: &[u8]) -> Result<ipv4::Ipv4Packet<'_>, DecodeError> {
+    ipv4_packet(quoted, "quoted IPv4 header")
+}
+
+/// An IPv4 packet whose header length makes sense, since the payload is found with it.
+fn ipv4_packet<'a>(
+    bytes: &'a [u8],
+    what: &'static str,
+) -> Result<ipv4::Ipv4Packet<'a>, DecodeError> {
+    let packet = ipv4::Ipv4Packet::new(bytes).ok_or(DecodeError::Truncated(what))?;
+    if packet.get_header_length() < 5 {
+        return Err(DecodeError::Invalid(what));
+    }
+    Ok(packet)
+}
+
+fn quoted_ipv6_packet(quoted: &[u8]) -> Result<ipv6::Ipv6Packet<'_>, DecodeError> {
+    ipv6::Ipv6Packet::new(quoted).ok_or(DecodeError::Truncated("quoted IPv6 header"))
+}
+
+/// Follow an IPv6 packet's extension headers to whatever comes after them, returning its
+/// protocol and bytes. Fragments after the first don't start with a header of their own, so
+/// those come back as the Fragment header's payload.
+fn ipv6_transport<'a>(
+    packet: &'a ipv6::Ipv6Packet,
+) -> Result<(IpNextHeaderProtocol, &'a [u8]), DecodeError> {
+    let mut protocol = packet.get_next_header();
+    let mut rest = packet.payload();
+
+    loop {
+        let length = match protocol {
+            // Lengths are in 8 byte units, not counting the first 8.
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => {
+                let units = rest
+                    .get(1)
+                    .ok_or(DecodeError::Truncated("IPv6 extension header"))?;
+                (*units as usize + 1) * 8
+            }
+            // Except this one, which is in 4 byte units, not counting the first 8.
+            IpNextHeaderProtocols::Ah => {
+                let units = rest
+                    .get(1)
+                    .ok_or(DecodeError::Truncated("IPv6 extension header"))?;
+                (*units as usize + 2) * 4
+            }
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let offset =
+                    quoted_u16(rest, 2).ok_or(DecodeError::Truncated("IPv6 Fragment header"))? >> 3;
+                if offset != 0 {
+                    return Ok((protocol, rest.get(8..).unwrap_or_default()));
+                }
+                8
+            }
+            _ => return Ok((protocol, rest)),
+        };
+        let header = rest
+            .get(..length)
+            .ok_or(DecodeError::Truncated("IPv6 extension header"))?;
+        protocol = IpNextHeaderProtocol(header[0]);
+        rest = &rest[length..];
+    }
+}
+
+/// One of our probes found in a capture, described by what `send_probe` was called with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DecodedProbe {
+    pub protocol: ProbeProtocol,
+    pub dst_ip: IpAddr,
+    pub ttl: u8,
+    pub id: PacketId,
+    /// Total length of the IP packet.
+    pub size: u16,
+    pub header: ProbeHeader,
+}
+
+/// Recognize a probe like the ones we send: an Echo Request, a UDP datagram to a traceroute
+/// port, or a bare SYN. The ID is read from the same fields routers quote back.
+pub(crate) fn decode_probe(link_type: LinkType, frame: &[u8]) -> Option<DecodedProbe> {
+    let (ethertype, packet) = link_type.network_packet(frame)?;
+    match ethertype {
+        EtherTypes::Ipv4 => {
+            let packet = ipv4::Ipv4Packet::new(packet)?;
+            Some(DecodedProbe {
+                protocol: probe_protocol(packet.get_next_level_protocol(), packet.payload())?,
+                dst_ip: IpAddr::V4(packet.get_destination()),
+                ttl: packet.get_ttl(),
+                id: quoted_ipv4_packet_id(&packet),
+                size: packet.get_total_length(),
+                header: ProbeHeader::from_ipv4(&packet),
+            })
+        }
+        EtherTypes::Ipv6 => {
+            let packet = ipv6::Ipv6Packet::new(packet)?;
+            let (protocol, transport) = ipv6_transport(&packet).ok()?;
+            Some(DecodedProbe {
+                protocol: probe_protocol(protocol, transport)?,
+                dst_ip: IpAddr::V6(packet.get_destination()),
+                ttl: packet.get_hop_limit(),
+                id: quoted_ipv6_packet_id(&packet).ok()?,
+                size: packet
+                    .get_payload_length()
+                    .saturating_add(ipv6::Ipv6Packet::minimum_packet_size() as u16),
+                header: ProbeHeader::from_ipv6(&packet).ok()?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Which kind of probe a transport packet is, if it looks like one of ours.
+fn probe_protocol(protocol: IpNextHeaderProtocol, transport: &[u8]) -> Option<ProbeProtocol> {
+    match protocol {
+        // Echo Request has type 8 in ICMP and 128 in ICMPv6.
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
+            matches!(transport.first()?, 8 | 128).then_some(ProbeProtocol::Icmp)
+        }
+        IpNextHeaderProtocols::Udp => {
+            let port = quoted_u16(transport, 2)?;
+            (UDP_BASE_PORT..=UDP_BASE_PORT + u8::MAX as u16)
+                .contains(&port)
+                .then_some(ProbeProtocol::Udp)
         }
+        IpNextHeaderProtocols::Tcp => {
+            let packet = tcp::TcpPacket::new(transport)?;
+            (packet.get_flags() == TcpFlags::SYN).then_some(ProbeProtocol::Tcp {
+                port: packet.get_destination(),
+            })
+        }
+        _ => None,
     }
 }
 
@@ -279,3 +2030,460 @@ pub fn interface_from_name(name: &str) -> Option<NetworkInterface> {
         .into_iter()
         .find(|iface: &NetworkInterface| iface.name == name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_icmpv4_unreachable_codes() {
+        let codes: Vec<_> = (0..=16).map(UnreachableCode::from_icmpv4).collect();
+        assert_eq!(
+            codes,
+            vec![
+                UnreachableCode::Network,
+                UnreachableCode::Host,
+                UnreachableCode::Protocol,
+                UnreachableCode::Port,
+                UnreachableCode::FragmentationNeeded,
+                UnreachableCode::SourceRouteFailed,
+                UnreachableCode::Network,
+                UnreachableCode::Host,
+                UnreachableCode::Host,
+                UnreachableCode::AdminProhibited,
+                UnreachableCode::AdminProhibited,
+                UnreachableCode::Network,
+                UnreachableCode::Host,
+                UnreachableCode::AdminProhibited,
+                UnreachableCode::HostPrecedenceViolation,
+                UnreachableCode::PrecedenceCutoff,
+                UnreachableCode::Other(16),
+            ]
+        );
+        assert_eq!(UnreachableCode::from_icmpv4(9).flag(), "!X");
+        assert_eq!(UnreachableCode::from_icmpv4(10).flag(), "!X");
+    }
+
+    #[test]
+    fn maps_icmpv6_unreachable_codes() {
+        let codes: Vec<_> = (0..=7).map(UnreachableCode::from_icmpv6).collect();
+        assert_eq!(
+            codes,
+            vec![
+                UnreachableCode::Network,
+                UnreachableCode::AdminProhibited,
+                UnreachableCode::BeyondScope,
+                UnreachableCode::Host,
+                UnreachableCode::Port,
+                UnreachableCode::SourcePolicyFailed,
+                UnreachableCode::RejectRoute,
+                UnreachableCode::Other(7),
+            ]
+        );
+    }
+
+    fn interface(ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: "eth0".to_string(),
+            description: String::new(),
+            index: 2,
+            mac: None,
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn picks_source_addresses_on_the_interface() {
+        let interface = interface(&[
+            "192.0.2.10/24",
+            "192.0.2.20/24",
+            "fe80::1/64",
+            "2001:db8::10/64",
+        ]);
+
+        // Link-local addresses don't count for the default.
+        assert_eq!(
+            interface_source_ips(&interface, SourceAddresses::default()).unwrap(),
+            (
+                Some("192.0.2.10".parse().unwrap()),
+                Some("2001:db8::10".parse().unwrap())
+            )
+        );
+
+        let requested = SourceAddresses {
+            ipv4: Some("192.0.2.20".parse().unwrap()),
+            ipv6: None,
+        };
+        assert_eq!(
+            interface_source_ips(&interface, requested).unwrap(),
+            (
+                Some("192.0.2.20".parse().unwrap()),
+                Some("2001:db8::10".parse().unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_source_addresses_off_the_interface() {
+        let interface = interface(&["192.0.2.10/24", "2001:db8::10/64"]);
+        let off_interface: Ipv6Addr = "2001:db8::99".parse().unwrap();
+        let requested = SourceAddresses {
+            ipv4: None,
+            ipv6: Some(off_interface),
+        };
+        assert!(matches!(
+            interface_source_ips(&interface, requested),
+            Err(TracerouteError::SourceAddressNotOnInterface(ip)) if ip == IpAddr::V6(off_interface)
+        ));
+    }
+
+    #[test]
+    fn ipv6_only_interface_has_no_ipv4_source() {
+        let interface = interface(&["fe80::1/64", "2001:db8::10/64"]);
+
+        // Probes to IPv4 destinations then fail with `NoSourceAddress` instead of going out.
+        assert_eq!(
+            interface_source_ips(&interface, SourceAddresses::default()).unwrap(),
+            (None, Some("2001:db8::10".parse().unwrap()))
+        );
+
+        let requested = SourceAddresses {
+            ipv4: Some("192.0.2.10".parse().unwrap()),
+            ipv6: None,
+        };
+        assert!(matches!(
+            interface_source_ips(&interface, requested),
+            Err(TracerouteError::SourceAddressNotOnInterface(_))
+        ));
+    }
+
+    const SOURCE_IPV4: &str = "192.0.2.10";
+    const SOURCE_IPV6: &str = "2001:db8::10";
+    const PARIS_FLOW: FlowId = FlowId(0x1234);
+
+    /// The IP packet `RawChannel` would send for a probe, from `src_ip` at TTL 5.
+    fn probe_packet(
+        protocol: ProbeProtocol,
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        id: PacketId,
+        flow: Option<FlowId>,
+    ) -> Vec<u8> {
+        let options = ProbeOptions::default();
+        let ttl = 5;
+        let source_port = 0x8000;
+        let (transport, next_header) = match protocol {
+            ProbeProtocol::Icmp => {
+                // A sequence number that changes with the ID, to show it's ignored in a flow.
+                let echo = echo_request(dst_ip, id, id.0, flow, &options);
+                let next_header = match dst_ip {
+                    IpAddr::V4(_) => IpNextHeaderProtocols::Icmp,
+                    IpAddr::V6(_) => IpNextHeaderProtocols::Icmpv6,
+                };
+                (echo, next_header)
+            }
+            ProbeProtocol::Udp => (
+                udp_probe(src_ip, dst_ip, ttl, id, flow, source_port, &options),
+                IpNextHeaderProtocols::Udp,
+            ),
+            ProbeProtocol::Tcp { port } => (
+                tcp_syn(
+                    src_ip,
+                    dst_ip,
+                    port,
+                    flow.map_or(source_port, FlowId::source_port),
+                    id,
+                    &options,
+                ),
+                IpNextHeaderProtocols::Tcp,
+            ),
+        };
+        let transport = transport.unwrap();
+        match (src_ip, dst_ip) {
+            (IpAddr::V4(src_ipv4), IpAddr::V4(dst_ipv4)) => {
+                ipv4_probe(src_ipv4, dst_ipv4, ttl, id, 0, next_header, &transport)
+            }
+            (IpAddr::V6(src_ipv6), IpAddr::V6(dst_ipv6)) => ipv6_probe(
+                src_ipv6,
+                dst_ipv6,
+                ttl,
+                0,
+                options.flow_label(id, flow),
+                next_header,
+                &transport,
+            ),
+            _ => panic!("mixed address families"),
+        }
+        .unwrap()
+    }
+
+    /// Two probes in `PARIS_FLOW` with different IDs, checking each decodes to its own ID.
+    fn paris_probes(protocol: ProbeProtocol, src_ip: &str, dst_ip: &str) -> [Vec<u8>; 2] {
+        let (src_ip, dst_ip) = (src_ip.parse().unwrap(), dst_ip.parse().unwrap());
+        [PacketId(1), PacketId(0xbeef)].map(|id| {
+            let packet = probe_packet(protocol, src_ip, dst_ip, id, Some(PARIS_FLOW));
+            let decoded = decode_probe(LinkType::RawIp, &packet).unwrap();
+            assert_eq!(decoded.id, id);
+            assert_eq!(decoded.protocol, protocol);
+            packet
+        })
+    }
+
+    fn transport_bytes(packet: &[u8]) -> &[u8] {
+        match packet[0] >> 4 {
+            4 => &packet[ipv4::Ipv4Packet::minimum_packet_size()..],
+            _ => &packet[ipv6::Ipv6Packet::minimum_packet_size()..],
+        }
+    }
+
+    fn flow_label(packet: &[u8]) -> u32 {
+        ipv6::Ipv6Packet::new(packet).unwrap().get_flow_label()
+    }
+
+    #[test]
+    fn paris_icmpv4_probes_share_a_checksum() {
+        let [first, second] = paris_probes(ProbeProtocol::Icmp, SOURCE_IPV4, "198.51.100.1");
+        assert_eq!(
+            transport_bytes(&first)[2..4],
+            transport_bytes(&second)[2..4]
+        );
+        assert_ne!(
+            transport_bytes(&first)[4..6],
+            transport_bytes(&second)[4..6]
+        );
+    }
+
+    #[test]
+    fn paris_icmpv6_probes_share_a_checksum_and_flow_label() {
+        let [first, second] = paris_probes(ProbeProtocol::Icmp, SOURCE_IPV6, "2001:db8::1");
+        assert_eq!(flow_label(&first), flow_label(&second));
+
+        // The kernel fills in ICMPv6 checksums, so work out what it would come to.
+        let kernel_checksum = |packet: &[u8]| {
+            let packet = ipv6::Ipv6Packet::new(packet).unwrap();
+            let icmpv6_packet = icmpv6::Icmpv6Packet::new(packet.payload()).unwrap();
+            icmpv6::checksum(
+                &icmpv6_packet,
+                &packet.get_source(),
+                &packet.get_destination(),
+            )
+        };
+        assert_eq!(kernel_checksum(&first), kernel_checksum(&second));
+    }
+
+    #[test]
+    fn paris_udp_ipv4_probes_share_ports_and_checksum() {
+        let [first, second] = paris_probes(ProbeProtocol::Udp, SOURCE_IPV4, "198.51.100.1");
+        assert_eq!(transport_bytes(&first)[..4], transport_bytes(&second)[..4]);
+        assert_eq!(
+            transport_bytes(&first)[6..8],
+            transport_bytes(&second)[6..8]
+        );
+    }
+
+    #[test]
+    fn paris_udp_ipv6_probes_share_ports_and_flow_label() {
+        let [first, second] = paris_probes(ProbeProtocol::Udp, SOURCE_IPV6, "2001:db8::1");
+        assert_eq!(flow_label(&first), flow_label(&second));
+        assert_eq!(transport_bytes(&first)[..4], transport_bytes(&second)[..4]);
+        // The checksum is where the ID goes, since the ports and flow label can't change.
+        assert_ne!(
+            transport_bytes(&first)[6..8],
+            transport_bytes(&second)[6..8]
+        );
+    }
+
+    /// An IP packet from `src_ip` that isn't a probe, like a reply or an ICMP error.
+    fn reply_packet(
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        protocol: IpNextHeaderProtocol,
+        transport: &[u8],
+    ) -> Vec<u8> {
+        match (src_ip, dst_ip) {
+            (IpAddr::V4(src_ipv4), IpAddr::V4(dst_ipv4)) => {
+                ipv4_probe(src_ipv4, dst_ipv4, 64, PacketId(0), 0, protocol, transport)
+            }
+            (IpAddr::V6(src_ipv6), IpAddr::V6(dst_ipv6)) => {
+                ipv6_probe(src_ipv6, dst_ipv6, 64, 0, 0, protocol, transport)
+            }
+            _ => panic!("mixed address families"),
+        }
+        .unwrap()
+    }
+
+    /// A bare TCP segment from the destination's port 443 to `dst_port`.
+    fn tcp_segment(
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        dst_port: u16,
+        flags: u8,
+        acknowledgement: u32,
+    ) -> Vec<u8> {
+        let mut buffer = vec![0; tcp::TcpPacket::minimum_packet_size()];
+        let mut segment = tcp::MutableTcpPacket::new(&mut buffer).unwrap();
+        segment.set_source(443);
+        segment.set_destination(dst_port);
+        segment.set_acknowledgement(acknowledgement);
+        segment.set_data_offset(5);
+        segment.set_flags(flags);
+        reply_packet(src_ip, dst_ip, IpNextHeaderProtocols::Tcp, &buffer)
+    }
+
+    const TCP_ENDPOINTS: [(&str, &str); 2] =
+        [(SOURCE_IPV4, "198.51.100.1"), (SOURCE_IPV6, "2001:db8::1")];
+
+    #[test]
+    fn tcp_syn_probes_carry_the_id_in_the_sequence_number() {
+        for (src_ip, dst_ip) in TCP_ENDPOINTS {
+            let (src_ip, dst_ip) = (src_ip.parse().unwrap(), dst_ip.parse().unwrap());
+            let protocol = ProbeProtocol::Tcp { port: 443 };
+            let packet = probe_packet(protocol, src_ip, dst_ip, PacketId(0xbeef), None);
+
+            let segment = tcp::TcpPacket::new(transport_bytes(&packet)).unwrap();
+            assert_eq!(segment.get_flags(), TcpFlags::SYN);
+            assert_eq!(segment.get_sequence(), 0xbeef);
+            assert_eq!(segment.get_source(), 0x8000);
+            assert_eq!(segment.get_destination(), 443);
+            let expected_checksum = match (src_ip, dst_ip) {
+                (IpAddr::V4(src_ipv4), IpAddr::V4(dst_ipv4)) => {
+                    tcp::ipv4_checksum(&segment, &src_ipv4, &dst_ipv4)
+                }
+                (IpAddr::V6(src_ipv6), IpAddr::V6(dst_ipv6)) => {
+                    tcp::ipv6_checksum(&segment, &src_ipv6, &dst_ipv6)
+                }
+                _ => unreachable!(),
+            };
+            assert_eq!(segment.get_checksum(), expected_checksum);
+
+            let decoded = decode_probe(LinkType::RawIp, &packet).unwrap();
+            assert_eq!(decoded.protocol, protocol);
+            assert_eq!(decoded.id, PacketId(0xbeef));
+        }
+    }
+
+    #[test]
+    fn matches_syn_ack_and_rst_by_acknowledgement() {
+        for (src_ip, dst_ip) in TCP_ENDPOINTS {
+            let (src_ip, dst_ip): (IpAddr, IpAddr) =
+                (src_ip.parse().unwrap(), dst_ip.parse().unwrap());
+            for flags in [TcpFlags::SYN | TcpFlags::ACK, TcpFlags::RST | TcpFlags::ACK] {
+                // The sequence number for ID 0xffff acknowledges as 0x10000.
+                let reply = tcp_segment(dst_ip, src_ip, 0x8000, flags, 0x10000);
+                let result = decode_reply(LinkType::RawIp, &reply).unwrap();
+                assert!(
+                    matches!(
+                        result,
+                        Some(TracerouteResult::TcpReply {
+                            ip,
+                            id: PacketId(0xffff),
+                            source_port: 443,
+                            destination_port: 0x8000,
+                        }) if ip == dst_ip
+                    ),
+                    "{result:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_tcp_segments_that_are_not_replies() {
+        let (src_ip, dst_ip) = (
+            SOURCE_IPV4.parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+        );
+        for (dst_port, flags) in [
+            // Someone else's SYN, and resets that don't acknowledge anything.
+            (0x8000, TcpFlags::SYN),
+            (0x8000, TcpFlags::RST),
+            // Not to one of our ports.
+            (0x1000, TcpFlags::SYN | TcpFlags::ACK),
+        ] {
+            let reply = tcp_segment(dst_ip, src_ip, dst_port, flags, 1);
+            assert!(decode_reply(LinkType::RawIp, &reply).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn matches_quoted_tcp_probes_in_time_exceeded() {
+        let router: IpAddr = "192.0.2.1".parse().unwrap();
+        let src_ip = SOURCE_IPV4.parse().unwrap();
+        let probe = probe_packet(
+            ProbeProtocol::Tcp { port: 443 },
+            src_ip,
+            "198.51.100.1".parse().unwrap(),
+            PacketId(0xbeef),
+            None,
+        );
+        // A middlebox rewrote the identification, and the router only quoted 8 bytes of TCP.
+        let mut quoted = probe[..28].to_vec();
+        quoted[4..6].copy_from_slice(&[0, 0]);
+        let mut icmp_buffer = [vec![11, 0, 0, 0, 0, 0, 0, 0], quoted].concat();
+        let icmp_checksum = checksum(&icmp_buffer, 1);
+        icmp_buffer[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+        let reply = reply_packet(router, src_ip, IpNextHeaderProtocols::Icmp, &icmp_buffer);
+        assert!(matches!(
+            decode_reply(LinkType::RawIp, &reply).unwrap(),
+            Some(TracerouteResult::IcmpTimeExceeded(ip, PacketId(0xbeef), ..)) if ip == router
+        ));
+
+        let router: IpAddr = "2001:db8::ff".parse().unwrap();
+        let src_ip = SOURCE_IPV6.parse().unwrap();
+        // In a flow, so the flow label doesn't give the ID away.
+        let probe = probe_packet(
+            ProbeProtocol::Tcp { port: 443 },
+            src_ip,
+            "2001:db8::1".parse().unwrap(),
+            PacketId(0xbeef),
+            Some(PARIS_FLOW),
+        );
+        let icmpv6_buffer = [vec![3, 0, 0, 0, 0, 0, 0, 0], probe[..48].to_vec()].concat();
+        let reply = reply_packet(
+            router,
+            src_ip,
+            IpNextHeaderProtocols::Icmpv6,
+            &icmpv6_buffer,
+        );
+        assert!(matches!(
+            decode_reply(LinkType::RawIp, &reply).unwrap(),
+            Some(TracerouteResult::IcmpTimeExceeded(ip, PacketId(0xbeef), ..)) if ip == router
+        ));
+    }
+
+    #[test]
+    fn tcp_replies_only_match_their_probe() {
+        let destination: IpAddr = "198.51.100.1".parse().unwrap();
+        let tcp_probes =
+            HashMap::from([(PacketId(7), (SocketAddr::new(destination, 443), 0x8000))]);
+        let reply = |ip: &str, id, source_port, destination_port| TracerouteResult::TcpReply {
+            ip: ip.parse().unwrap(),
+            id: PacketId(id),
+            source_port,
+            destination_port,
+        };
+
+        assert!(is_reply_to_probe(
+            &tcp_probes,
+            &reply("198.51.100.1", 7, 443, 0x8000)
+        ));
+        // From another host, from another port, to another port, or for a probe we didn't send.
+        assert!(!is_reply_to_probe(
+            &tcp_probes,
+            &reply("198.51.100.2", 7, 443, 0x8000)
+        ));
+        assert!(!is_reply_to_probe(
+            &tcp_probes,
+            &reply("198.51.100.1", 7, 80, 0x8000)
+        ));
+        assert!(!is_reply_to_probe(
+            &tcp_probes,
+            &reply("198.51.100.1", 7, 443, 0x8001)
+        ));
+        assert!(!is_reply_to_probe(
+            &tcp_probes,
+            &reply("198.51.100.1", 8, 443, 0x8000)
+        ));
+    }
+}
@@ -0,0 +1,450 @@
+//! Our own `AF_PACKET` receive socket, so that a classic BPF filter can be attached before any
+//! frames are queued. Everything that isn't a possible reply is dropped in the kernel, which
+//! matters on busy hosts where the interface sees far more traffic than we could parse.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use pnet::datalink::NetworkInterface;
+
+use super::unprivileged::{received_at, SO_TIMESTAMPNS};
+use super::ReceiveStats;
+
+// Not in every version of libc, but the same on every architecture.
+const SO_ATTACH_FILTER: libc::c_int = 26;
+const PACKET_STATISTICS: libc::c_int = 6;
+
+/// `struct tpacket_stats` from linux/if_packet.h.
+#[repr(C)]
+#[derive(Default)]
+struct TpacketStats {
+    packets: libc::c_uint,
+    drops: libc::c_uint,
+}
+
+const BPF_LD_H_ABS: u16 = 0x28;
+const BPF_LD_B_ABS: u16 = 0x30;
+const BPF_LD_B_IND: u16 = 0x50;
+const BPF_LDX_B_MSH: u16 = 0xb1;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JSET_K: u16 = 0x45;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offset the kernel maps onto the start of the network header, whatever the framing.
+const NET: u32 = libc::SKF_NET_OFF as u32;
+/// Offset the kernel maps onto `skb->protocol`, the EtherType.
+const PROTOCOL: u32 = (libc::SKF_AD_OFF + libc::SKF_AD_PROTOCOL) as u32;
+
+const ACCEPT: u32 = u32::MAX;
+const DROP: u32 = 0;
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// Accept ICMP Echo Replies, Destination Unreachables and Time Exceededs, their ICMPv6
+/// equivalents plus Packet Too Big, and TCP segments with RST or SYN-ACK, which covers every
+/// reply `poll` understands. Our own outgoing probes don't match. IPv6 packets with extension
+/// headers are let through since walking the chain here isn't practical.
+///
+/// Jumps count instructions to skip, so the numbered comments are there to check them against.
+/// Accepting from instruction `i` skips `30 - i`, and dropping skips `31 - i`.
+fn reply_filter() -> Vec<libc::sock_filter> {
+    vec![
+        // 0: What's the network protocol?
+        statement(BPF_LD_H_ABS, PROTOCOL),
+        jump(BPF_JEQ_K, 0x0800, 0, 12),
+        // 2: IPv4. X = header length, A = protocol.
+        statement(BPF_LDX_B_MSH, NET),
+        statement(BPF_LD_B_ABS, NET + 9),
+        jump(BPF_JEQ_K, 1, 0, 4),
+        // 5: ICMP type.
+        statement(BPF_LD_B_IND, NET),
+        jump(BPF_JEQ_K, 0, 24, 0),
+        jump(BPF_JEQ_K, 3, 23, 0),
+        jump(BPF_JEQ_K, 11, 22, 23),
+        // 9: TCP flags.
+        jump(BPF_JEQ_K, 6, 0, 22),
+        statement(BPF_LD_B_IND, NET + 13),
+        jump(BPF_JSET_K, 0x04, 19, 0),
+        statement(BPF_ALU_AND_K, 0x12),
+        jump(BPF_JEQ_K, 0x12, 17, 18),
+        // 14: IPv6, going by the next header straight after the fixed header.
+        jump(BPF_JEQ_K, 0x86dd, 0, 17),
+        statement(BPF_LD_B_ABS, NET + 6),
+        jump(BPF_JEQ_K, 58, 0, 5),
+        // 17: ICMPv6 type.
+        statement(BPF_LD_B_ABS, NET + 40),
+        jump(BPF_JEQ_K, 129, 12, 0),
+        jump(BPF_JEQ_K, 1, 11, 0),
+        jump(BPF_JEQ_K, 2, 10, 0),
+        jump(BPF_JEQ_K, 3, 9, 10),
+        // 22: TCP flags.
+        jump(BPF_JEQ_K, 6, 0, 4),
+        statement(BPF_LD_B_ABS, NET + 40 + 13),
+        jump(BPF_JSET_K, 0x04, 6, 0),
+        statement(BPF_ALU_AND_K, 0x12),
+        jump(BPF_JEQ_K, 0x12, 4, 5),
+        // 27: Hop-by-Hop, Routing, Fragment, or Destination Options.
+        jump(BPF_JEQ_K, 0, 3, 0),
+        jump(BPF_JEQ_K, 43, 2, 0),
+        jump(BPF_JEQ_K, 44, 1, 0),
+        jump(BPF_JEQ_K, 60, 0, 1),
+        // 31
+        statement(BPF_RET_K, ACCEPT),
+        statement(BPF_RET_K, DROP),
+    ]
+}
+
+/// A packet socket bound to one interface with `reply_filter` attached.
+pub(super) struct PacketSocket {
+    fd: OwnedFd,
+    buffer: Vec<u8>,
+    /// Counters so far. The kernel resets its own every time they're read.
+    stats: ReceiveStats,
+}
+
+impl PacketSocket {
+    pub fn open(interface: &NetworkInterface, read_timeout: Duration) -> io::Result<Self> {
+        // Protocol zero receives nothing until bound, so no unfiltered frames get queued.
+        // SAFETY: Plain syscall, and we own the returned descriptor.
+        let fd = unsafe {
+            let fd = libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+
+        let mut filter = reply_filter();
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        set_option(&fd, libc::SOL_SOCKET, SO_ATTACH_FILTER, &program)?;
+
+        let timeout = libc::timeval {
+            tv_sec: read_timeout.as_secs() as libc::time_t,
+            tv_usec: read_timeout.subsec_micros() as libc::suseconds_t,
+        };
+        set_option(&fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout)?;
+        let enable: libc::c_int = 1;
+        set_option(&fd, libc::SOL_SOCKET, SO_TIMESTAMPNS, &enable)?;
+
+        // SAFETY: All-zero is a valid sockaddr_ll.
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as libc::c_ushort;
+        address.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        address.sll_ifindex = interface.index as libc::c_int;
+        // SAFETY: `address` is a sockaddr_ll of the length given.
+        let result = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd,
+            buffer: vec![0; 65536],
+            stats: ReceiveStats::default(),
+        })
+    }
+
+    pub fn stats(&mut self) -> io::Result<ReceiveStats> {
+        let mut counters = TpacketStats::default();
+        let mut length = mem::size_of::<TpacketStats>() as libc::socklen_t;
+        // SAFETY: `counters` is a tpacket_stats and `length` is its size.
+        let result = unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                PACKET_STATISTICS,
+                &mut counters as *mut TpacketStats as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The kernel counts drops in its packet total too.
+        self.stats.received += counters.packets.saturating_sub(counters.drops) as u64;
+        self.stats.dropped += counters.drops as u64;
+        Ok(self.stats)
+    }
+
+    /// Wait for the next frame that passes the filter, along with when the kernel says it
+    /// arrived. Fails with `TimedOut` after the read timeout like pnet's channels do.
+    pub fn receive(&mut self) -> io::Result<(&[u8], Instant)> {
+        let mut control = [0u64; 8];
+        let mut iov = libc::iovec {
+            iov_base: self.buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: self.buffer.len(),
+        };
+        // SAFETY: All-zero is a valid msghdr.
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control) as _;
+
+        // SAFETY: Every pointer in `message` is valid for the length next to it.
+        let length = unsafe { libc::recvmsg(self.fd.as_raw_fd(), &mut message, 0) };
+        if length < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => {
+                    Err(io::ErrorKind::TimedOut.into())
+                }
+                _ => Err(error),
+            };
+        }
+        // The control length is a `usize` with glibc but a `u32` with musl.
+        #[allow(clippy::unnecessary_cast)]
+        let received_at = received_at(&mut control, message.msg_controllen as usize);
+        Ok((&self.buffer[..length as usize], received_at))
+    }
+}
+
+fn set_option<T>(fd: &OwnedFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: `value` outlives the call and the length matches it.
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+
+    use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+    use super::*;
+    use crate::traceroute_net::{
+        echo_request, ipv4_probe, ipv6_probe, tcp_syn, udp_probe, LinkType, PacketId, ProbeOptions,
+    };
+
+    /// Run a classic BPF program the way the kernel would over a packet with the given
+    /// EtherType, supporting only the instructions `reply_filter` uses. Loads past the end of
+    /// the packet drop it, like in the kernel.
+    fn run_filter(filter: &[libc::sock_filter], ethertype: u16, packet: &[u8]) -> u32 {
+        let load = |offset: u32, size: usize| -> Option<u32> {
+            if offset == PROTOCOL {
+                return Some(ethertype as u32);
+            }
+            let start = offset.checked_sub(NET)? as usize;
+            let bytes = packet.get(start..start + size)?;
+            Some(bytes.iter().fold(0, |word, &byte| word << 8 | byte as u32))
+        };
+
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0);
+        loop {
+            let instruction = filter[pc];
+            pc += 1;
+            let (k, jt, jf) = (instruction.k, instruction.jt, instruction.jf);
+            let skip = |condition: bool| if condition { jt } else { jf } as usize;
+            match instruction.code {
+                BPF_LD_H_ABS => match load(k, 2) {
+                    Some(value) => a = value,
+                    None => return DROP,
+                },
+                BPF_LD_B_ABS => match load(k, 1) {
+                    Some(value) => a = value,
+                    None => return DROP,
+                },
+                BPF_LD_B_IND => match load(k.wrapping_add(x), 1) {
+                    Some(value) => a = value,
+                    None => return DROP,
+                },
+                BPF_LDX_B_MSH => match load(k, 1) {
+                    Some(value) => x = (value & 0xf) * 4,
+                    None => return DROP,
+                },
+                BPF_JEQ_K => pc += skip(a == k),
+                BPF_JSET_K => pc += skip(a & k != 0),
+                BPF_ALU_AND_K => a &= k,
+                BPF_RET_K => return k,
+                code => panic!("unsupported instruction {:#x}", code),
+            }
+        }
+    }
+
+    fn accepts(ethertype: u16, packet: &[u8]) -> bool {
+        run_filter(&reply_filter(), ethertype, packet) == ACCEPT
+    }
+
+    /// The samples in the `decode_reply` fuzz corpus, as EtherTypes and network packets.
+    fn captured_replies() -> Vec<(String, u16, Vec<u8>)> {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/decode_reply");
+        let mut replies: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let frame = fs::read(&path).unwrap();
+                // Names start with the framing.
+                let link_type = match name.split('-').next().unwrap() {
+                    "ethernet" => LinkType::Ethernet,
+                    "raw" => LinkType::RawIp,
+                    "cooked" => LinkType::LinuxCooked,
+                    "null" => LinkType::Null,
+                    other => panic!("unknown framing {} in {}", other, name),
+                };
+                let (ethertype, packet) = link_type.network_packet(&frame).unwrap();
+                (name, ethertype.0, packet.to_vec())
+            })
+            .filter(|(name, ..)| !name.starts_with("crash-"))
+            .collect();
+        replies.sort();
+        assert!(!replies.is_empty());
+        replies
+    }
+
+    fn ipv4_packet(protocol: IpNextHeaderProtocol, transport: &[u8]) -> Vec<u8> {
+        let (src_ip, dst_ip) = (
+            "192.0.2.10".parse().unwrap(),
+            "198.51.100.1".parse().unwrap(),
+        );
+        ipv4_probe(src_ip, dst_ip, 64, PacketId(1), 0, protocol, transport).unwrap()
+    }
+
+    fn ipv6_packet(protocol: IpNextHeaderProtocol, transport: &[u8]) -> Vec<u8> {
+        let (src_ip, dst_ip) = (
+            "2001:db8::10".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        );
+        ipv6_probe(src_ip, dst_ip, 64, 0, 0, protocol, transport).unwrap()
+    }
+
+    /// A bare TCP header with `flags` set.
+    fn tcp_segment(flags: u8) -> Vec<u8> {
+        let mut segment = vec![0; 20];
+        segment[12] = 5 << 4;
+        segment[13] = flags;
+        segment
+    }
+
+    #[test]
+    fn accepts_captured_replies() {
+        for (name, ethertype, packet) in captured_replies() {
+            assert!(accepts(ethertype, &packet), "{}", name);
+        }
+    }
+
+    #[test]
+    fn finds_icmp_after_ipv4_options() {
+        let (_, ethertype, packet) = captured_replies()
+            .into_iter()
+            .find(|(name, ..)| name == "raw-ipv4-time-exceeded")
+            .unwrap();
+        // Four NOP options push the ICMP header back, where a fixed offset would miss it.
+        let mut with_options = packet[..20].to_vec();
+        with_options[0] = 0x46;
+        with_options.extend([1; 4]);
+        with_options.extend(&packet[20..]);
+        assert!(accepts(ethertype, &with_options));
+
+        // And the same options in front of an Echo Request.
+        with_options[24] = 8;
+        assert!(!accepts(ethertype, &with_options));
+    }
+
+    #[test]
+    fn drops_our_own_probes() {
+        let options = ProbeOptions::default();
+        let id = PacketId(0xbeef);
+        for (src_ip, dst_ip) in [
+            ("192.0.2.10", "198.51.100.1"),
+            ("2001:db8::10", "2001:db8::1"),
+        ] {
+            let (src_ip, dst_ip) = (src_ip.parse().unwrap(), dst_ip.parse().unwrap());
+            let echo = echo_request(dst_ip, id, 1, None, &options).unwrap();
+            let udp = udp_probe(src_ip, dst_ip, 5, id, None, 0x8000, &options).unwrap();
+            let syn = tcp_syn(src_ip, dst_ip, 443, 0x8000, id, &options).unwrap();
+            let probes = match dst_ip {
+                IpAddr::V4(_) => [
+                    ipv4_packet(IpNextHeaderProtocols::Icmp, &echo),
+                    ipv4_packet(IpNextHeaderProtocols::Udp, &udp),
+                    ipv4_packet(IpNextHeaderProtocols::Tcp, &syn),
+                ]
+                .map(|packet| (0x0800, packet)),
+                IpAddr::V6(_) => [
+                    ipv6_packet(IpNextHeaderProtocols::Icmpv6, &echo),
+                    ipv6_packet(IpNextHeaderProtocols::Udp, &udp),
+                    ipv6_packet(IpNextHeaderProtocols::Tcp, &syn),
+                ]
+                .map(|packet| (0x86dd, packet)),
+            };
+            for (ethertype, packet) in probes {
+                assert!(!accepts(ethertype, &packet), "{:?}", packet);
+            }
+        }
+    }
+
+    #[test]
+    fn drops_unrelated_traffic() {
+        let dns_response = [0, 53, 0x80, 0, 0, 8, 0, 0];
+        let unrelated = [
+            // ARP.
+            (0x0806, vec![0, 1, 8, 0, 6, 4, 0, 2]),
+            (
+                0x0800,
+                ipv4_packet(IpNextHeaderProtocols::Udp, &dns_response),
+            ),
+            (
+                0x86dd,
+                ipv6_packet(IpNextHeaderProtocols::Udp, &dns_response),
+            ),
+            // Data on an established connection, and someone else's handshake.
+            (
+                0x0800,
+                ipv4_packet(IpNextHeaderProtocols::Tcp, &tcp_segment(0x18)),
+            ),
+            (
+                0x0800,
+                ipv4_packet(IpNextHeaderProtocols::Tcp, &tcp_segment(0x02)),
+            ),
+            (
+                0x86dd,
+                ipv6_packet(IpNextHeaderProtocols::Tcp, &tcp_segment(0x10)),
+            ),
+            // Redirect, and Neighbor Solicitation.
+            (
+                0x0800,
+                ipv4_packet(IpNextHeaderProtocols::Icmp, &[5, 1, 0, 0]),
+            ),
+            (
+                0x86dd,
+                ipv6_packet(IpNextHeaderProtocols::Icmpv6, &[135, 0, 0, 0]),
+            ),
+            // Claims to be ICMP but stops before the type.
+            (0x0800, ipv4_packet(IpNextHeaderProtocols::Icmp, &[])),
+        ];
+        for (ethertype, packet) in unrelated {
+            assert!(!accepts(ethertype, &packet), "{:?}", packet);
+        }
+    }
+}
@@ -0,0 +1,977 @@
+//! Traceroute without raw sockets. ICMP probes go out of Linux ping sockets and UDP probes out of
+//! ordinary UDP sockets, and with `IP_RECVERR` the kernel hands us the ICMP errors they cause
+//! through each socket's error queue instead of us sniffing the interface.
+
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use pnet::datalink::NetworkInterface;
+
+use super::{
+    checksum_balancing_word, interface_mtu, interface_source_ips, is_too_big_to_send,
+    saturating_u16, FlowId, PacketId, ProbeOptions, ProbeProtocol, ReceiveStats, ReceivedPacket,
+    SourceAddresses, TracerouteError, TracerouteResult, UnreachableCode, UDP_BASE_PORT,
+};
+use crate::icmp_extensions::IcmpExtensions;
+
+/// Not in every version of libc, and different on SPARC.
+#[cfg(not(any(target_arch = "sparc", target_arch = "sparc64")))]
+pub(super) const SO_TIMESTAMPNS: libc::c_int = 35;
+#[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+pub(super) const SO_TIMESTAMPNS: libc::c_int = 0x21;
+
+/// How long `poll` waits for something to arrive, to match the raw channel's read timeout.
+const POLL_TIMEOUT_MS: libc::c_int = 50;
+
+/// UDP probes rotate through this many destination ports, each standing in for a packet ID.
+const UDP_PORT_SLOTS: u16 = 512;
+
+const UDP_HEADER_LEN: usize = 8;
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const ICMPV6_DESTINATION_UNREACHABLE: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+
+/// A ping socket and a UDP socket for one IP version.
+struct Sockets {
+    icmp: OwnedFd,
+    udp: OwnedFd,
+}
+
+impl Sockets {
+    fn open(source_ip: Option<IpAddr>, is_ipv6: bool) -> io::Result<Self> {
+        let (domain, icmp_protocol) = if is_ipv6 {
+            (libc::AF_INET6, libc::IPPROTO_ICMPV6)
+        } else {
+            (libc::AF_INET, libc::IPPROTO_ICMP)
+        };
+        let sockets = Self {
+            icmp: open_socket(domain, icmp_protocol)?,
+            udp: open_socket(domain, libc::IPPROTO_UDP)?,
+        };
+
+        // Probing sets Don't Fragment without letting earlier Packet Too Bigs shrink what we
+        // can send, so probes come out at the size they were asked for. Replies and errors
+        // come with the TTL they arrived with, and when.
+        for fd in [&sockets.icmp, &sockets.udp] {
+            set_option(fd, libc::SOL_SOCKET, SO_TIMESTAMPNS, 1)?;
+            if is_ipv6 {
+                set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)?;
+                set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?;
+                set_option(
+                    fd,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_MTU_DISCOVER,
+                    libc::IPV6_PMTUDISC_PROBE,
+                )?;
+            } else {
+                set_option(fd, libc::IPPROTO_IP, libc::IP_RECVERR, 1)?;
+                set_option(fd, libc::IPPROTO_IP, libc::IP_RECVTTL, 1)?;
+                set_option(
+                    fd,
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    libc::IP_PMTUDISC_PROBE,
+                )?;
+            }
+            if let Some(source_ip) = source_ip {
+                bind(fd, SocketAddr::new(source_ip, 0))?;
+            }
+        }
+
+        Ok(sockets)
+    }
+}
+
+/// An ICMP error read from a socket's error queue.
+struct QueuedError {
+    icmp_type: u8,
+    icmp_code: u8,
+    /// The router or host that sent the error.
+    offender: IpAddr,
+    /// Where the probe that caused it was going.
+    destination: SocketAddr,
+    /// For Packet Too Big, the next-hop MTU.
+    info: u32,
+    /// TTL or hop limit of the ICMP error itself.
+    ttl: Option<u8>,
+    received_at: Instant,
+    /// Whatever the error quoted of the probe after its transport header, or for ping sockets,
+    /// starting with the echo header.
+    payload: Vec<u8>,
+}
+
+pub(super) struct UnprivilegedChannel {
+    v4: Sockets,
+    v6: Option<Sockets>,
+    /// Offset from `UDP_BASE_PORT` of the next UDP probe's destination port.
+    next_udp_slot: u16,
+    /// Destination and packet ID of the last UDP probe sent to each port. ICMP errors are only
+    /// guaranteed to quote the UDP header, so the destination port is all we get back.
+    udp_probes: Vec<Option<(IpAddr, PacketId)>>,
+    source_ipv4: Option<Ipv4Addr>,
+    source_ipv6: Option<Ipv6Addr>,
+    /// MTU of the interface, for probes too big to send.
+    interface_mtu: Option<u16>,
+    /// Packet Too Bigs for probes the kernel refused to send, returned before anything else.
+    local_errors: Vec<TracerouteResult>,
+    /// Echo replies and ICMP errors too short or too strange to decode, for `ReceiveStats`.
+    undecodable: u64,
+}
+
+impl UnprivilegedChannel {
+    pub(super) fn new(
+        interface: &NetworkInterface,
+        enable_ipv6: bool,
+        source: SourceAddresses,
+    ) -> Result<Self, TracerouteError> {
+        let (source_ipv4, source_ipv6) = interface_source_ips(interface, source)?;
+
+        let v4 = Sockets::open(source_ipv4.map(IpAddr::V4), false)
+            .map_err(TracerouteError::Ipv4ChannelIo)?;
+        let v6 = if enable_ipv6 {
+            Some(
+                Sockets::open(source_ipv6.map(IpAddr::V6), true)
+                    .map_err(TracerouteError::Ipv6ChannelIo)?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Self {
+            v4,
+            v6,
+            next_udp_slot: 0,
+            udp_probes: vec![None; UDP_PORT_SLOTS as usize],
+            source_ipv4,
+            source_ipv6,
+            interface_mtu: interface_mtu(interface),
+            local_errors: Vec::new(),
+            undecodable: 0,
+        })
+    }
+
+    pub(super) fn send_probe(
+        &mut self,
+        protocol: ProbeProtocol,
+        dst_ip: IpAddr,
+        ttl: u8,
+        id: PacketId,
+        flow: Option<FlowId>,
+        options: &ProbeOptions,
+    ) -> Result<(), TracerouteError> {
+        let sockets = match dst_ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => self.v6.as_ref().ok_or(TracerouteError::Ipv6Disabled)?,
+        };
+
+        let (fd, payload, dst_port) = match protocol {
+            ProbeProtocol::Icmp => {
+                // The kernel overwrites the identifier with the socket's own, so the packet ID
+                // goes in the sequence number. In a flow, a payload word balances it out to keep
+                // the checksum constant.
+                let icmp_type = match dst_ip {
+                    IpAddr::V4(_) => ICMP_ECHO_REQUEST,
+                    IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+                };
+                let mut packet = vec![icmp_type, 0, 0, 0, 0, 0];
+                packet.extend_from_slice(&id.0.to_be_bytes());
+                if let Some(flow) = flow {
+                    packet.extend_from_slice(&checksum_balancing_word(id, flow.0).to_be_bytes());
+                }
+                packet.extend(options.padding(dst_ip, packet.len()));
+                (&sockets.icmp, packet, 0)
+            }
+            ProbeProtocol::Udp => {
+                let slot = self.next_udp_slot;
+                self.next_udp_slot = (slot + 1) % UDP_PORT_SLOTS;
+                self.udp_probes[slot as usize] = Some((dst_ip, id));
+                let payload = options.padding(dst_ip, UDP_HEADER_LEN);
+                (&sockets.udp, payload, UDP_BASE_PORT + slot)
+            }
+            ProbeProtocol::Tcp { .. } => {
+                return Err(TracerouteError::UnsupportedProtocol(protocol))
+            }
+        };
+
+        let channel_error = match dst_ip {
+            IpAddr::V4(_) => TracerouteError::Ipv4ChannelIo,
+            IpAddr::V6(_) => TracerouteError::Ipv6ChannelIo,
+        };
+        // The flow label would need a lease from the kernel, so it's left to pick one.
+        let traffic_class = options.traffic_class() as libc::c_int;
+        match dst_ip {
+            IpAddr::V4(_) => set_option(fd, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+                .and_then(|_| set_option(fd, libc::IPPROTO_IP, libc::IP_TOS, traffic_class)),
+            IpAddr::V6(_) => set_option(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_UNICAST_HOPS,
+                ttl as libc::c_int,
+            )
+            .and_then(|_| set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, traffic_class)),
+        }
+        .map_err(channel_error)?;
+
+        let dst = SocketAddr::new(dst_ip, dst_port);
+        let result = match send_to(fd, &payload, dst) {
+            // A pending ICMP error fails the next send once, so just try again.
+            Err(error) if is_reported_icmp_error(&error) => send_to(fd, &payload, dst),
+            result => result,
+        }
+        .map_err(channel_error);
+
+        // Bigger than the interface's MTU, so it's our own first hop that's too small.
+        match result {
+            Err(error) if options.size.is_some() && is_too_big_to_send(&error) => {
+                let source_ip = match dst_ip {
+                    IpAddr::V4(_) => self.source_ipv4.map(IpAddr::V4),
+                    IpAddr::V6(_) => self.source_ipv6.map(IpAddr::V6),
+                };
+                self.local_errors.push(TracerouteResult::IcmpPacketTooBig {
+                    ip: source_ip.ok_or(error)?,
+                    id,
+                    dst_ip,
+                    mtu: self.interface_mtu,
+                    extensions: IcmpExtensions::default(),
+                    quoted: None,
+                });
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    pub(super) fn poll(&mut self) -> Result<Option<ReceivedPacket>, TracerouteError> {
+        if !self.local_errors.is_empty() {
+            return Ok(Some(ReceivedPacket {
+                result: self.local_errors.remove(0),
+                received_at: Instant::now(),
+                ttl: None,
+            }));
+        }
+
+        if let Some(packet) = self.try_receive()? {
+            return Ok(Some(packet));
+        }
+
+        let mut fds: Vec<libc::pollfd> = self
+            .sockets()
+            .map(|(fd, _)| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfds.
+        let ready =
+            unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, POLL_TIMEOUT_MS) };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(TracerouteError::RxChannelIo(error)),
+            };
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+
+        self.try_receive()
+    }
+
+    /// The kernel doesn't count what it queues on ordinary sockets, so only what we skipped.
+    pub(super) fn receive_stats(&self) -> ReceiveStats {
+        ReceiveStats {
+            undecodable: self.undecodable,
+            ..Default::default()
+        }
+    }
+
+    /// Every socket, and whether it's a ping socket.
+    fn sockets(&self) -> impl Iterator<Item = (RawFd, bool)> + '_ {
+        [Some(&self.v4), self.v6.as_ref()]
+            .into_iter()
+            .flatten()
+            .flat_map(|sockets| {
+                [
+                    (sockets.icmp.as_raw_fd(), true),
+                    (sockets.udp.as_raw_fd(), false),
+                ]
+            })
+    }
+
+    /// Read the first thing we understand from any socket without blocking.
+    fn try_receive(&mut self) -> Result<Option<ReceivedPacket>, TracerouteError> {
+        let sockets: Vec<(RawFd, bool)> = self.sockets().collect();
+        for (fd, is_ping) in sockets {
+            while let Some(error) = receive_error(fd).map_err(TracerouteError::RxChannelIo)? {
+                let (received_at, ttl) = (error.received_at, error.ttl);
+                if let Some(result) = self.parse_error(error, is_ping) {
+                    return Ok(Some(ReceivedPacket {
+                        result,
+                        received_at,
+                        ttl,
+                    }));
+                }
+            }
+
+            let mut buffer = [0; 1500];
+            let mut control = [0u64; 16];
+            loop {
+                let (length, source, control_length) =
+                    match receive(fd, &mut buffer, &mut control, 0) {
+                        Ok(Some((length, Some(source), control_length))) => {
+                            (length, source, control_length)
+                        }
+                        Ok(Some(_)) => continue,
+                        Ok(None) => break,
+                        // The error queue has something new, which we'll get to next time.
+                        Err(error) if is_reported_icmp_error(&error) => break,
+                        Err(error) => return Err(TracerouteError::RxChannelIo(error)),
+                    };
+                let received_at = received_at(&mut control, control_length);
+
+                // Anything arriving on a UDP socket is an application talking back; ignore it.
+                if !is_ping {
+                    continue;
+                }
+                // Ping sockets only get echo replies for their own identifier, without the IP
+                // header.
+                let packet = &buffer[..length];
+                if let (Some(&ICMP_ECHO_REPLY | &ICMPV6_ECHO_REPLY), Some(sequence)) =
+                    (packet.first(), packet.get(6..8))
+                {
+                    return Ok(Some(ReceivedPacket {
+                        result: TracerouteResult::IcmpReply(
+                            source.ip(),
+                            PacketId(u16::from_be_bytes([sequence[0], sequence[1]])),
+                        ),
+                        received_at,
+                        ttl: received_ttl(&mut control, control_length),
+                    }));
+                }
+                self.undecodable += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn parse_error(&mut self, error: QueuedError, is_ping: bool) -> Option<TracerouteResult> {
+        let id = if is_ping {
+            // The quoted echo header, with our packet ID in the sequence number.
+            let Some(sequence) = error.payload.get(6..8) else {
+                self.undecodable += 1;
+                return None;
+            };
+            PacketId(u16::from_be_bytes([sequence[0], sequence[1]]))
+        } else {
+            let slot = error.destination.port().checked_sub(UDP_BASE_PORT)?;
+            match self.udp_probes.get(slot as usize)? {
+                Some((dst_ip, id)) if *dst_ip == error.destination.ip() => *id,
+                _ => return None,
+            }
+        };
+        let result = error_result(&error, id);
+        if result.is_none() {
+            self.undecodable += 1;
+        }
+        result
+    }
+}
+
+/// What an ICMP error from the error queue says about the probe with `id`, or `None` if it's a
+/// type that doesn't say anything about probes.
+fn error_result(error: &QueuedError, id: PacketId) -> Option<TracerouteResult> {
+    let ip = error.offender;
+    // The kernel doesn't pass multipart extensions through the error queue.
+    let extensions = IcmpExtensions::default();
+
+    let too_big = TracerouteResult::IcmpPacketTooBig {
+        ip,
+        id,
+        dst_ip: error.destination.ip(),
+        mtu: Some(saturating_u16(error.info)).filter(|&mtu| mtu != 0),
+        extensions: IcmpExtensions::default(),
+        quoted: None,
+    };
+
+    let code = match error.destination {
+        SocketAddr::V4(_) => match error.icmp_type {
+            ICMP_TIME_EXCEEDED => {
+                return Some(TracerouteResult::IcmpTimeExceeded(ip, id, extensions, None))
+            }
+            ICMP_DESTINATION_UNREACHABLE if error.icmp_code == ICMP_FRAGMENTATION_NEEDED => {
+                return Some(too_big)
+            }
+            ICMP_DESTINATION_UNREACHABLE => UnreachableCode::from_icmpv4(error.icmp_code),
+            _ => return None,
+        },
+        SocketAddr::V6(_) => match error.icmp_type {
+            ICMPV6_TIME_EXCEEDED => {
+                return Some(TracerouteResult::IcmpTimeExceeded(ip, id, extensions, None))
+            }
+            ICMPV6_DESTINATION_UNREACHABLE => UnreachableCode::from_icmpv6(error.icmp_code),
+            ICMPV6_PACKET_TOO_BIG => return Some(too_big),
+            _ => return None,
+        },
+    };
+
+    if code == UnreachableCode::Port {
+        Some(TracerouteResult::IcmpPortUnreachable(
+            ip, id, extensions, None,
+        ))
+    } else {
+        Some(TracerouteResult::IcmpDestinationUnreachable {
+            ip,
+            id,
+            dst_ip: error.destination.ip(),
+            code,
+            extensions,
+            quoted: None,
+        })
+    }
+}
+
+/// With `IP_RECVERR`, an ICMP error also fails the next send or receive once with its errno.
+/// The error itself is still waiting in the error queue.
+fn is_reported_icmp_error(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(
+            libc::EHOSTUNREACH
+                | libc::ENETUNREACH
+                | libc::ECONNREFUSED
+                | libc::EACCES
+                | libc::EPROTO
+                | libc::EMSGSIZE
+                | libc::ENOPROTOOPT
+        )
+    )
+}
+
+fn open_socket(domain: libc::c_int, protocol: libc::c_int) -> io::Result<OwnedFd> {
+    // SAFETY: Plain syscall, and we own the returned descriptor.
+    unsafe {
+        let fd = libc::socket(
+            domain,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            protocol,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn set_option(
+    fd: &OwnedFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: `value` outlives the call and the length matches it.
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn bind(fd: &OwnedFd, addr: SocketAddr) -> io::Result<()> {
+    let (storage, length) = socket_addr_to_raw(addr);
+    // SAFETY: `storage` holds a sockaddr of `length` bytes.
+    let result = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+            length,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn send_to(fd: &OwnedFd, payload: &[u8], dst: SocketAddr) -> io::Result<()> {
+    let (storage, length) = socket_addr_to_raw(dst);
+    // SAFETY: `payload` and `storage` are valid for the lengths given.
+    let result = unsafe {
+        libc::sendto(
+            fd.as_raw_fd(),
+            payload.as_ptr() as *const libc::c_void,
+            payload.len(),
+            0,
+            &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+            length,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `recvmsg` into `buffer` and `control`, returning the data length, the peer address and the
+/// control length, or `None` if there's nothing to read.
+fn receive(
+    fd: RawFd,
+    buffer: &mut [u8],
+    control: &mut [u64],
+    flags: libc::c_int,
+) -> io::Result<Option<(usize, Option<SocketAddr>, usize)>> {
+    // SAFETY: All-zero is a valid sockaddr_storage and msghdr.
+    let (mut name, mut message): (libc::sockaddr_storage, libc::msghdr) =
+        unsafe { (mem::zeroed(), mem::zeroed()) };
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    message.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    message.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    if !control.is_empty() {
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(control) as _;
+    }
+
+    // SAFETY: Every pointer in `message` is valid for the length next to it.
+    let length = unsafe { libc::recvmsg(fd, &mut message, flags | libc::MSG_DONTWAIT) };
+    if length < 0 {
+        let error = io::Error::last_os_error();
+        return match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+            _ => Err(error),
+        };
+    }
+
+    // SAFETY: The kernel filled in `name`, which is big enough for any address.
+    let source = unsafe {
+        socket_addr_from_raw(&name as *const libc::sockaddr_storage as *const libc::sockaddr)
+    };
+    // The control length is a `usize` with glibc but a `u32` with musl.
+    #[allow(clippy::unnecessary_cast)]
+    let control_length = message.msg_controllen as usize;
+    Ok(Some((length as usize, source, control_length)))
+}
+
+/// Pop the next ICMP error off a socket's error queue. Errors that didn't come from ICMP, or
+/// that don't say who sent them, come back as `None` too but are still removed from the queue,
+/// so this keeps going until the queue is empty.
+fn receive_error(fd: RawFd) -> io::Result<Option<QueuedError>> {
+    let mut buffer = [0; 512];
+    let mut control = [0u64; 64];
+    loop {
+        let Some((length, Some(destination), control_length)) =
+            receive(fd, &mut buffer, &mut control, libc::MSG_ERRQUEUE)?
+        else {
+            return Ok(None);
+        };
+
+        let payload = &buffer[..length];
+        if let Some(queued) = queued_error(&mut control, control_length, destination, payload) {
+            return Ok(Some(queued));
+        }
+    }
+}
+
+/// The ICMP error among the control messages `receive` read off an error queue, for a probe to
+/// `destination` that it quoted `payload` of. Errors that didn't come from ICMP, or that don't
+/// say who sent them, are `None`.
+fn queued_error(
+    control: &mut [u64],
+    control_length: usize,
+    destination: SocketAddr,
+    payload: &[u8],
+) -> Option<QueuedError> {
+    let ttl = received_ttl(control, control_length);
+    let received_at = received_at(control, control_length);
+    // SAFETY: All-zero is a valid msghdr.
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = control_length as _;
+
+    // SAFETY: `message` points at the control messages the kernel wrote, and the extended error
+    // and its offender address are within the one they came in.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&message);
+        while !cmsg.is_null() {
+            let is_error = matches!(
+                ((*cmsg).cmsg_level, (*cmsg).cmsg_type),
+                (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+            );
+            if is_error {
+                let extended = libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err;
+                let error = ptr::read_unaligned(extended);
+                let from_icmp = error.ee_origin == libc::SO_EE_ORIGIN_ICMP
+                    || error.ee_origin == libc::SO_EE_ORIGIN_ICMP6;
+                let offender = socket_addr_from_raw(libc::SO_EE_OFFENDER(extended));
+                if let (true, Some(offender)) = (from_icmp, offender) {
+                    return Some(QueuedError {
+                        icmp_type: error.ee_type,
+                        icmp_code: error.ee_code,
+                        offender: offender.ip(),
+                        destination,
+                        info: error.ee_info,
+                        ttl,
+                        received_at,
+                        payload: payload.to_vec(),
+                    });
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+        }
+    }
+    None
+}
+
+/// The TTL or hop limit among the control messages `receive` wrote, which the kernel adds with
+/// `IP_RECVTTL` or `IPV6_RECVHOPLIMIT`, error queue included.
+fn received_ttl(control: &mut [u64], control_length: usize) -> Option<u8> {
+    // SAFETY: All-zero is a valid msghdr.
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = control_length as _;
+
+    // SAFETY: `message` points at the control messages the kernel wrote, and both kinds we
+    // read carry an int.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&message);
+        while !cmsg.is_null() {
+            if matches!(
+                ((*cmsg).cmsg_level, (*cmsg).cmsg_type),
+                (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT)
+            ) {
+                let ttl = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return u8::try_from(ttl).ok();
+            }
+            cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+        }
+    }
+    None
+}
+
+/// When the kernel says a packet arrived, from the control message `SO_TIMESTAMPNS` adds to
+/// whatever `receive` wrote, error queue included. Replies can sit in the socket for a whole
+/// poll interval before we read them, which would otherwise count towards their round trip.
+/// The timestamp is wall clock time, so it becomes an `Instant` by how long ago it was. Without
+/// one, or if the clock went backwards since, it's now.
+pub(super) fn received_at(control: &mut [u64], control_length: usize) -> Instant {
+    let now = Instant::now();
+    // SAFETY: All-zero is a valid msghdr.
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = control_length as _;
+
+    // SAFETY: `message` points at the control messages the kernel wrote, and the timestamp one
+    // carries a timespec.
+    let timestamp = unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&message);
+        let mut timestamp = None;
+        while !cmsg.is_null() {
+            if ((*cmsg).cmsg_level, (*cmsg).cmsg_type) == (libc::SOL_SOCKET, SO_TIMESTAMPNS) {
+                timestamp = Some(ptr::read_unaligned(
+                    libc::CMSG_DATA(cmsg) as *const libc::timespec
+                ));
+                break;
+            }
+            cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+        }
+        timestamp
+    };
+
+    timestamp
+        .and_then(|timestamp| {
+            let arrived = UNIX_EPOCH
+                + Duration::new(
+                    u64::try_from(timestamp.tv_sec).ok()?,
+                    u32::try_from(timestamp.tv_nsec).ok()?,
+                );
+            now.checked_sub(SystemTime::now().duration_since(arrived).ok()?)
+        })
+        .unwrap_or(now)
+}
+
+fn socket_addr_to_raw(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: All-zero is a valid sockaddr_storage, and both sockaddr kinds fit in one.
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let length = match addr {
+            SocketAddr::V4(addr) => {
+                let raw = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+                ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, raw);
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let raw = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: addr.port().to_be(),
+                    sin6_flowinfo: 0,
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    },
+                    sin6_scope_id: addr.scope_id(),
+                };
+                ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, raw);
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, length as libc::socklen_t)
+    }
+}
+
+/// # Safety
+///
+/// `addr` must point to a sockaddr that's as big as its family says.
+unsafe fn socket_addr_from_raw(addr: *const libc::sockaddr) -> Option<SocketAddr> {
+    match ptr::read_unaligned(addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = ptr::read_unaligned(addr as *const libc::sockaddr_in);
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = ptr::read_unaligned(addr as *const libc::sockaddr_in6);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Control messages like the kernel writes for an error from `offender`: the TTL the error
+    /// arrived with, then the extended error with the offender's address after it. Returns the
+    /// buffer and how much of it was written.
+    fn control_messages(
+        origin: u8,
+        icmp_type: u8,
+        icmp_code: u8,
+        info: u32,
+        offender: Option<SocketAddr>,
+        is_ipv6: bool,
+    ) -> ([u64; 64], usize) {
+        let (level, error_type, ttl_type) = if is_ipv6 {
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVERR, libc::IPV6_HOPLIMIT)
+        } else {
+            (libc::IPPROTO_IP, libc::IP_RECVERR, libc::IP_TTL)
+        };
+        let (offender, offender_length) = match offender {
+            Some(offender) => socket_addr_to_raw(offender),
+            // SAFETY: All-zero is a valid sockaddr_storage, with family `AF_UNSPEC`.
+            None => (
+                unsafe { mem::zeroed() },
+                mem::size_of::<libc::sockaddr_in6>() as _,
+            ),
+        };
+        let error = libc::sock_extended_err {
+            ee_errno: libc::EHOSTUNREACH as u32,
+            ee_origin: origin,
+            ee_type: icmp_type,
+            ee_code: icmp_code,
+            ee_pad: 0,
+            ee_info: info,
+            ee_data: 0,
+        };
+        let error_length = mem::size_of::<libc::sock_extended_err>() + offender_length as usize;
+
+        let mut control = [0u64; 64];
+        // SAFETY: All-zero is a valid msghdr.
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control) as _;
+        // SAFETY: Both control messages fit in `control`, and are written within their space.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&message);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = ttl_type;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, 57);
+
+            let cmsg = libc::CMSG_NXTHDR(&message, cmsg);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = error_type;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(error_length as u32) as _;
+            let data = libc::CMSG_DATA(cmsg);
+            ptr::write_unaligned(data as *mut libc::sock_extended_err, error);
+            ptr::copy_nonoverlapping(
+                &offender as *const libc::sockaddr_storage as *const u8,
+                data.add(mem::size_of::<libc::sock_extended_err>()),
+                offender_length as usize,
+            );
+
+            let end = cmsg as usize - control.as_ptr() as usize;
+            let length = end + libc::CMSG_SPACE(error_length as u32) as usize;
+            (control, length)
+        }
+    }
+
+    /// Read an ICMP error for a probe to `destination`, sent by `offender`, back out of the
+    /// control messages and turn it into a result for packet ID 7.
+    fn decode(
+        icmp_type: u8,
+        icmp_code: u8,
+        info: u32,
+        offender: &str,
+        destination: &str,
+    ) -> Option<TracerouteResult> {
+        let destination: SocketAddr = destination.parse().unwrap();
+        let origin = match destination {
+            SocketAddr::V4(_) => libc::SO_EE_ORIGIN_ICMP,
+            SocketAddr::V6(_) => libc::SO_EE_ORIGIN_ICMP6,
+        };
+        let offender = SocketAddr::new(offender.parse().unwrap(), 0);
+        let (mut control, length) = control_messages(
+            origin,
+            icmp_type,
+            icmp_code,
+            info,
+            Some(offender),
+            destination.is_ipv6(),
+        );
+        let error = queued_error(&mut control, length, destination, &[]).unwrap();
+        assert_eq!(error.offender, offender.ip());
+        assert_eq!(error.ttl, Some(57));
+        error_result(&error, PacketId(7))
+    }
+
+    #[test]
+    fn maps_icmpv4_errors() {
+        let router: IpAddr = "192.0.2.1".parse().unwrap();
+        let destination = "198.51.100.1:33434";
+        let fragmentation_needed = |mtu| {
+            let code = ICMP_FRAGMENTATION_NEEDED;
+            decode(
+                ICMP_DESTINATION_UNREACHABLE,
+                code,
+                mtu,
+                "192.0.2.1",
+                destination,
+            )
+        };
+
+        assert!(matches!(
+            decode(ICMP_TIME_EXCEEDED, 0, 0, "192.0.2.1", destination),
+            Some(TracerouteResult::IcmpTimeExceeded(ip, PacketId(7), _, None)) if ip == router
+        ));
+        assert!(matches!(
+            decode(ICMP_DESTINATION_UNREACHABLE, 3, 0, "192.0.2.1", destination),
+            Some(TracerouteResult::IcmpPortUnreachable(ip, PacketId(7), _, None)) if ip == router
+        ));
+        assert!(matches!(
+            decode(ICMP_DESTINATION_UNREACHABLE, 1, 0, "192.0.2.1", destination),
+            Some(TracerouteResult::IcmpDestinationUnreachable {
+                ip,
+                code: UnreachableCode::Host,
+                ..
+            }) if ip == router
+        ));
+        assert!(matches!(
+            fragmentation_needed(1400),
+            Some(TracerouteResult::IcmpPacketTooBig {
+                ip,
+                mtu: Some(1400),
+                ..
+            }) if ip == router
+        ));
+        // Old routers don't say what the MTU is.
+        assert!(matches!(
+            fragmentation_needed(0),
+            Some(TracerouteResult::IcmpPacketTooBig { mtu: None, .. })
+        ));
+        // Source Quench says nothing about where the probe got to.
+        assert!(decode(4, 0, 0, "192.0.2.1", destination).is_none());
+    }
+
+    #[test]
+    fn maps_icmpv6_errors() {
+        let router: IpAddr = "2001:db8::ff".parse().unwrap();
+        let destination = "[2001:db8::1]:33434";
+
+        assert!(matches!(
+            decode(ICMPV6_TIME_EXCEEDED, 0, 0, "2001:db8::ff", destination),
+            Some(TracerouteResult::IcmpTimeExceeded(ip, PacketId(7), _, None)) if ip == router
+        ));
+        assert!(matches!(
+            decode(ICMPV6_DESTINATION_UNREACHABLE, 4, 0, "2001:db8::ff", destination),
+            Some(TracerouteResult::IcmpPortUnreachable(ip, PacketId(7), _, None)) if ip == router
+        ));
+        assert!(matches!(
+            decode(ICMPV6_DESTINATION_UNREACHABLE, 1, 0, "2001:db8::ff", destination),
+            Some(TracerouteResult::IcmpDestinationUnreachable {
+                ip,
+                code: UnreachableCode::AdminProhibited,
+                ..
+            }) if ip == router
+        ));
+        assert!(matches!(
+            decode(ICMPV6_PACKET_TOO_BIG, 0, 1280, "2001:db8::ff", destination),
+            Some(TracerouteResult::IcmpPacketTooBig {
+                ip,
+                mtu: Some(1280),
+                ..
+            }) if ip == router
+        ));
+        // ICMPv4 type numbers mean something else in ICMPv6.
+        assert!(decode(ICMP_TIME_EXCEEDED, 0, 0, "2001:db8::ff", destination).is_none());
+    }
+
+    #[test]
+    fn skips_errors_not_from_icmp_or_without_an_offender() {
+        let destination: SocketAddr = "198.51.100.1:33434".parse().unwrap();
+        let offender = Some("192.0.2.1:0".parse().unwrap());
+
+        // Like the kernel's own errors for a send that failed locally.
+        let (mut control, length) =
+            control_messages(libc::SO_EE_ORIGIN_LOCAL, 0, 0, 1400, offender, false);
+        assert!(queued_error(&mut control, length, destination, &[]).is_none());
+
+        let (mut control, length) = control_messages(
+            libc::SO_EE_ORIGIN_ICMP,
+            ICMP_TIME_EXCEEDED,
+            0,
+            0,
+            None,
+            false,
+        );
+        assert!(queued_error(&mut control, length, destination, &[]).is_none());
+    }
+}
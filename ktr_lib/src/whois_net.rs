@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io::{self, prelude::*, BufReader, Lines};
 use std::net::{IpAddr, TcpStream, ToSocketAddrs};
@@ -16,12 +17,65 @@ pub enum AsnResult {
     NotFound,
 }
 
+/// Where traces get the ASNs of the routers they find from.
+pub trait AsnLookup: Debug + Send + Sync {
+    /// Start finding the ASN for an IP, to be polled until it's no longer pending.
+    fn lookup(&self, ip: IpAddr) -> Result<AsnFinder, io::Error>;
+}
+
+/// Asks WHOIS servers. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WhoisAsnLookup;
+
+impl AsnLookup for WhoisAsnLookup {
+    fn lookup(&self, ip: IpAddr) -> Result<AsnFinder, io::Error> {
+        AsnFinder::lookup(ip)
+    }
+}
+
+/// Stands in for WHOIS with a fixed table, so lookups are instant and work offline. IPs that
+/// aren't in the table have no ASN.
+#[derive(Debug, Clone, Default)]
+pub struct StaticAsnLookup {
+    asns: HashMap<IpAddr, Asn>,
+}
+
+impl StaticAsnLookup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, ip: IpAddr, asn: Asn) {
+        self.asns.insert(ip, asn);
+    }
+}
+
+impl FromIterator<(IpAddr, Asn)> for StaticAsnLookup {
+    fn from_iter<I: IntoIterator<Item = (IpAddr, Asn)>>(iter: I) -> Self {
+        Self {
+            asns: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl AsnLookup for StaticAsnLookup {
+    fn lookup(&self, ip: IpAddr) -> Result<AsnFinder, io::Error> {
+        Ok(AsnFinder::known(
+            self.asns
+                .get(&ip)
+                .map_or(AsnResult::NotFound, |&asn| AsnResult::Found(asn)),
+        ))
+    }
+}
+
 /// ASN finder that tries multiple WHOIS servers.
 #[derive(Debug)]
 pub struct AsnFinder {
     iana: Option<NormalAsnServer>,
     radb: Option<NormalAsnServer>,
     cymru: Option<CymruAsnServer>,
+    /// An answer we already have, from a stand-in for WHOIS.
+    known: Option<AsnResult>,
 }
 
 impl AsnFinder {
@@ -30,10 +84,25 @@ impl AsnFinder {
             iana: NormalAsnServer::connect(ip, "whois.iana.org").ok(),
             radb: NormalAsnServer::connect(ip, "whois.radb.net").ok(),
             cymru: CymruAsnServer::connect(ip).ok(),
+            known: None,
         })
     }
 
+    /// A finder that doesn't ask anyone, and just returns `result` when polled.
+    pub fn known(result: AsnResult) -> AsnFinder {
+        Self {
+            iana: None,
+            radb: None,
+            cymru: None,
+            known: Some(result),
+        }
+    }
+
     pub fn poll(&mut self) -> Result<AsnResult, io::Error> {
+        if let Some(result) = self.known {
+            return Ok(result);
+        }
+
         let results = [
             self.iana
                 .as_mut()
@@ -0,0 +1,151 @@
+//! Runs the fuzz corpora through the parsers on stable, along with every truncation and some
+//! corruption of each input, so anything the fuzzers have found stays fixed.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use ktr_lib::pcap::read_capture;
+use ktr_lib::traceroute_net::{decode_reply, LinkType, PacketId, TracerouteResult};
+
+const LINK_TYPES: [LinkType; 4] = [
+    LinkType::Ethernet,
+    LinkType::RawIp,
+    LinkType::LinuxCooked,
+    LinkType::Null,
+];
+
+fn corpus(target: &str) -> Vec<(String, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+    let mut inputs: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(path).unwrap())
+        })
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty());
+    inputs
+}
+
+/// Every prefix of the input, then the input with each byte zeroed and then maxed out.
+fn mangled(input: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    let truncated = (0..input.len()).map(|length| input[..length].to_vec());
+    let corrupted = (0..input.len()).flat_map(move |index| {
+        [0x00, 0xff].map(|byte| {
+            let mut corrupted = input.to_vec();
+            corrupted[index] = byte;
+            corrupted
+        })
+    });
+    truncated.chain(corrupted)
+}
+
+/// What a valid reply in the corpus decodes to: the kind of reply, who sent it, and the ID of
+/// the probe it answers.
+fn expected_reply(name: &str) -> (&'static str, &'static str, u16) {
+    match name {
+        "cooked-ipv4-time-exceeded" => ("TimeExceeded", "10.98.0.1", 35364),
+        "ethernet-ipv4-echo-reply" => ("EchoReply", "9.9.9.9", 4100),
+        "ethernet-ipv4-time-exceeded" => ("TimeExceeded", "192.168.1.1", 4097),
+        "ethernet-ipv6-time-exceeded" => ("TimeExceeded", "2001:db8:1::1", 4660),
+        "null-ipv4-echo-reply" => ("EchoReply", "10.99.0.2", 50153),
+        "raw-ipv4-echo-reply" => ("EchoReply", "10.99.0.2", 50153),
+        "raw-ipv4-fragmentation-needed" => ("PacketTooBig", "10.98.0.3", 46190),
+        "raw-ipv4-host-unreachable" => ("DestinationUnreachable", "10.98.0.3", 46190),
+        "raw-ipv4-port-unreachable" => ("PortUnreachable", "10.99.0.2", 671),
+        "raw-ipv4-tcp-rst-ack" => ("Tcp", "10.99.0.2", 20760),
+        "raw-ipv4-tcp-syn-ack" => ("Tcp", "10.99.0.2", 23393),
+        "raw-ipv4-time-exceeded" => ("TimeExceeded", "10.98.0.1", 35364),
+        "raw-ipv4-time-exceeded-mpls" => ("TimeExceeded", "10.98.0.2", 15940),
+        "raw-ipv6-admin-prohibited" => ("DestinationUnreachable", "2001:db8:1::1", 4660),
+        "raw-ipv6-echo-reply-destination-options" => ("EchoReply", "2001:db8::9", 4660),
+        "raw-ipv6-packet-too-big" => ("PacketTooBig", "2001:db8:1::1", 4660),
+        "raw-ipv6-port-unreachable" => ("PortUnreachable", "2001:db8::9", 17185),
+        "raw-ipv6-tcp-syn-ack" => ("Tcp", "2001:db8::9", 22136),
+        "raw-ipv6-time-exceeded" => ("TimeExceeded", "2001:db8:1::1", 4660),
+        "raw-ipv6-time-exceeded-extension-headers" => ("TimeExceeded", "2001:db8:1::1", 4660),
+        "raw-ipv6-time-exceeded-quoted-fragment" => ("TimeExceeded", "2001:db8:1::1", 17185),
+        // New samples need an expectation too.
+        other => panic!("no expected reply for {}", other),
+    }
+}
+
+/// The kind of reply and who sent it, in the terms `expected_reply` uses.
+fn describe(result: &TracerouteResult) -> (&'static str, IpAddr) {
+    match *result {
+        TracerouteResult::IcmpReply(ip, _) => ("EchoReply", ip),
+        TracerouteResult::IcmpTimeExceeded(ip, ..) => ("TimeExceeded", ip),
+        TracerouteResult::IcmpDestinationUnreachable { ip, .. } => ("DestinationUnreachable", ip),
+        TracerouteResult::IcmpPortUnreachable(ip, ..) => ("PortUnreachable", ip),
+        TracerouteResult::IcmpPacketTooBig { ip, .. } => ("PacketTooBig", ip),
+        TracerouteResult::TcpReply { ip, .. } => ("Tcp", ip),
+    }
+}
+
+/// Inputs the fuzzers crashed on are kept as `crash-*`, and don't have to parse.
+fn is_crash(name: &str) -> bool {
+    name.starts_with("crash-")
+}
+
+#[test]
+fn decodes_captured_replies() {
+    for (name, frame) in corpus("decode_reply") {
+        if is_crash(&name) {
+            continue;
+        }
+        // Names start with the framing.
+        let link_type = match name.split('-').next().unwrap() {
+            "ethernet" => LinkType::Ethernet,
+            "raw" => LinkType::RawIp,
+            "cooked" => LinkType::LinuxCooked,
+            "null" => LinkType::Null,
+            other => panic!("unknown framing {} in {}", other, name),
+        };
+        let result = match decode_reply(link_type, &frame) {
+            Ok(Some(result)) => result,
+            other => panic!("{}: {:?}", name, other),
+        };
+
+        let (kind, ip, id) = expected_reply(&name);
+        assert_eq!(
+            describe(&result),
+            (kind, ip.parse().unwrap()),
+            "{}: {:?}",
+            name,
+            result
+        );
+        assert_eq!(result.id(), PacketId(id), "{}", name);
+    }
+}
+
+#[test]
+fn survives_mangled_replies() {
+    for (_, frame) in corpus("decode_reply") {
+        for frame in mangled(&frame) {
+            for link_type in LINK_TYPES {
+                let _ = decode_reply(link_type, &frame);
+            }
+        }
+    }
+}
+
+#[test]
+fn survives_mangled_captures() {
+    for (name, capture) in corpus("read_capture") {
+        let result = read_capture(capture.as_slice());
+        assert!(
+            is_crash(&name) || result.is_ok(),
+            "{}: {:?}",
+            name,
+            result.err()
+        );
+        for capture in mangled(&capture) {
+            let _ = read_capture(capture.as_slice());
+        }
+    }
+}
@@ -0,0 +1,24 @@
+-- Just the columns PeeringDbManager reads, and just the networks the tests need.
+CREATE TABLE peeringdb_organization (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    website TEXT NOT NULL
+);
+
+CREATE TABLE peeringdb_network (
+    id INTEGER PRIMARY KEY,
+    org_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    website TEXT NOT NULL,
+    info_scope TEXT NOT NULL,
+    asn INTEGER NOT NULL,
+    info_type TEXT NOT NULL,
+    info_unicast BOOLEAN NOT NULL,
+    info_multicast BOOLEAN NOT NULL,
+    info_ipv6 BOOLEAN NOT NULL,
+    info_never_via_route_servers BOOLEAN NOT NULL
+);
+
+INSERT INTO peeringdb_organization VALUES (1, 'Arelion Sweden AB', 'https://www.arelion.com');
+INSERT INTO peeringdb_network VALUES
+    (1, 1, 'Arelion', 'https://www.arelion.com', 'Global', 1299, 'NSP', 1, 0, 1, 0);
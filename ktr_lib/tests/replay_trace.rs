@@ -0,0 +1,488 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use ktr_lib::metadata::Asn;
+use ktr_lib::pcap::{read_capture, PcapWriter};
+use ktr_lib::peeringdb::PeeringDbManager;
+use ktr_lib::replay_net::ReplayNetwork;
+use ktr_lib::trace::{Hop, Rewrites, TerminationReason, Trace, TraceConfig};
+use ktr_lib::traceroute_net::{LinkType, ProbeProtocol, ProbeTransport, UnreachableCode};
+use ktr_lib::whois_net::StaticAsnLookup;
+
+/// An ICMP trace from 192.168.1.10 to 9.9.9.9 over Ethernet, as Wireshark would save it. The
+/// first probe to hop 2 was lost and answered when retried a second later, and there's a DNS
+/// query in the middle that has nothing to do with the trace.
+const INCIDENT: &[u8] = include_bytes!("fixtures/incident-quad9.pcapng");
+const PEERINGDB: &str = include_str!("fixtures/peeringdb.sql");
+
+fn ip(text: &str) -> IpAddr {
+    text.parse().unwrap()
+}
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+fn config(network: &ReplayNetwork) -> TraceConfig {
+    TraceConfig {
+        max_hops: 16,
+        max_sequential_pending: 3,
+        wait_time_per_hop: ms(100),
+        retry_frequency: ms(500),
+        destination_timeout: ms(2000),
+        completion_timeout: ms(3000),
+        asn_cache_size: 16,
+        probes_per_hop: 1,
+        clock: Arc::new(network.clock().clone()),
+        asn_lookup: Arc::new(StaticAsnLookup::from_iter([
+            (ip("62.115.1.1"), Asn(1299)),
+            (ip("62.115.2.2"), Asn(1299)),
+            (ip("9.9.9.9"), Asn(19281)),
+        ])),
+        ..Default::default()
+    }
+}
+
+fn run(
+    trace: &mut Trace,
+    network: &mut ReplayNetwork,
+    peeringdb: &PeeringDbManager,
+) -> TerminationReason {
+    loop {
+        assert!(network.clock().elapsed() < Duration::from_secs(60));
+        if let (_, Some(reason)) = trace.non_packet_poll(network, peeringdb).unwrap() {
+            return reason;
+        }
+        if let Some(packet) = network.poll().unwrap() {
+            let (_, reason) = trace
+                .perhaps_use_packet(&packet, network, peeringdb)
+                .unwrap();
+            if let Some(reason) = reason {
+                return reason;
+            }
+        }
+    }
+}
+
+#[test]
+fn replays_incident() {
+    let mut network = ReplayNetwork::from_reader(INCIDENT).unwrap();
+    let config = config(&network);
+    let peeringdb = PeeringDbManager::from_sql(PEERINGDB).unwrap();
+    let mut trace = Trace::new(ip("9.9.9.9"), &config);
+
+    let reason = run(&mut trace, &mut network, &peeringdb);
+    assert_eq!(reason, TerminationReason::Done);
+
+    let hops: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done {
+                ip,
+                network_info,
+                details,
+                ..
+            } => (
+                *ip,
+                network_info.as_ref().map(|info| info.asn),
+                network_info
+                    .as_ref()
+                    .and_then(|info| info.network.as_ref())
+                    .map(|network| network.name.clone()),
+                details.rtt.min(),
+            ),
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    assert_eq!(
+        hops,
+        vec![
+            (
+                ip("192.168.1.1"),
+                None,
+                None,
+                Some(Duration::from_micros(1200))
+            ),
+            (
+                ip("62.115.1.1"),
+                Some(Asn(1299)),
+                Some("Arelion".to_string()),
+                Some(ms(8))
+            ),
+            (
+                ip("62.115.2.2"),
+                Some(Asn(1299)),
+                Some("Arelion".to_string()),
+                Some(ms(12))
+            ),
+            (ip("9.9.9.9"), Some(Asn(19281)), None, Some(ms(15))),
+        ]
+    );
+}
+
+#[test]
+fn reads_back_written_capture() {
+    let timestamp = UNIX_EPOCH + Duration::from_micros(1_760_000_000_123_456);
+    let packet = [
+        0x45, 0, 0, 20, 0, 0, 0x40, 0, 64, 1, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+    ];
+
+    let mut bytes = Vec::new();
+    let mut writer = PcapWriter::new(&mut bytes).unwrap();
+    writer.write_packet(timestamp, &packet).unwrap();
+    writer.write_packet(UNIX_EPOCH, &packet[..4]).unwrap();
+    writer.flush().unwrap();
+
+    let frames = read_capture(bytes.as_slice()).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].timestamp, timestamp);
+    assert_eq!(frames[0].link_type, LinkType::RawIp);
+    assert_eq!(frames[0].data, packet);
+    assert_eq!(frames[1].data, packet[..4]);
+}
+
+fn ipv6(src: &str, dst: &str, next_header: u8, hop_limit: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[next_header, hop_limit]);
+    for address in [src, dst] {
+        let address: std::net::Ipv6Addr = address.parse().unwrap();
+        packet.extend_from_slice(&address.octets());
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// An 8 byte Hop-by-Hop or Destination Options header, padded out with PadN.
+fn options(next_header: u8) -> Vec<u8> {
+    vec![next_header, 0, 1, 4, 0, 0, 0, 0]
+}
+
+fn echo(icmpv6_type: u8, id: u16) -> Vec<u8> {
+    let [high, low] = id.to_be_bytes();
+    vec![icmpv6_type, 0, 0, 0, high, low, 0, 1]
+}
+
+#[test]
+fn replays_ipv6_replies_behind_extension_headers() {
+    let (source, destination, router) = ("2001:db8::10", "2001:db8::9", "2001:db8:1::1");
+    let first_probe = ipv6(source, destination, 58, 1, &echo(128, 0x1111));
+    let second_probe = ipv6(source, destination, 58, 2, &echo(128, 0x2222));
+
+    // The router quotes the probe with a Destination Options header it never had, and sends
+    // its Time Exceeded with a Hop-by-Hop header.
+    let quoted = ipv6(
+        source,
+        destination,
+        60,
+        1,
+        &[options(58), echo(128, 0x1111)].concat(),
+    );
+    let time_exceeded = [options(58), vec![3, 0, 0, 0, 0, 0, 0, 0], quoted].concat();
+    let time_exceeded = ipv6(router, source, 0, 64, &time_exceeded);
+    let echo_reply = [options(58), echo(129, 0x2222)].concat();
+    let echo_reply = ipv6(destination, source, 60, 64, &echo_reply);
+
+    let start = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+    let mut bytes = Vec::new();
+    let mut writer = PcapWriter::new(&mut bytes).unwrap();
+    writer.write_packet(start, &first_probe).unwrap();
+    writer.write_packet(start, &second_probe).unwrap();
+    // Cut off in the middle of the fixed header, which used to panic.
+    writer
+        .write_packet(start + ms(1), &echo_reply[..30])
+        .unwrap();
+    writer.write_packet(start + ms(3), &time_exceeded).unwrap();
+    writer.write_packet(start + ms(7), &echo_reply).unwrap();
+    writer.flush().unwrap();
+
+    let mut network = ReplayNetwork::from_reader(bytes.as_slice()).unwrap();
+    let config = config(&network);
+    let peeringdb = PeeringDbManager::from_sql(PEERINGDB).unwrap();
+    let mut trace = Trace::new(ip(destination), &config);
+
+    let reason = run(&mut trace, &mut network, &peeringdb);
+    assert_eq!(reason, TerminationReason::Done);
+
+    let hops: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { ip, details, .. } => (*ip, details.rtt.min()),
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    assert_eq!(
+        hops,
+        vec![(ip(router), Some(ms(3))), (ip(destination), Some(ms(7)))]
+    );
+}
+
+fn ipv4(src: &str, dst: &str, protocol: u8, ttl: u8, id: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x45, 0];
+    packet.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x40, 0, ttl, protocol, 0, 0]);
+    for address in [src, dst] {
+        let address: std::net::Ipv4Addr = address.parse().unwrap();
+        packet.extend_from_slice(&address.octets());
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn echo_with_checksum(icmp_type: u8, id: u16, checksum: u16) -> Vec<u8> {
+    let mut packet = echo(icmp_type, id);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+#[test]
+fn replays_nat_rewrites() {
+    let (source, destination, router) = ("192.168.1.10", "9.9.9.9", "62.115.1.1");
+    let first_probe = ipv4(
+        source,
+        destination,
+        1,
+        1,
+        0x1111,
+        &echo_with_checksum(8, 0x1111, 0xaaaa),
+    );
+    let second_probe = ipv4(
+        source,
+        destination,
+        1,
+        2,
+        0x2222,
+        &echo_with_checksum(8, 0x2222, 0x9999),
+    );
+
+    // The probe got to the router from the NAT's public address, with a new identifier and a
+    // checksum to match.
+    let quoted = ipv4(
+        "203.0.113.7",
+        destination,
+        1,
+        1,
+        0x1111,
+        &echo_with_checksum(8, 0x04d2, 0xbbbb),
+    );
+    let time_exceeded = [vec![11, 0, 0, 0, 0, 0, 0, 0], quoted].concat();
+    let time_exceeded = ipv4(router, source, 1, 64, 0, &time_exceeded);
+    let echo_reply = ipv4(destination, source, 1, 64, 0, &echo(0, 0x2222));
+
+    let start = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+    let mut bytes = Vec::new();
+    let mut writer = PcapWriter::new(&mut bytes).unwrap();
+    writer.write_packet(start, &first_probe).unwrap();
+    writer.write_packet(start, &second_probe).unwrap();
+    writer.write_packet(start + ms(3), &time_exceeded).unwrap();
+    writer.write_packet(start + ms(7), &echo_reply).unwrap();
+    writer.flush().unwrap();
+
+    let mut network = ReplayNetwork::from_reader(bytes.as_slice()).unwrap();
+    let config = config(&network);
+    let peeringdb = PeeringDbManager::from_sql(PEERINGDB).unwrap();
+    let mut trace = Trace::new(ip(destination), &config);
+
+    let reason = run(&mut trace, &mut network, &peeringdb);
+    assert_eq!(reason, TerminationReason::Done);
+
+    let rewrites: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { ip, details, .. } => (*ip, details.rewrites),
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    assert_eq!(
+        rewrites,
+        vec![
+            (
+                ip(router),
+                Rewrites {
+                    source_ip: Some(ip("203.0.113.7")),
+                    source_port: Some(0x04d2),
+                    checksum: Some(0xbbbb),
+                    ..Default::default()
+                }
+            ),
+            (ip(destination), Rewrites::default()),
+        ]
+    );
+}
+
+fn tcp(source_port: u16, destination_port: u16, sequence: u32, ack: u32, flags: u8) -> Vec<u8> {
+    let mut segment = Vec::new();
+    segment.extend_from_slice(&source_port.to_be_bytes());
+    segment.extend_from_slice(&destination_port.to_be_bytes());
+    segment.extend_from_slice(&sequence.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    segment
+}
+
+#[test]
+fn ignores_syn_acks_on_other_connections() {
+    const SYN: u8 = 0x02;
+    const SYN_ACK: u8 = 0x12;
+    let (source, destination, router) = ("192.168.1.10", "9.9.9.9", "62.115.1.1");
+    let first_probe = ipv4(
+        source,
+        destination,
+        6,
+        1,
+        0,
+        &tcp(0xc000, 443, 0x1111, 0, SYN),
+    );
+    let second_probe = ipv4(
+        source,
+        destination,
+        6,
+        2,
+        0,
+        &tcp(0xc000, 443, 0x2222, 0, SYN),
+    );
+
+    let quoted = ipv4(
+        source,
+        destination,
+        6,
+        1,
+        0,
+        &tcp(0xc000, 443, 0x1111, 0, SYN),
+    );
+    let time_exceeded = [vec![11, 0, 0, 0, 0, 0, 0, 0], quoted[..28].to_vec()].concat();
+    let time_exceeded = ipv4(router, source, 1, 64, 0, &time_exceeded);
+    // The host's own connections, to somewhere else and to the destination from another port,
+    // happen to acknowledge the first probe's sequence number.
+    let elsewhere = ipv4(
+        "140.82.112.3",
+        source,
+        6,
+        52,
+        0,
+        &tcp(443, 0xc123, 7, 0x1112, SYN_ACK),
+    );
+    let other_port = ipv4(
+        destination,
+        source,
+        6,
+        58,
+        0,
+        &tcp(443, 0xd000, 7, 0x1112, SYN_ACK),
+    );
+    let syn_ack = ipv4(
+        destination,
+        source,
+        6,
+        58,
+        0,
+        &tcp(443, 0xc000, 7, 0x2223, SYN_ACK),
+    );
+
+    let start = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+    let mut bytes = Vec::new();
+    let mut writer = PcapWriter::new(&mut bytes).unwrap();
+    writer.write_packet(start, &first_probe).unwrap();
+    writer.write_packet(start, &second_probe).unwrap();
+    writer.write_packet(start + ms(1), &elsewhere).unwrap();
+    writer.write_packet(start + ms(2), &other_port).unwrap();
+    writer.write_packet(start + ms(3), &time_exceeded).unwrap();
+    writer.write_packet(start + ms(7), &syn_ack).unwrap();
+    writer.flush().unwrap();
+
+    let mut network = ReplayNetwork::from_reader(bytes.as_slice()).unwrap();
+    let config = TraceConfig {
+        probe_protocol: ProbeProtocol::Tcp { port: 443 },
+        ..config(&network)
+    };
+    let peeringdb = PeeringDbManager::from_sql(PEERINGDB).unwrap();
+    let mut trace = Trace::new(ip(destination), &config);
+
+    let reason = run(&mut trace, &mut network, &peeringdb);
+    assert_eq!(reason, TerminationReason::Done);
+
+    let hops: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { ip, details, .. } => (*ip, details.rtt.min()),
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    assert_eq!(
+        hops,
+        vec![(ip(router), Some(ms(3))), (ip(destination), Some(ms(7)))]
+    );
+}
+
+#[test]
+fn replays_ttl_and_unreachable_rewrites() {
+    let (source, destination, router) = ("192.168.1.10", "9.9.9.9", "62.115.1.1");
+    let first_probe = ipv4(source, destination, 1, 1, 0x1111, &echo(8, 0x1111));
+    let second_probe = ipv4(source, destination, 1, 2, 0x2222, &echo(8, 0x2222));
+
+    // Something before the router reset the TTL, so it expired there with plenty left.
+    let quoted = ipv4(source, destination, 1, 5, 0x1111, &echo(8, 0x1111));
+    let time_exceeded = [vec![11, 0, 0, 0, 0, 0, 0, 0], quoted].concat();
+    let time_exceeded = ipv4(router, source, 1, 64, 0, &time_exceeded);
+    // The destination's firewall turned it away, after something remarked it as EF.
+    let mut quoted = ipv4(source, destination, 1, 1, 0x2222, &echo(8, 0x2222));
+    quoted[1] = 46 << 2;
+    let prohibited = [vec![3, 13, 0, 0, 0, 0, 0, 0], quoted].concat();
+    let prohibited = ipv4(destination, source, 1, 60, 0, &prohibited);
+
+    let start = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+    let mut bytes = Vec::new();
+    let mut writer = PcapWriter::new(&mut bytes).unwrap();
+    writer.write_packet(start, &first_probe).unwrap();
+    writer.write_packet(start, &second_probe).unwrap();
+    writer.write_packet(start + ms(3), &time_exceeded).unwrap();
+    writer.write_packet(start + ms(7), &prohibited).unwrap();
+    writer.flush().unwrap();
+
+    let mut network = ReplayNetwork::from_reader(bytes.as_slice()).unwrap();
+    let config = config(&network);
+    let peeringdb = PeeringDbManager::from_sql(PEERINGDB).unwrap();
+    let mut trace = Trace::new(ip(destination), &config);
+
+    let reason = run(&mut trace, &mut network, &peeringdb);
+    assert_eq!(
+        reason,
+        TerminationReason::DestinationUnreachable(UnreachableCode::AdminProhibited)
+    );
+
+    let rewrites: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::FindingAsn { ip, details, .. } | Hop::Done { ip, details, .. } => {
+                (*ip, details.rewrites)
+            }
+            _ => panic!("hop not answered: {:?}", hop),
+        })
+        .collect();
+    assert_eq!(
+        rewrites,
+        vec![
+            (
+                ip(router),
+                Rewrites {
+                    ttl: Some(5),
+                    ..Default::default()
+                }
+            ),
+            (
+                ip(destination),
+                Rewrites {
+                    dscp: Some(46),
+                    ..Default::default()
+                }
+            ),
+        ]
+    );
+}
@@ -0,0 +1,671 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ktr_lib::clock::Clock;
+use ktr_lib::peeringdb::PeeringDbManager;
+use ktr_lib::simulated_net::{SimulatedDestination, SimulatedHop, SimulatedNetwork, SimulatedPath};
+use ktr_lib::trace::{
+    Hop, Monitor, MonitorConfig, ProbeOutcome, Rewrites, TerminationReason, Trace, TraceConfig,
+};
+use ktr_lib::traceroute_net::{
+    PacketId, ProbeOptions, ProbeProtocol, ProbeTransport, UnreachableCode,
+};
+
+fn ip(text: &str) -> IpAddr {
+    text.parse().unwrap()
+}
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+fn destination() -> IpAddr {
+    ip("198.51.100.1")
+}
+
+fn config(network: &SimulatedNetwork) -> TraceConfig {
+    TraceConfig {
+        max_hops: 16,
+        max_sequential_pending: 3,
+        wait_time_per_hop: ms(100),
+        retry_frequency: ms(500),
+        destination_timeout: ms(2000),
+        completion_timeout: ms(3000),
+        asn_cache_size: 16,
+        probes_per_hop: 1,
+        clock: Arc::new(network.clock().clone()),
+        ..Default::default()
+    }
+}
+
+/// Routers at 192.0.2.1 onwards, each 10ms further away than the last. Documentation addresses
+/// aren't public, so no WHOIS lookups happen.
+fn routers(count: u8) -> Vec<SimulatedHop> {
+    (1..=count)
+        .map(|i| SimulatedHop::router(ip(&format!("192.0.2.{}", i)), ms(10 * i as u64)))
+        .collect()
+}
+
+fn network(
+    hops: Vec<SimulatedHop>,
+    destination_behavior: SimulatedDestination,
+) -> SimulatedNetwork {
+    let mut network = SimulatedNetwork::new(0);
+    network.add_path(
+        destination(),
+        SimulatedPath {
+            hops,
+            destination: destination_behavior,
+        },
+    );
+    network
+}
+
+/// Drive a trace until it terminates, failing if that takes more than a virtual minute.
+fn run(trace: &mut Trace, network: &mut SimulatedNetwork) -> TerminationReason {
+    let peeringdb = PeeringDbManager::connect(":memory:").unwrap();
+    while network.clock().elapsed() < Duration::from_secs(60) {
+        if let (_, Some(reason)) = trace.non_packet_poll(network, &peeringdb).unwrap() {
+            return reason;
+        }
+        while let Some(packet) = network.poll().unwrap() {
+            let (_, reason) = trace
+                .perhaps_use_packet(&packet, network, &peeringdb)
+                .unwrap();
+            if let Some(reason) = reason {
+                return reason;
+            }
+        }
+    }
+    panic!("trace never terminated");
+}
+
+/// Drive a monitor for a virtual duration. Monitors never terminate by themselves.
+fn run_monitor(monitor: &mut Monitor, network: &mut SimulatedNetwork, duration: Duration) {
+    let peeringdb = PeeringDbManager::connect(":memory:").unwrap();
+    let until = network.clock().elapsed() + duration;
+    while network.clock().elapsed() < until {
+        monitor.non_packet_poll(network, &peeringdb).unwrap();
+        while let Some(packet) = network.poll().unwrap() {
+            monitor
+                .perhaps_use_packet(&packet, network, &peeringdb)
+                .unwrap();
+        }
+    }
+}
+
+fn hop_ips(trace: &Trace) -> Vec<Option<IpAddr>> {
+    trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::FindingAsn { ip, .. } | Hop::Done { ip, .. } => Some(*ip),
+            _ => None,
+        })
+        .collect()
+}
+
+fn probes_sent_with_ttl(network: &SimulatedNetwork, ttl: u8) -> usize {
+    network
+        .sent_probes()
+        .iter()
+        .filter(|probe| probe.ttl == ttl)
+        .count()
+}
+
+#[test]
+fn reaches_destination() {
+    let mut network = network(routers(3), SimulatedDestination::Reply { delay: ms(40) });
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    assert_eq!(
+        hop_ips(&trace),
+        vec![
+            Some(ip("192.0.2.1")),
+            Some(ip("192.0.2.2")),
+            Some(ip("192.0.2.3")),
+            Some(destination()),
+        ]
+    );
+    // Virtual time makes round trips exact.
+    for (hop, rtt) in trace.hops().iter().zip([10, 20, 30, 40]) {
+        let Hop::Done { details, .. } = hop else {
+            panic!("hop not done: {:?}", hop);
+        };
+        assert_eq!(details.rtt.min(), Some(ms(rtt)));
+    }
+}
+
+#[test]
+fn destination_unreachable_terminates() {
+    let mut network = network(
+        routers(2),
+        SimulatedDestination::Unreachable {
+            ip: ip("192.0.2.2"),
+            code: UnreachableCode::AdminProhibited,
+            delay: ms(20),
+        },
+    );
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(
+        run(&mut trace, &mut network),
+        TerminationReason::DestinationUnreachable(UnreachableCode::AdminProhibited)
+    );
+    let Some(Hop::Done {
+        ip: last_ip,
+        details,
+        ..
+    }) = trace.hops().last()
+    else {
+        panic!("last hop not done: {:?}", trace.hops().last());
+    };
+    assert_eq!(*last_ip, ip("192.0.2.2"));
+    assert_eq!(details.unreachable, Some(UnreachableCode::AdminProhibited));
+}
+
+#[test]
+fn silent_destination_times_out() {
+    let mut network = network(routers(2), SimulatedDestination::Silent);
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(
+        run(&mut trace, &mut network),
+        TerminationReason::DestinationTimeout
+    );
+    // Past the last router, it only gets as far as the maximum number of pending hops.
+    let furthest_ttl = network.sent_probes().iter().map(|probe| probe.ttl).max();
+    assert_eq!(furthest_ttl, Some(2 + config.max_sequential_pending));
+    assert!(network.clock().elapsed() > config.destination_timeout);
+}
+
+#[test]
+fn silent_hop_hits_completion_timeout() {
+    let mut hops = routers(3);
+    hops[1] = SimulatedHop::silent();
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(40) });
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(
+        run(&mut trace, &mut network),
+        TerminationReason::CompletionTimeout
+    );
+    let silent_hop = &trace.hops()[1];
+    assert!(matches!(silent_hop, Hop::Pending { .. }));
+    assert_eq!(silent_hop.loss(), Some(1.0));
+    // It kept being retried until the completion timeout.
+    assert!(probes_sent_with_ttl(&network, 2) > 2);
+}
+
+#[test]
+fn retry_recovers_missed_probe() {
+    let mut hops = routers(3);
+    hops[1].ignored_probes = 1;
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(40) });
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    assert_eq!(probes_sent_with_ttl(&network, 2), 2);
+    assert_eq!(hop_ips(&trace)[1], Some(ip("192.0.2.2")));
+    // Only the retry was answered, so the round trip is measured from the resend.
+    let Hop::Done { details, .. } = &trace.hops()[1] else {
+        panic!("hop not done: {:?}", trace.hops()[1]);
+    };
+    assert_eq!(details.rtt.min(), Some(ms(20)));
+}
+
+#[test]
+fn late_reply_is_timed_from_its_own_send() {
+    let mut hops = routers(3);
+    hops[1].delay = ms(900);
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(40) });
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    assert_eq!(probes_sent_with_ttl(&network, 2), 2);
+    // The first send was answered after the retry went out, but that doesn't make it faster.
+    let Hop::Done { details, .. } = &trace.hops()[1] else {
+        panic!("hop not done: {:?}", trace.hops()[1]);
+    };
+    assert_eq!(details.rtt.min(), Some(ms(900)));
+}
+
+#[test]
+fn duplicate_replies_count_once() {
+    let mut network = network(routers(2), SimulatedDestination::Reply { delay: ms(30) });
+    let config = TraceConfig {
+        probes_per_hop: 3,
+        ..config(&network)
+    };
+    let mut trace = Trace::new(destination(), &config);
+    let peeringdb = PeeringDbManager::connect(":memory:").unwrap();
+
+    // Every reply arrives twice, like on a link that duplicates packets.
+    let reason = 'trace: loop {
+        assert!(network.clock().elapsed() < Duration::from_secs(60));
+        if let (_, Some(reason)) = trace.non_packet_poll(&mut network, &peeringdb).unwrap() {
+            break reason;
+        }
+        while let Some(packet) = network.poll().unwrap() {
+            for _ in 0..2 {
+                let (_, reason) = trace
+                    .perhaps_use_packet(&packet, &mut network, &peeringdb)
+                    .unwrap();
+                if let Some(reason) = reason {
+                    break 'trace reason;
+                }
+            }
+        }
+    };
+    assert_eq!(reason, TerminationReason::Done);
+
+    for hop in trace.hops() {
+        let Hop::Done { details, .. } = hop else {
+            panic!("hop not done: {:?}", hop);
+        };
+        let replies = details
+            .probes
+            .iter()
+            .filter(|probe| matches!(probe.outcome, ProbeOutcome::Reply { .. }))
+            .count();
+        assert_eq!(replies, 3);
+        assert_eq!(details.rtt.samples().len(), replies);
+    }
+}
+
+#[test]
+fn rate_limited_router_answers_once() {
+    let mut hops = routers(2);
+    hops[0].rate_limit = Some(1);
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(30) });
+    let config = TraceConfig {
+        probes_per_hop: 3,
+        ..config(&network)
+    };
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    let replies = trace.hops()[0]
+        .probes()
+        .iter()
+        .filter(|probe| matches!(probe.outcome, ProbeOutcome::Reply { .. }))
+        .count();
+    assert_eq!(replies, 1);
+    assert_eq!(trace.hops()[1].probes().len(), 3);
+}
+
+#[test]
+fn poll_waits_on_virtual_time() {
+    let mut network = network(routers(1), SimulatedDestination::Silent);
+    let start = network.clock().now();
+    network
+        .send_probe(
+            ProbeProtocol::Icmp,
+            destination(),
+            1,
+            PacketId(7),
+            None,
+            &ProbeOptions::default(),
+        )
+        .unwrap();
+
+    let packet = network
+        .poll()
+        .unwrap()
+        .expect("reply within the poll timeout");
+    assert_eq!(packet.received_at - start, ms(10));
+    assert_eq!(network.clock().now() - start, ms(10));
+
+    assert!(network.poll().unwrap().is_none());
+    assert_eq!(network.clock().now() - start, ms(60));
+}
+
+#[test]
+fn discovers_path_mtu() {
+    let mut hops = routers(4);
+    hops[1].mtu = Some(1400);
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(50) });
+    let config = TraceConfig {
+        pmtu: true,
+        probe_options: ProbeOptions {
+            size: Some(1500),
+            ..Default::default()
+        },
+        ..config(&network)
+    };
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    assert_eq!(hop_ips(&trace).len(), 5);
+    let mtus: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { details, .. } => (details.mtu, details.next_hop_mtu),
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    assert_eq!(
+        mtus,
+        vec![
+            (Some(1500), None),
+            (Some(1500), Some(1400)),
+            (Some(1400), None),
+            (Some(1400), None),
+            (Some(1400), None),
+        ]
+    );
+    assert_eq!(trace.path_mtu(), Some(1400));
+    // The probe that got the Packet Too Big went straight out again, small enough this time.
+    let sizes: Vec<_> = network
+        .sent_probes()
+        .iter()
+        .filter(|probe| probe.ttl == 3)
+        .map(|probe| probe.size)
+        .collect();
+    assert_eq!(sizes, vec![Some(1500), Some(1400)]);
+}
+
+#[test]
+fn reports_dscp_rewrites() {
+    let mut hops = routers(4);
+    hops[1].dscp = Some(0);
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(50) });
+    let config = TraceConfig {
+        probe_options: ProbeOptions {
+            dscp: 46,
+            ..Default::default()
+        },
+        ..config(&network)
+    };
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    let rewrites: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { details, .. } => details.rewrites,
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    let bleached = Rewrites {
+        dscp: Some(0),
+        ..Default::default()
+    };
+    // The destination's reply doesn't quote the probe, so there's nothing to compare.
+    assert_eq!(
+        rewrites,
+        vec![
+            Rewrites::default(),
+            Rewrites::default(),
+            bleached,
+            bleached,
+            Rewrites::default(),
+        ]
+    );
+}
+
+#[test]
+fn reports_nat_rewrites() {
+    let mut hops = routers(4);
+    hops[0].nat = Some(ip("192.0.2.100"));
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(50) });
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    let source_ips: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { details, .. } => details.rewrites.source_ip,
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    let nat = Some(ip("192.0.2.100"));
+    assert_eq!(source_ips, vec![None, nat, nat, nat, None]);
+}
+
+#[test]
+fn infers_reverse_distance() {
+    let mut hops = routers(4);
+    hops[2].reverse_hops = Some(6);
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(50) });
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    let distances: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { details, .. } => (
+                details.forward_distance,
+                details.reply_ttl,
+                details.reverse_distance,
+            ),
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    // Replies start at 64 and lose one per router on the way back.
+    assert_eq!(
+        distances,
+        vec![
+            (1, Some(64), Some(1)),
+            (2, Some(63), Some(2)),
+            (3, Some(58), Some(7)),
+            (4, Some(61), Some(4)),
+            (5, Some(60), Some(5)),
+        ]
+    );
+}
+
+#[test]
+fn stops_at_routing_loop() {
+    let mut hops = routers(2);
+    for i in 0..7 {
+        let router = if i % 2 == 0 {
+            "192.0.2.10"
+        } else {
+            "192.0.2.11"
+        };
+        hops.push(SimulatedHop::router(ip(router), ms(30 + 10 * i)));
+    }
+    let mut network = network(hops, SimulatedDestination::Silent);
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(
+        run(&mut trace, &mut network),
+        TerminationReason::RoutingLoop(vec![ip("192.0.2.10"), ip("192.0.2.11")])
+    );
+    // Caught as soon as the second lap was in, not after running out of hops.
+    assert!(trace.hops().len() < config.max_hops as usize);
+}
+
+#[test]
+fn flags_router_answering_at_several_ttls() {
+    let mut hops = routers(4);
+    hops[2].ip = hops[1].ip;
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(50) });
+    let config = config(&network);
+    let mut trace = Trace::new(destination(), &config);
+
+    assert_eq!(run(&mut trace, &mut network), TerminationReason::Done);
+    let repeats: Vec<_> = trace
+        .hops()
+        .iter()
+        .map(|hop| match hop {
+            Hop::Done { details, .. } => details.also_answered_at.clone(),
+            _ => panic!("hop not done: {:?}", hop),
+        })
+        .collect();
+    assert_eq!(repeats, vec![vec![], vec![3], vec![2], vec![], vec![]]);
+}
+
+#[test]
+fn monitor_holds_flow_constant() {
+    let mut network = network(routers(3), SimulatedDestination::Reply { delay: ms(40) });
+    let config = config(&network);
+    assert!(!config.paris);
+    let mut monitor = Monitor::new(destination(), &config);
+
+    run_monitor(&mut monitor, &mut network, Duration::from_secs(10));
+    let flow = network.sent_probes()[0].flow;
+    assert!(flow.is_some());
+    assert!(network.sent_probes().iter().all(|probe| probe.flow == flow));
+    assert!(monitor.snapshot().cycles > 1);
+}
+
+#[test]
+fn monitor_rediscovers_after_nothing_answered() {
+    // No path yet, so every probe vanishes.
+    let mut network = SimulatedNetwork::new(0);
+    let config = config(&network);
+    let mut monitor = Monitor::new(destination(), &config);
+
+    run_monitor(&mut monitor, &mut network, Duration::from_secs(10));
+    assert_eq!(monitor.snapshot().cycles, 0);
+
+    network.add_path(
+        destination(),
+        SimulatedPath {
+            hops: routers(2),
+            destination: SimulatedDestination::Reply { delay: ms(30) },
+        },
+    );
+    run_monitor(&mut monitor, &mut network, Duration::from_secs(10));
+    let snapshot = monitor.snapshot();
+    assert!(snapshot.cycles > 0);
+    let responders: Vec<_> = snapshot.hops.iter().map(|hop| hop.responder).collect();
+    assert_eq!(
+        responders,
+        vec![
+            Some(ip("192.0.2.1")),
+            Some(ip("192.0.2.2")),
+            Some(destination())
+        ]
+    );
+}
+
+/// `routers(3)` with a different router at TTL 2, further away than the one it replaces.
+fn rerouted_hops() -> Vec<SimulatedHop> {
+    let mut hops = routers(3);
+    hops[1] = SimulatedHop::router(ip("192.0.2.99"), ms(50));
+    hops
+}
+
+#[test]
+fn monitor_reports_path_changes() {
+    let mut network = network(routers(3), SimulatedDestination::Reply { delay: ms(40) });
+    let config = config(&network);
+    let mut monitor = Monitor::new(destination(), &config);
+
+    run_monitor(&mut monitor, &mut network, Duration::from_secs(5));
+    assert!(monitor.snapshot().path_changes.is_empty());
+
+    network.add_path(
+        destination(),
+        SimulatedPath {
+            hops: rerouted_hops(),
+            destination: SimulatedDestination::Reply { delay: ms(40) },
+        },
+    );
+    run_monitor(&mut monitor, &mut network, Duration::from_secs(5));
+
+    let snapshot = monitor.snapshot();
+    let changes: Vec<_> = snapshot
+        .path_changes
+        .iter()
+        .map(|change| (change.ttl, change.from, change.to))
+        .collect();
+    assert_eq!(changes, vec![(2, ip("192.0.2.2"), ip("192.0.2.99"))]);
+
+    // Every round trip to TTL 2 was 20ms before the change and 50ms after, so the only
+    // difference between consecutive ones is the 30ms jump.
+    let hop = &snapshot.hops[1];
+    assert_eq!(hop.responder, Some(ip("192.0.2.99")));
+    assert_eq!(hop.lost, 0);
+    assert_eq!(
+        (hop.best, hop.worst, hop.last),
+        (Some(ms(20)), Some(ms(50)), Some(ms(50)))
+    );
+    assert_eq!(hop.jitter, Some(ms(30) / (hop.sent as u32 - 1)));
+    // Nothing else moved.
+    assert_eq!(snapshot.hops[0].jitter, Some(Duration::ZERO));
+}
+
+#[test]
+fn monitor_keeps_latest_path_changes() {
+    let mut network = network(routers(3), SimulatedDestination::Reply { delay: ms(40) });
+    let config = TraceConfig {
+        monitor: MonitorConfig {
+            window: 2,
+            ..Default::default()
+        },
+        ..config(&network)
+    };
+    let mut monitor = Monitor::new(destination(), &config);
+
+    run_monitor(&mut monitor, &mut network, Duration::from_secs(3));
+    for hops in [rerouted_hops(), routers(3), rerouted_hops(), routers(3)] {
+        network.add_path(
+            destination(),
+            SimulatedPath {
+                hops,
+                destination: SimulatedDestination::Reply { delay: ms(40) },
+            },
+        );
+        run_monitor(&mut monitor, &mut network, Duration::from_secs(3));
+    }
+
+    let changes: Vec<_> = monitor
+        .snapshot()
+        .path_changes
+        .iter()
+        .map(|change| (change.from, change.to))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            (ip("192.0.2.2"), ip("192.0.2.99")),
+            (ip("192.0.2.99"), ip("192.0.2.2")),
+        ]
+    );
+}
+
+#[test]
+fn monitor_measures_loss_and_latency() {
+    let mut hops = routers(3);
+    hops[1].loss = 0.5;
+    let mut network = network(hops, SimulatedDestination::Reply { delay: ms(40) });
+    let config = config(&network);
+    let mut monitor = Monitor::new(destination(), &config);
+
+    run_monitor(&mut monitor, &mut network, Duration::from_secs(60));
+    let snapshot = monitor.snapshot();
+
+    let lossy = &snapshot.hops[1];
+    assert!(lossy.sent > 50, "{:?}", lossy);
+    assert_eq!(lossy.loss, lossy.lost as f64 / lossy.sent as f64);
+    assert!(lossy.loss > 0.25 && lossy.loss < 0.75, "{:?}", lossy);
+    // Whatever made it back took exactly as long as always.
+    assert_eq!(
+        (lossy.last, lossy.avg, lossy.best, lossy.worst),
+        (Some(ms(20)), Some(ms(20)), Some(ms(20)), Some(ms(20)))
+    );
+    assert_eq!(lossy.jitter, Some(Duration::ZERO));
+
+    let clean = &snapshot.hops[0];
+    assert_eq!((clean.lost, clean.loss), (0, 0.0));
+    assert_eq!(clean.avg, Some(ms(10)));
+}